@@ -0,0 +1,411 @@
+use crate::channels::Channels;
+use crate::events;
+use crate::help;
+use crate::ignores::{self, Ignores};
+use crate::outbound::Outbound;
+use crate::permissions::{Level, Permissions, is_valid_mask};
+use crate::plugins::{self, PluginManager};
+use crate::reconnect::Connections;
+use crate::timers::parse_interval;
+use common::author::Author;
+use std::path::Path;
+use std::time::Instant;
+
+/// How many hex digits of a plugin's hash `+plugins list` shows.
+const HASH_LENGTH: usize = 12;
+
+const USAGE: &str =
+    "+plugins [list|conflicts|reload|load <file>|unload <plugin>|disable <plugin>|enable <plugin>]";
+
+const ACCESS_USAGE: &str = "+access [list|check <mask>|set <mask> <level>|del <mask>]";
+
+const IGNORE_USAGE: &str = "+ignore [list|add <mask> [duration] [reason]|del <mask>]";
+
+/// `+plugins`: lists plugins, or loads, unloads, reloads, disables or enables
+/// them. Only for admins; the caller checks.
+pub async fn plugins(manager: &PluginManager, param: &str, author: &Author) -> Vec<String> {
+    let (subcommand, argument) = match param.split_once(char::is_whitespace) {
+        Some((subcommand, argument)) => (subcommand, argument.trim()),
+        None => (param, ""),
+    };
+
+    let result = match (subcommand, argument) {
+        ("" | "list", _) => return list(manager, author),
+        ("conflicts", _) => return vec![conflicts(manager, author)],
+        ("reload", _) => reload(manager).await,
+        ("load", file) if !file.is_empty() => load(manager, file).await,
+        ("unload", name) if !name.is_empty() => loaded(manager, name).and_then(|path| {
+            manager.unload(&path)?;
+            Ok(format!("unloaded {}", path))
+        }),
+        ("disable", name) if !name.is_empty() => loaded(manager, name).and_then(|path| {
+            manager.disable(&path)?;
+            Ok(format!("disabled {}", path))
+        }),
+        ("enable", name) if !name.is_empty() => loaded(manager, name).and_then(|path| {
+            manager.enable(&path)?;
+            Ok(format!("enabled {}", path))
+        }),
+        _ => Err(format!("usage: {}", USAGE)),
+    };
+
+    match result {
+        Ok(output) => vec![[author.l("Plugins"), author.c1(&output)].join(" ")],
+        Err(e) => vec![[author.l("Error"), author.c1(&e)].join(" ")],
+    }
+}
+
+/// `+access`: lists, checks and edits permission rules. Only for admins; the
+/// caller checks. Rules can only be set to, or changed from, levels below the
+/// caller's own, except by owners.
+pub fn access(
+    permissions: &Permissions,
+    param: &str,
+    author: &Author,
+    level: Level,
+) -> Vec<String> {
+    let arguments = param.split_whitespace().collect::<Vec<&str>>();
+
+    let result = match arguments.as_slice() {
+        [] | ["list"] => {
+            let rules = permissions
+                .rules()
+                .iter()
+                .map(|rule| [author.l(&rule.mask), author.c1(rule.level.name())].join(" "))
+                .collect();
+
+            return help::pages("Access", rules, author);
+        }
+        ["check", mask] => Ok(format!("{} is {}", mask, permissions.level(mask, None))),
+        ["set", mask, name] => match Level::parse(name) {
+            Some(_) if !is_valid_mask(mask) => Err(format!("invalid mask '{}'", mask)),
+            Some(Level::Ignored) => Err(format!("use +ignore add {} to ignore them", mask)),
+            Some(new) if !may_manage(level, new) => {
+                Err(format!("only owners may grant {} or above", level))
+            }
+            Some(_)
+                if permissions
+                    .rule(mask)
+                    .is_some_and(|old| !may_manage(level, old)) =>
+            {
+                Err(format!("{} can only be changed by an owner", mask))
+            }
+            Some(new) => {
+                permissions.set(mask, new);
+                Ok(format!("{} is now {}", mask, new))
+            }
+            None => Err(format!("unknown level '{}'", name)),
+        },
+        ["del", mask] => match permissions.rule(mask) {
+            Some(old) if !may_manage(level, old) => {
+                Err(format!("{} can only be removed by an owner", mask))
+            }
+            Some(_) => {
+                permissions.remove(mask);
+                Ok(format!("removed {}", mask))
+            }
+            None => Err(format!("no rule for {}", mask)),
+        },
+        _ => Err(format!("usage: {}", ACCESS_USAGE)),
+    };
+
+    match result {
+        Ok(output) => vec![[author.l("Access"), author.c1(&output)].join(" ")],
+        Err(e) => vec![[author.l("Error"), author.c1(&e)].join(" ")],
+    }
+}
+
+/// `+ignore`: lists, adds and removes ignores. A mask is a nick, a
+/// `nick!user@host` mask or an `$a:account` mask, and an ignore without a duration (like `"2h"`) lasts
+/// until it's removed or the bot restarts. Only for admins; the caller checks.
+pub fn ignore(ignores: &Ignores, param: &str, author: &Author) -> Vec<String> {
+    let arguments = param.split_whitespace().collect::<Vec<&str>>();
+
+    let result = match arguments.as_slice() {
+        [] | ["list"] => {
+            let now = Instant::now();
+            let items = ignores
+                .list()
+                .iter()
+                .map(|ignore| {
+                    let mut status = match ignore.expires {
+                        Some(expires) => format!(
+                            "for {}",
+                            ignores::describe_duration(expires.saturating_duration_since(now))
+                        ),
+                        None => "permanent".to_string(),
+                    };
+                    if !ignore.reason.is_empty() {
+                        status = format!("{}, {}", status, ignore.reason);
+                    }
+
+                    [author.l(&ignore.mask), author.c1(&status)].join(" ")
+                })
+                .collect();
+
+            return help::pages("Ignores", items, author);
+        }
+        ["add", mask, rest @ ..] => {
+            // An optional duration, then the reason
+            let (duration, reason) = match rest.split_first() {
+                Some((first, reason)) => match parse_interval(first) {
+                    Some(duration) => (Some(duration), reason.join(" ")),
+                    None => (None, rest.join(" ")),
+                },
+                None => (None, String::new()),
+            };
+
+            let mask = ignores.add(mask, duration, &reason);
+            match duration {
+                Some(duration) => Ok(format!(
+                    "ignoring {} for {}",
+                    mask,
+                    ignores::describe_duration(duration)
+                )),
+                None => Ok(format!("ignoring {}", mask)),
+            }
+        }
+        ["del", mask] => {
+            if ignores.remove(mask) {
+                Ok(format!("no longer ignoring {}", ignores::normalize(mask)))
+            } else {
+                Err(format!("{} isn't ignored", ignores::normalize(mask)))
+            }
+        }
+        _ => Err(format!("usage: {}", IGNORE_USAGE)),
+    };
+
+    match result {
+        Ok(output) => vec![[author.l("Ignore"), author.c1(&output)].join(" ")],
+        Err(e) => vec![[author.l("Error"), author.c1(&e)].join(" ")],
+    }
+}
+
+/// `+queue`: how many lines are waiting to be sent, for each target with any.
+pub fn queue(outbound: &Outbound, author: &Author) -> Vec<String> {
+    let depth = outbound.depth();
+    let total = depth.iter().map(|(_, _, lines)| lines).sum::<usize>();
+
+    let mut items = vec![[author.l("Total"), author.c1(&format!("{} lines", total))].join(" ")];
+    items.extend(depth.iter().map(|(priority, target, lines)| {
+        let target = if target.is_empty() { "server" } else { target };
+        [
+            author.l(target),
+            author.c1(&format!("{} {} lines", lines, priority.name())),
+        ]
+        .join(" ")
+    }));
+
+    help::pages("Queue", items, author)
+}
+
+/// `+lag`: the round trip to the server, as the watchdog last measured it.
+pub fn lag(connections: &Connections, author: &Author) -> String {
+    let lag = match (connections.current(), connections.lag()) {
+        (Some((server, _)), Some(lag)) => format!("{}ms to {}", lag.as_millis(), server),
+        (Some((server, _)), None) => format!("not measured yet on {}", server),
+        (None, _) => "not connected".to_string(),
+    };
+
+    [author.l("Lag"), author.c1(&lag)].join(" ")
+}
+
+/// `+servers`: where the network is connected and how recent connections
+/// ended, newest first.
+pub fn servers(connections: &Connections, author: &Author) -> Vec<String> {
+    let current = match connections.current() {
+        Some((server, since)) => format!("{} since {}", server, since.format("%Y-%m-%d %H:%M:%S")),
+        None => "not connected".to_string(),
+    };
+
+    let mut items = vec![[author.l("Connected"), author.c1(&current)].join(" ")];
+    items.extend(
+        connections
+            .recent()
+            .iter()
+            .map(|attempt| [author.l(&attempt.server), author.c1(&attempt.to_string())].join(" ")),
+    );
+
+    help::pages("Servers", items, author)
+}
+
+/// `+channel [#channel]`: who's in a channel and its modes, including the
+/// bot's own; without one, every channel the bot is in.
+pub fn channel(channels: &Channels, name: &str, me: &str, author: &Author) -> Vec<String> {
+    if !events::is_channel(name) {
+        return help::pages("Channels", channels.names(), author);
+    }
+
+    let channel = match channels.channel(name) {
+        Some(channel) => channel,
+        None => {
+            let output = format!("not in {}", name);
+            return vec![[author.l("Channel"), author.c1(&output)].join(" ")];
+        }
+    };
+
+    let members = channel.members().count();
+    let ops = channel.members().filter(|member| member.has('o')).count();
+    let voiced = channel.members().filter(|member| member.has('v')).count();
+    let mine = match channel.member(me).map(|member| member.modes.as_str()) {
+        Some("") | None => "none".to_string(),
+        Some(modes) => format!("+{}", modes),
+    };
+
+    let items = vec![
+        [
+            author.l(&channel.name),
+            author.c1(&format!(
+                "{} members, {} ops, {} voiced",
+                members, ops, voiced
+            )),
+        ]
+        .join(" "),
+        [author.l("Modes"), author.c1(&channel.mode_string())].join(" "),
+        [author.l("Bot"), author.c1(&mine)].join(" "),
+    ];
+
+    help::pages("Channel", items, author)
+}
+
+// Owners manage everything; everyone else only what's below them.
+fn may_manage(caller: Level, level: Level) -> bool {
+    caller == Level::Owner || level < caller
+}
+
+// Every loaded plugin with when it was loaded and its hash, then every library
+// that failed to load and why.
+fn list(manager: &PluginManager, author: &Author) -> Vec<String> {
+    let mut items = match manager.active.read() {
+        Ok(active) => active
+            .iter()
+            .map(|plugin| {
+                let mut status = format!(
+                    "loaded {}, sha256 {}",
+                    plugin.loaded_at.format("%Y-%m-%d %H:%M:%S"),
+                    &plugin.hash[..HASH_LENGTH.min(plugin.hash.len())]
+                );
+                if manager.is_disabled(&plugin.name) {
+                    status.push_str(", disabled");
+                }
+
+                [author.l(&file_name(&plugin.name)), author.c1(&status)].join(" ")
+            })
+            .collect::<Vec<String>>(),
+        Err(_) => vec![],
+    };
+
+    items.extend(manager.failures().into_iter().map(|(path, e)| {
+        [
+            author.l(&file_name(&path)),
+            author.c1(&format!("failed: {}", e)),
+        ]
+        .join(" ")
+    }));
+
+    help::pages("Plugins", items, author)
+}
+
+fn conflicts(manager: &PluginManager, author: &Author) -> String {
+    let conflicts = match manager.dispatch.read() {
+        Ok(dispatch) => dispatch
+            .conflicts
+            .iter()
+            .map(|conflict| {
+                format!(
+                    "{}: {}",
+                    conflict.command,
+                    plugins::describe_order(&conflict.plugins)
+                )
+            })
+            .chain(dispatch.shadowed.iter().map(|shadowed| {
+                format!(
+                    "{}: built-in > {}",
+                    shadowed.command,
+                    plugins::describe_order(&shadowed.plugins)
+                )
+            }))
+            .collect::<Vec<String>>(),
+        Err(_) => vec![],
+    };
+
+    if conflicts.is_empty() {
+        [author.l("Conflicts"), author.c1("none")].join(" ")
+    } else {
+        [author.l("Conflicts"), author.c1(&conflicts.join("; "))].join(" ")
+    }
+}
+
+// Loading runs plugin code, and may start a child process, so it's kept off
+// the message loop.
+async fn load(manager: &PluginManager, file: &str) -> Result<String, String> {
+    let path =
+        PluginManager::find_file(file).ok_or_else(|| format!("no plugin library '{}'", file))?;
+
+    // A plugin already loaded from the file is replaced
+    let replacing = manager.find_loaded(&path).is_some();
+
+    let worker = manager.clone();
+    let target = path.clone();
+    tokio::task::spawn_blocking(move || worker.add(&target))
+        .await
+        .map_err(|e| e.to_string())??;
+
+    if replacing {
+        Ok(format!("reloaded {}", path))
+    } else {
+        Ok(format!("loaded {}", path))
+    }
+}
+
+async fn reload(manager: &PluginManager) -> Result<String, String> {
+    let worker = manager.clone();
+    tokio::task::spawn_blocking(move || worker.reload().map(|_| ()))
+        .await
+        .map_err(|e| e.to_string())?
+        .map_err(|_| "reload failed, see the log".to_string())?;
+
+    let active = manager
+        .active
+        .read()
+        .map(|active| active.len())
+        .unwrap_or(0);
+
+    Ok(format!(
+        "reloaded {} plugins, {} failed",
+        active,
+        manager.failures().len()
+    ))
+}
+
+fn loaded(manager: &PluginManager, name: &str) -> Result<String, String> {
+    manager
+        .find_loaded(name)
+        .ok_or_else(|| format!("no loaded plugin '{}'", name))
+}
+
+fn file_name(path: &str) -> String {
+    Path::new(path)
+        .file_name()
+        .map(|name| name.to_string_lossy().to_string())
+        .unwrap_or_else(|| path.to_string())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_may_manage() {
+        assert!(may_manage(Level::Owner, Level::Owner));
+        assert!(may_manage(Level::Admin, Level::Trusted));
+        assert!(may_manage(Level::Admin, Level::Ignored));
+        assert!(!may_manage(Level::Admin, Level::Admin));
+        assert!(!may_manage(Level::Trusted, Level::Owner));
+    }
+
+    #[test]
+    fn test_file_name() {
+        assert_eq!(file_name("plugins/librunescape.so"), "librunescape.so");
+    }
+}
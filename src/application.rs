@@ -3,106 +3,335 @@ extern crate common;
 extern crate reqwest;
 extern crate select;
 
+use crate::admin;
+use crate::dispatch::Dispatch;
+use crate::events::{self, Event};
+use crate::executor::{CallError, Executor};
+use crate::help;
+use crate::ignores::Ignores;
+use crate::ircv3::{self, Negotiation};
+use crate::listeners;
+use crate::outbound::{Outbound, Priority};
+use crate::pager::Page;
+use crate::permissions::{self, Caller, Level, Permissions};
 use crate::plugins::{Plugin, PluginManager};
-use crate::timers::TimerManager;
+use crate::ratelimit::{self, Scope, Verdict};
+use crate::reconnect::{self, Attempt, Connections, Outcome, Reconnect, Server};
+use crate::response::{self, Item, Kind};
+use crate::services::Services;
+use crate::settings::Settings;
+use crate::split;
+use crate::watchdog::{Tick, Watchdog};
+use common::ColorResult;
 use common::author::Author;
-use common::{ColorResult, PluginContext};
 use futures::prelude::*;
 use irc::client::prelude::*;
-use libloading::{Library, Symbol};
 use regex::Regex;
-use std::ffi::{CStr, CString};
+use std::collections::HashMap;
 use std::os::raw::c_char;
-use std::sync::{Arc, RwLock};
+use std::sync::{Arc, LazyLock};
 use std::thread;
 use std::time::Duration;
 use tokio::sync::mpsc;
 use tokio::time;
 
+// How often nick reclaims, held joins and the watchdog are checked
+const TICK: Duration = Duration::from_secs(5);
+// How long a target's message worker waits for more before it stops
+const WORKER_IDLE: Duration = Duration::from_secs(60);
+
+// `+cmd param` replies in the channel, `-cmd param` by notice
+static COMMAND: LazyLock<Regex> =
+    LazyLock::new(|| Regex::new(r"^([-+])([a-zA-Z\d-]+)(?:\s+(.*))?$").unwrap());
+
 pub async fn run<T>(path: T, color_ffi: extern "C" fn(*const c_char, *const c_char) -> ColorResult)
 where
     T: ToString,
 {
     let config = Config::load(path.to_string()).unwrap();
-    let mut interval = 1;
+    let settings = Arc::new(Settings::load(&path.to_string()));
+    // Outlive reconnects, so runtime `+access` and `+ignore` edits do too
+    let permissions = Arc::new(Permissions::new(&settings));
+    let ignores = Arc::new(Ignores::new(&settings));
+    let connections = Arc::new(Connections::new());
+    let mut reconnect = Reconnect::new(
+        &settings.reconnect,
+        reconnect::servers(&settings.servers, &config),
+    );
 
-    loop {
-        let plugin_manager = PluginManager::new(color_ffi);
-        plugin_manager
-            .timer_manager
-            .set_runtime(tokio::runtime::Handle::current());
-        plugin_manager.reload().unwrap();
+    // Loaded once; only what belongs to a connection is reset between them
+    let plugin_manager = PluginManager::new(
+        color_ffi,
+        settings.clone(),
+        permissions.clone(),
+        ignores.clone(),
+        connections.clone(),
+    );
+    plugin_manager
+        .timer_manager
+        .set_runtime(tokio::runtime::Handle::current());
+    plugin_manager.reload().unwrap();
 
-        let active_ref = plugin_manager.active.clone();
-        let timer_manager = plugin_manager.timer_manager.clone();
+    let watcher = plugin_manager.clone();
+    thread::spawn(move || watcher.watch());
 
-        thread::spawn(move || plugin_manager.watch());
+    loop {
+        let server = reconnect.server().clone();
+        println!("Connecting to {}", server);
 
+        let started = chrono::Local::now();
         let before = time::Instant::now();
-        run_client(&config, active_ref, timer_manager, color_ffi).await;
-        let after = time::Instant::now();
-        let difference = after - before;
-        interval = if difference.as_secs() > 300 {
-            1
-        } else {
-            interval
-        };
+        let outcome = run_client(&config, &server, plugin_manager.clone(), color_ffi).await;
+        let lasted = before.elapsed();
+        let wait = reconnect.next(&outcome, lasted);
 
+        let attempt = Attempt {
+            server: server.to_string(),
+            started,
+            lasted,
+            outcome,
+        };
         eprintln!(
-            "Disconnected. Waiting {} secs before trying again...",
-            interval
+            "{} {}. Waiting {:.1?} before connecting to {}...",
+            server,
+            attempt,
+            wait,
+            reconnect.server()
         );
+        connections.record(attempt);
 
-        time::sleep(Duration::from_secs(interval)).await;
-        interval = 2 * interval;
+        time::sleep(wait).await;
     }
 }
 
+// Connects to `server` and runs until the connection ends, returning how.
 async fn run_client(
     config: &Config,
-    active: Arc<RwLock<Vec<Plugin>>>,
-    timer_manager: Arc<TimerManager>,
+    server: &Server,
+    plugin_manager: PluginManager,
     color_ffi: extern "C" fn(*const c_char, *const c_char) -> ColorResult,
-) {
-    let mut client = Client::from_config(config.to_owned()).await.unwrap();
-    client.identify().unwrap();
-    let mut stream = client.stream().unwrap();
+) -> Outcome {
+    let config = &server.apply(config);
+    let mut services = Services::new(&plugin_manager.settings.services, config);
+    let mut client = match Client::from_config(Services::client_config(config)).await {
+        Ok(client) => client,
+        Err(e) => return Outcome::Failed(e.to_string()),
+    };
+    let mut negotiation = Negotiation::new(&plugin_manager.settings.capabilities);
+    if !negotiation.wanted()
+        && let Err(e) = client.identify()
+    {
+        return Outcome::Failed(e.to_string());
+    }
+    let mut stream = match client.stream() {
+        Ok(stream) => stream,
+        Err(e) => return Outcome::Failed(e.to_string()),
+    };
+    let outbound = Arc::new(Outbound::new(
+        Arc::new(client),
+        &plugin_manager.settings.flood,
+        &plugin_manager.settings.services,
+    ));
+    tokio::spawn(outbound.clone().run());
+    plugin_manager.host.connect(outbound.clone());
+    // Cancelled when the last connection ended
+    plugin_manager.restart_timers();
+
+    if negotiation.wanted() {
+        send_all(&outbound, negotiation.start(config));
+    }
+
+    let (tx, mut rx) = mpsc::unbounded_channel::<Message>();
+
+    let manager = plugin_manager.clone();
+    let queue = outbound.clone();
+    tokio::spawn(async move {
+        // One worker per target, so a channel's replies go out in the order
+        // its commands came in, and a slow plugin only holds up its own target
+        let mut workers: HashMap<String, mpsc::UnboundedSender<Message>> = HashMap::new();
+
+        while let Some(mut message) = rx.recv().await {
+            workers.retain(|_, worker| !worker.is_closed());
+
+            let target = message.response_target().unwrap_or_default().to_lowercase();
+            if let Some(worker) = workers.get(&target) {
+                match worker.send(message) {
+                    Ok(()) => continue,
+                    // It went idle and stopped in the meantime
+                    Err(unsent) => message = unsent.0,
+                }
+            }
+
+            let worker = spawn_worker(queue.clone(), manager.clone(), color_ffi);
+            worker.send(message).ok();
+            workers.insert(target, worker);
+        }
+    });
+
+    let mut registered = false;
+    // Why the server is about to close the connection, if it said
+    let mut closing: Option<Outcome> = None;
+    let mut watchdog = Watchdog::new(&plugin_manager.settings.watchdog);
+    let mut ticks = time::interval(TICK);
+
+    let reason = loop {
+        let message = tokio::select! {
+            message = stream.next() => message,
+            _ = ticks.tick() => {
+                match watchdog.tick() {
+                    // Straight out, so the lag is the server's and not our queue's
+                    Tick::Ping(ping) => {
+                        outbound.send_now(ping.into());
+                    }
+                    Tick::Stale(waited) => break format!("no reply to PING in {:.1?}", waited),
+                    Tick::Idle => (),
+                }
+
+                send_all(&outbound, services.tick(&outbound.nickname()));
+                continue;
+            }
+        };
+
+        let message = match message {
+            Some(Ok(message)) => message,
+            // Having no alternates, the client reports a refused nick as an
+            // error instead of passing the reply on
+            Some(Err(irc::error::Error::NoUsableNick)) => {
+                send_all(&outbound, services.rejected());
+                continue;
+            }
+            Some(Err(e)) => break e.to_string(),
+            None => break "connection closed".to_string(),
+        };
 
+        // Stamped with when the server saw it, if it says
+        let time = ircv3::server_time(&message).unwrap_or_else(chrono::Local::now);
+        print!("[{}] {}", time.format("%Y-%m-%d %H:%M:%S"), message);
+
+        if !negotiation.is_done() {
+            send_all(&outbound, negotiation.handle(&message));
+            if let Some(reason) = negotiation.refused() {
+                closing.get_or_insert_with(|| Outcome::Refused(reason.to_string()));
+            }
+        }
+
+        match &message.command {
+            Command::Response(Response::RPL_WELCOME, _) => {
+                registered = true;
+                plugin_manager.connections.connected(&server.to_string());
+                watchdog.start();
+            }
+            Command::Response(
+                Response::ERR_PASSWDMISMATCH | Response::ERR_YOUREBANNEDCREEP,
+                args,
+            ) => {
+                closing = Some(Outcome::Refused(args.last().cloned().unwrap_or_default()));
+            }
+            Command::ERROR(reason) if closing.is_none() => {
+                closing = Some(Outcome::from_error(reason, registered));
+            }
+            _ => (),
+        }
+
+        if let Some(lag) = watchdog.pong(&message) {
+            plugin_manager.connections.set_lag(lag);
+        }
+
+        // Before `outbound` takes in a NICK, so the bot's own is still known
+        let me = outbound.nickname();
+        send_all(&outbound, plugin_manager.channels.observe(&message, &me));
+        outbound.observe(&message);
+        send_all(&outbound, services.handle(&message, &outbound.nickname()));
+        tx.send(message).ok();
+    };
+
+    plugin_manager.timer_manager.cancel_all();
+    plugin_manager.host.disconnect();
+    plugin_manager.channels.reset();
+    outbound.close();
+
+    match closing {
+        Some(outcome) => outcome,
+        None if registered => Outcome::Disconnected(reason),
+        None => Outcome::Failed(reason),
+    }
+}
+
+// Handles one target's messages in turn, stopping once none have come for
+// `WORKER_IDLE`
+fn spawn_worker(
+    outbound: Arc<Outbound>,
+    plugin_manager: PluginManager,
+    color_ffi: extern "C" fn(*const c_char, *const c_char) -> ColorResult,
+) -> mpsc::UnboundedSender<Message> {
     let (tx, mut rx) = mpsc::unbounded_channel::<Message>();
 
-    let active_for_messages = active.clone();
     tokio::spawn(async move {
-        while let Some(message) = rx.recv().await {
-            let plugins = match active_for_messages.read() {
+        loop {
+            let message = match time::timeout(WORKER_IDLE, rx.recv()).await {
+                Ok(Some(message)) => message,
+                Ok(None) => return,
+                // Refuse anything more, but finish what was already sent
+                Err(_) => {
+                    rx.close();
+                    continue;
+                }
+            };
+
+            let dispatch = match plugin_manager.dispatch.read() {
                 Ok(g) => g.clone(),
                 _ => continue,
             };
 
-            if !handle_incoming_message(&client, &message, plugins, color_ffi).await {
-                eprintln!("Error handling message: {}", message.to_string());
+            if !handle_incoming_message(&outbound, &plugin_manager, &message, dispatch, color_ffi)
+                .await
+            {
+                eprintln!("Error handling message: {}", message);
             }
         }
     });
 
-    while let Ok(Some(message)) = stream.next().await.transpose() {
-        print!(
-            "[{}] {}",
-            chrono::Local::now().format("%Y-%m-%d %H:%M:%S"),
-            message
-        );
+    tx
+}
 
-        tx.send(message).ok();
+// Queues registration, services and other protocol lines ahead of everything
+// else
+fn send_all(outbound: &Outbound, commands: Vec<Command>) {
+    for command in commands {
+        outbound.send(Priority::Urgent, Level::Owner, command.into());
     }
-
-    timer_manager.cancel_all();
 }
 
 async fn handle_incoming_message(
-    client: &Client,
+    outbound: &Outbound,
+    plugin_manager: &PluginManager,
     message: &Message,
-    loaded_plugins: Vec<Plugin>,
+    dispatch: Arc<Dispatch>,
     color_ffi: extern "C" fn(*const c_char, *const c_char) -> ColorResult,
 ) -> bool {
+    let account = permissions::account(message);
+
+    if let Some(event) = Event::from_message(message) {
+        let level = plugin_manager
+            .permissions
+            .level(&event.source, account.as_deref());
+        if plugin_manager
+            .ignores
+            .is_ignored(&event.source, account.as_deref(), level)
+        {
+            return true;
+        }
+
+        return handle_event(
+            outbound,
+            &plugin_manager.executor,
+            &event,
+            &dispatch.plugins,
+            color_ffi,
+        )
+        .await;
+    }
+
     let ref msg = match message.command {
         Command::PRIVMSG(ref _channel, ref msg) => msg,
         Command::NOTICE(ref _channel, ref msg) => msg,
@@ -117,19 +346,58 @@ async fn handle_incoming_message(
     let author = Author::create(prefix, color_ffi);
     let nick: String = author.nick.to_string();
 
+    // With `echo-message` the server sends our own lines back
+    if nick.eq_ignore_ascii_case(&outbound.nickname()) {
+        return true;
+    }
+
+    let level = plugin_manager
+        .permissions
+        .level(&author.full, account.as_deref());
+
+    // Ignored users get neither commands nor listeners
+    if plugin_manager
+        .ignores
+        .is_ignored(&author.full, account.as_deref(), level)
+    {
+        return true;
+    }
+
+    let caller = Caller {
+        author: author.full.clone(),
+        level,
+        tags: ircv3::tags(message),
+    };
+
     let response_target = match message.response_target() {
         Some(target) => target,
         None => return true,
     };
 
-    let re = Regex::new(r"^([-+])([a-zA-Z\d-]+)(?:\s+(.*))?$").unwrap();
-    let matched = match re.captures(msg) {
+    let matched = match COMMAND.captures(msg) {
         Some(matched) => vec![matched],
         None => vec![],
     };
 
-    // if the regex match fails, just return
+    // Ordinary chat lines only go to passive listeners
     if matched.is_empty() {
+        if matches!(message.command, Command::PRIVMSG(..)) && events::is_channel(response_target) {
+            return handle_listeners(
+                outbound,
+                plugin_manager,
+                response_target,
+                &dispatch.plugins,
+                &author,
+                &caller,
+                msg,
+            )
+            .await;
+        }
+        return true;
+    }
+
+    // Answering notices is how bots end up answering each other
+    if matches!(message.command, Command::NOTICE(..)) && !plugin_manager.ignores.notice_commands() {
         return true;
     }
 
@@ -146,9 +414,37 @@ async fn handle_incoming_message(
         None => "",
     };
 
-    let respond_method: fn(&Client, &str, &str) -> bool = match trigger {
-        "-" => process_notice,
-        "+" | _ => process_privmsg,
+    // Chat that only looks like a command, like "+1", isn't one, so it isn't
+    // counted or limited
+    if !dispatch.answers(cmd) {
+        return true;
+    }
+
+    // Only lines a plugin would run count as repeats; built-ins like `+more`
+    // are repeated on purpose
+    if dispatch.runs_plugin(cmd, level)
+        && let Some(mask) = plugin_manager.ignores.record(&author.full, level, msg)
+    {
+        println!("Ignoring {} for repeating '{}'", mask, msg);
+        return true;
+    }
+
+    // Admins aren't limited, and neither are timers, which never get here
+    if level < Level::Admin {
+        let mut keys = vec![(Scope::User, author.full.as_str())];
+        if events::is_channel(response_target) {
+            keys.push((Scope::Channel, response_target));
+        }
+
+        let verdict = plugin_manager.rate_limiter.check(&author.full, None, &keys);
+        if !allowed(outbound, &author, verdict) {
+            return true;
+        }
+    }
+
+    let kind = match trigger {
+        "-" => Kind::Notice,
+        _ => Kind::Privmsg,
     };
 
     let target = match trigger {
@@ -157,12 +453,14 @@ async fn handle_incoming_message(
     };
 
     handle_messages(
-        respond_method,
-        client,
+        kind,
+        outbound,
+        plugin_manager,
         target,
         response_target,
-        &loaded_plugins,
+        &dispatch,
         author,
+        caller,
         cmd,
         param,
     )
@@ -170,182 +468,690 @@ async fn handle_incoming_message(
 }
 
 async fn handle_messages(
-    respond_method: fn(&Client, &str, &str) -> bool,
-    client: &Client,
+    // How replies are sent unless a structured output line says otherwise
+    kind: Kind,
+    outbound: &Outbound,
+    plugin_manager: &PluginManager,
     target: &str,
     // The channel the command originated in (used to scope per-channel plugin
     // state). Distinct from `target`, which is where the reply is sent — for a
     // `-` notice that's the nick, but the state still belongs to the channel.
     channel: &str,
-    loaded_plugins: &Vec<Plugin>,
+    dispatch: &Dispatch,
     author: Author,
+    caller: Caller,
     cmd: &str,
     param: &str,
 ) -> bool {
+    let level = caller.level;
+
     match cmd {
-        "help" => {
-            let commands = loaded_plugins
+        "help" if param.is_empty() => {
+            let output = help::overview(&dispatch.plugins, &author)
                 .iter()
-                .map(|plugin| plugin.commands.to_owned())
-                .flatten()
-                .collect::<Vec<String>>();
+                .map(|output| Item::plain(output))
+                .collect();
+            let output = split_items(|command| outbound.budget(command), kind, target, output);
+            let page = plugin_manager
+                .pager
+                .first(channel, &author.full, kind, target, output);
+            send_page(outbound, Priority::Reply, &page, &author, level);
+
+            return true;
+        }
+        "help" => {
+            let output = help::detail(&dispatch.plugins, param, &author);
+            process_item(
+                outbound,
+                Priority::Reply,
+                level,
+                kind,
+                target,
+                &Item::plain(&output),
+            );
+
+            return true;
+        }
+        "plugins" | "access" | "ignore" | "queue" | "servers" | "lag" | "channel"
+            if level < Level::Admin =>
+        {
+            let output = [author.l(cmd), author.c1("admins only")].join(" ");
+            process_item(
+                outbound,
+                Priority::Reply,
+                level,
+                kind,
+                target,
+                &Item::plain(&output),
+            );
+
+            return true;
+        }
+        "plugins" => {
+            for output in admin::plugins(plugin_manager, param, &author).await {
+                process_item(
+                    outbound,
+                    Priority::Reply,
+                    level,
+                    kind,
+                    target,
+                    &Item::plain(&output),
+                );
+            }
+
+            return true;
+        }
+        "access" => {
+            for output in admin::access(&plugin_manager.permissions, param, &author, level) {
+                process_item(
+                    outbound,
+                    Priority::Reply,
+                    level,
+                    kind,
+                    target,
+                    &Item::plain(&output),
+                );
+            }
 
-            let output = vec![author.l("Commands"), author.c1(&commands.join(", "))].join(" ");
+            return true;
+        }
+        "ignore" => {
+            for output in admin::ignore(&plugin_manager.ignores, param, &author) {
+                process_item(
+                    outbound,
+                    Priority::Reply,
+                    level,
+                    kind,
+                    target,
+                    &Item::plain(&output),
+                );
+            }
 
-            respond_method(client, &target, &output);
+            return true;
+        }
+        "more" => {
+            match plugin_manager.pager.more(channel, &author.full) {
+                Some(page) => send_page(outbound, Priority::Bulk, &page, &author, level),
+                None => {
+                    let output = [author.l("More"), author.c1("nothing more to show")].join(" ");
+                    process_item(
+                        outbound,
+                        Priority::Reply,
+                        level,
+                        kind,
+                        target,
+                        &Item::plain(&output),
+                    );
+                }
+            }
+
+            return true;
+        }
+        "lag" => {
+            let output = admin::lag(&plugin_manager.connections, &author);
+            process_item(
+                outbound,
+                Priority::Reply,
+                level,
+                kind,
+                target,
+                &Item::plain(&output),
+            );
+
+            return true;
+        }
+        "channel" => {
+            let name = if param.is_empty() { channel } else { param };
+            let me = outbound.nickname();
+            for output in admin::channel(&plugin_manager.channels, name, &me, &author) {
+                process_item(
+                    outbound,
+                    Priority::Reply,
+                    level,
+                    kind,
+                    target,
+                    &Item::plain(&output),
+                );
+            }
+
+            return true;
+        }
+        "servers" => {
+            for output in admin::servers(&plugin_manager.connections, &author) {
+                process_item(
+                    outbound,
+                    Priority::Reply,
+                    level,
+                    kind,
+                    target,
+                    &Item::plain(&output),
+                );
+            }
+
+            return true;
+        }
+        "queue" => {
+            for output in admin::queue(outbound, &author) {
+                process_item(
+                    outbound,
+                    Priority::Reply,
+                    level,
+                    kind,
+                    target,
+                    &Item::plain(&output),
+                );
+            }
 
             return true;
         }
         _ => (),
     };
 
-    // Catch commands that are handled by plugins
-    for plugin in loaded_plugins {
-        for command in &plugin.triggers {
-            let re = match Regex::new(&command) {
-                Ok(re) => re,
-                Err(e) => {
-                    println!("Error loading regex: {}", e);
-                    continue;
-                }
-            };
+    // The lowest level that would have run something, if the caller's didn't
+    let mut denied: Option<Level> = None;
+    let mut ran = false;
+    // Everything the plugins said, paged once they're done
+    let mut output = vec![];
+
+    // Catch commands that are handled by plugins, highest priority first,
+    // until one of them says it handled the command
+    for (plugin, required) in dispatch.matching(cmd) {
+        if level < required {
+            denied = Some(denied.map_or(required, |denied| denied.min(required)));
+            continue;
+        }
+        ran = true;
+
+        if level < Level::Admin {
+            let key = ratelimit::command_key(&plugin.name, cmd);
+            let verdict = plugin_manager.rate_limiter.check(
+                &author.full,
+                Some(&plugin.name),
+                &[(Scope::Command, &key)],
+            );
+            if !allowed(outbound, &author, verdict) {
+                break;
+            }
+        }
+
+        // Pass the command, query, and caller to the plugin
+        let results = match plugin_manager
+            .executor
+            .call(plugin, cmd, param, &caller, channel, author.color)
+            .await
+        {
+            Ok(results) => results,
+            Err(CallError::TimedOut(timeout)) => {
+                println!("Plugin {} timed out running '{}'", plugin.name, cmd);
+                let output = format!("{} timed out after {}s", cmd, timeout.as_secs());
+                vec![[author.l("Error"), author.c1(&output)].join(" ")]
+            }
+            Err(CallError::Busy) => {
+                println!("Plugin {} was too busy to run '{}'", plugin.name, cmd);
+                let output = format!("{} is busy, try again shortly", cmd);
+                vec![[author.l("Error"), author.c1(&output)].join(" ")]
+            }
+            Err(e) => {
+                println!("Error running plugin {}: {}", plugin.name, e);
+                vec![[author.l("Error"), author.c1(&e.to_string())].join(" ")]
+            }
+        };
+
+        let mut handled = false;
+        for line in results {
+            if line.is_empty() {
+                continue;
+            }
+
+            let item = Item::parse(&line, plugin.structured_output());
+            handled |= item.handled;
+            if !item.text.is_empty() {
+                output.push(item);
+            }
+        }
+
+        if handled {
+            break;
+        }
+    }
+
+    if !output.is_empty() {
+        let output = split_items(|command| outbound.budget(command), kind, target, output);
+        let page = plugin_manager
+            .pager
+            .first(channel, &author.full, kind, target, output);
+        send_page(outbound, Priority::Bulk, &page, &author, level);
+    }
+
+    if let (false, Some(required)) = (ran, denied) {
+        let output = format!("{} needs {} access", cmd, required);
+        process_item(
+            outbound,
+            Priority::Reply,
+            level,
+            kind,
+            target,
+            &Item::plain(&[author.l("Error"), author.c1(&output)].join(" ")),
+        );
+    }
+
+    true
+}
+
+// Sends a page of command output, then says how much is left for `+more`
+fn send_page(outbound: &Outbound, priority: Priority, page: &Page, author: &Author, level: Level) {
+    for item in &page.items {
+        process_item(outbound, priority, level, page.kind, &page.target, item);
+    }
+
+    if page.remaining > 0 {
+        let output = format!("{} more lines, type +more", page.remaining);
+        process_item(
+            outbound,
+            priority,
+            level,
+            page.kind,
+            &page.target,
+            &Item::plain(&[author.l("More"), author.c1(&output)].join(" ")),
+        );
+    }
+}
+
+// Whether a rate-limited command may run. The first time a sender is
+// limited they're told, quietly, by notice; after that it's just dropped.
+fn allowed(outbound: &Outbound, author: &Author, verdict: Verdict) -> bool {
+    match verdict {
+        Verdict::Allowed => true,
+        Verdict::Limited { wait, warn } => {
+            if warn {
+                println!("Rate limiting {}", author.full);
+                let output = format!(
+                    "too many commands, try again in {}s",
+                    wait.as_secs_f64().ceil()
+                );
+                // Only those below admin are ever limited
+                process_item(
+                    outbound,
+                    Priority::Reply,
+                    Level::User,
+                    Kind::Notice,
+                    &author.nick,
+                    &Item::plain(&[author.l("Slow down"), author.c1(&output)].join(" ")),
+                );
+            }
+            false
+        }
+    }
+}
 
-            match re.captures(&cmd) {
-                Some(_) => (),
+// Runs every listener enabled in `channel` whose regex matches `line` and
+// isn't cooling down, sending output to the channel.
+async fn handle_listeners(
+    outbound: &Outbound,
+    plugin_manager: &PluginManager,
+    channel: &str,
+    loaded_plugins: &[Plugin],
+    author: &Author,
+    caller: &Caller,
+    line: &str,
+) -> bool {
+    for plugin in loaded_plugins {
+        for listener in &plugin.listeners {
+            let captures = match listeners::captures(listener, line) {
+                Some(captures) => captures,
                 None => continue,
             };
 
-            let lib = match unsafe { Library::new(plugin.name.clone()) } {
-                Ok(lib) => lib,
+            if !plugin_manager
+                .listeners
+                .try_fire(&plugin.name, listener, channel)
+            {
+                continue;
+            }
+
+            let results = match plugin_manager
+                .executor
+                .listen(
+                    plugin,
+                    &listener.name,
+                    captures,
+                    caller,
+                    channel,
+                    author.color,
+                )
+                .await
+            {
+                Ok(results) => results,
                 Err(e) => {
-                    println!("Error loading plugin: {}", e);
+                    println!(
+                        "Error running listener {} of plugin {}: {}",
+                        listener.name, plugin.name, e
+                    );
                     continue;
                 }
             };
 
-            let host = author.full.to_string();
-            let cmd = cmd.to_string();
-            let param = param.to_string();
-
-            // Pass the command, query, and author to the plugin
-            let results = unsafe {
-                // Load the "exported" function from the plugin
-                let exported: Symbol<extern "C" fn(context: &PluginContext) -> *mut c_char> =
-                    match lib.get(b"exported\0") {
-                        Ok(exported) => exported,
-                        Err(e) => {
-                            println!("Error loading plugin: {}", e);
-                            return false;
-                        }
-                    };
-                // Convert the command, query, and author to C strings
-                let cstr_cmd = match CString::new(cmd) {
-                    Ok(cmd) => cmd.into_raw(),
-                    Err(_) => return false,
-                };
-                let cstr_param = match CString::new(param) {
-                    Ok(param) => param.into_raw(),
-                    Err(_) => return false,
-                };
-                let cstr_author = match CString::new(host) {
-                    Ok(author) => author.into_raw(),
-                    Err(_) => return false,
-                };
-                let cstr_channel = match CString::new(channel) {
-                    Ok(channel) => channel.into_raw(),
-                    Err(_) => return false,
-                };
-
-                let context: PluginContext = PluginContext {
-                    cmd: cstr_cmd,
-                    param: cstr_param,
-                    author: cstr_author,
-                    color: author.color,
-                    channel: cstr_channel,
-                };
-
-                let raw_results = exported(&context);
-
-                let output = match CStr::from_ptr(raw_results).to_str() {
-                    Ok(results) => results
-                        .split("\n")
-                        .map(|s| s.to_string())
-                        .collect::<Vec<String>>(),
-                    _ => vec![],
-                };
-
-                _ = CString::from_raw(raw_results);
-                _ = CString::from_raw(cstr_channel);
-                _ = CString::from_raw(cstr_author);
-                _ = CString::from_raw(cstr_param);
-                _ = CString::from_raw(cstr_cmd);
-
-                output
-            };
+            for line in results.iter().filter(|line| !line.is_empty()) {
+                process_item(
+                    outbound,
+                    Priority::Bulk,
+                    caller.level,
+                    Kind::Privmsg,
+                    channel,
+                    &Item::parse(line, plugin.structured_output()),
+                );
+            }
+        }
+    }
 
-            for line in results {
-                if line.is_empty() {
-                    continue;
-                }
-                respond_method(&client, target, &line);
+    true
+}
+
+// Delivers an event to every plugin subscribed to it, routing output to the
+// event's channel or, failing that, the user behind it.
+async fn handle_event(
+    outbound: &Outbound,
+    executor: &Executor,
+    event: &Event,
+    loaded_plugins: &[Plugin],
+    color_ffi: extern "C" fn(*const c_char, *const c_char) -> ColorResult,
+) -> bool {
+    for plugin in loaded_plugins {
+        if !plugin.subscribes(&event.kind) {
+            continue;
+        }
+
+        let results = match executor.event(plugin, event, color_ffi).await {
+            Ok(results) => results,
+            Err(e) => {
+                println!(
+                    "Error delivering {} to plugin {}: {}",
+                    event.kind, plugin.name, e
+                );
+                continue;
+            }
+        };
+
+        for line in results.iter().filter(|line| !line.is_empty()) {
+            let item = Item::parse(line, plugin.structured_output());
+            let target = event.reply_target().unwrap_or_default();
+
+            // Raw lines carry their own target
+            if target.is_empty() && item.target.is_none() && item.kind != Some(Kind::Raw) {
+                println!(
+                    "Dropping output from plugin {} for {}: nowhere to send it",
+                    plugin.name, event.kind
+                );
+                continue;
             }
+
+            process_item(
+                outbound,
+                Priority::Bulk,
+                Level::User,
+                Kind::Privmsg,
+                target,
+                &item,
+            );
         }
     }
 
     true
 }
 
-fn process_privmsg(client: &Client, target: &str, message: &str) -> bool {
-    process_message(send_privmsg, client, target, message)
+/// Queues one output line, falling back to the reply's default kind and
+/// target for anything the line doesn't specify.
+pub fn process_item(
+    outbound: &Outbound,
+    priority: Priority,
+    level: Level,
+    kind: Kind,
+    target: &str,
+    item: &Item,
+) -> bool {
+    let target = item.target.as_deref().unwrap_or(target);
+
+    let function = match formatter(item.kind.unwrap_or(kind)) {
+        Some(function) => function,
+        None => return send_raw(outbound, priority, level, &item.text),
+    };
+
+    if item.split {
+        process_message(outbound, priority, level, function, target, &item.text)
+    } else {
+        outbound.send(priority, level, function(target, &item.text).into())
+    }
 }
 
-fn send_privmsg(client: &Client, target: &str, message: &str) -> bool {
-    match client.send_privmsg(target, message) {
-        Ok(_) => true,
-        Err(e) => {
-            println!("Error sending message: {}", e);
-            false
-        }
+// Splits items that may be split into the lines they'll go out as, so a cap
+// on lines counts what the target will actually see. `budget` is how much
+// text fits in a command, as `Outbound::budget` says.
+fn split_items(
+    budget: impl Fn(&Command) -> usize,
+    kind: Kind,
+    target: &str,
+    items: Vec<Item>,
+) -> Vec<Item> {
+    items
+        .into_iter()
+        .flat_map(|item| {
+            let function = match formatter(item.kind.unwrap_or(kind)) {
+                Some(function) if item.split => function,
+                _ => return vec![item],
+            };
+
+            let target = item.target.as_deref().unwrap_or(target);
+            split::split(&item.text, budget(&function(target, "")))
+                .into_iter()
+                .map(|line| Item {
+                    text: line,
+                    split: false,
+                    ..item.clone()
+                })
+                .collect()
+        })
+        .collect()
+}
+
+// How to build a line of each kind, or `None` for raw lines
+fn formatter(kind: Kind) -> Option<fn(&str, &str) -> Command> {
+    match kind {
+        Kind::Privmsg => Some(privmsg),
+        Kind::Notice => Some(notice),
+        Kind::Action => Some(action),
+        Kind::Raw => None,
     }
 }
 
-fn process_notice(client: &Client, target: &str, message: &str) -> bool {
-    process_message(send_notice, client, target, message)
+fn privmsg(target: &str, message: &str) -> Command {
+    Command::PRIVMSG(target.to_string(), message.to_string())
 }
 
-fn send_notice(client: &Client, target: &str, message: &str) -> bool {
-    match client.send_notice(target, message) {
-        Ok(_) => true,
+fn notice(target: &str, message: &str) -> Command {
+    Command::NOTICE(target.to_string(), message.to_string())
+}
+
+fn action(target: &str, message: &str) -> Command {
+    Command::PRIVMSG(target.to_string(), format!("\u{1}ACTION {}\u{1}", message))
+}
+
+fn send_raw(outbound: &Outbound, priority: Priority, level: Level, line: &str) -> bool {
+    if !response::raw_allowed(line) {
+        println!("Refusing to send raw line '{}'", line);
+        return false;
+    }
+
+    let message = match line.parse::<Message>() {
+        Ok(message) => message,
         Err(e) => {
-            println!("Error sending notice: {}", e);
-            false
+            println!("Error parsing raw line '{}': {}", line, e);
+            return false;
         }
-    }
+    };
+
+    outbound.send(priority, level, message)
 }
 
 fn process_message(
-    function: fn(&Client, &str, &str) -> bool,
-    client: &Client,
+    outbound: &Outbound,
+    priority: Priority,
+    level: Level,
+    function: fn(&str, &str) -> Command,
     target: &str,
     message: &str,
 ) -> bool {
-    let mut output: Vec<&str> = Vec::new();
-
-    let words = message.split_whitespace();
-    let flush = |out: &mut Vec<&str>| {
-        let joined = out.join(" ");
-        let ok = function(client, target, &joined);
-        out.clear();
-        ok
-    };
+    // The server adds our prefix to the line, so that counts too
+    let budget = outbound.budget(&function(target, ""));
+    let lines = split::split(message, budget);
 
-    for word in words {
-        output.push(word);
+    !lines.is_empty()
+        && lines
+            .iter()
+            .all(|line| outbound.send(priority, level, function(target, line).into()))
+}
 
-        if output.join(" ").len() >= 400 && !flush(&mut output) {
-            return false;
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use common::author::cache::color_ffi;
+    use tokio::io::{AsyncBufReadExt, AsyncWriteExt, BufReader};
+    use tokio::net::TcpListener;
+
+    // Plays a server through capability negotiation and SASL: each line it
+    // sends, once the client has sent a line starting with the one before
+    // it. Returns everything the client sent, in order.
+    async fn scripted_server(listener: TcpListener, script: &[(&str, &str)]) -> Vec<String> {
+        let (stream, _) = listener.accept().await.unwrap();
+        let (read, mut write) = stream.into_split();
+        let mut lines = BufReader::new(read).lines();
+        let mut received = vec![];
+
+        for (waiting_for, reply) in script {
+            while !received
+                .iter()
+                .any(|line: &String| line.starts_with(waiting_for))
+            {
+                let line = time::timeout(Duration::from_secs(10), lines.next_line())
+                    .await
+                    .expect("client went quiet")
+                    .unwrap()
+                    .expect("client hung up");
+                received.push(line);
+            }
+            write
+                .write_all(format!("{}\r\n", reply).as_bytes())
+                .await
+                .unwrap();
         }
+
+        received
+    }
+
+    #[test]
+    fn test_split_items() {
+        let long = "word ".repeat(10);
+        let items = vec![
+            Item::plain(long.trim()),
+            Item {
+                split: false,
+                ..Item::plain(long.trim())
+            },
+            Item::parse(
+                r#"{"kind": "raw", "text": "TOPIC #rshelp :kick kick kick"}"#,
+                true,
+            ),
+        ];
+
+        // Room for two words a line
+        let split = split_items(|_| 10, Kind::Privmsg, "#rshelp", items);
+        assert_eq!(split.len(), 5 + 1 + 1);
+        assert!(
+            split[..5]
+                .iter()
+                .all(|item| item.text == "word word" && !item.split)
+        );
+        assert_eq!(split[5].text, long.trim());
+        assert_eq!(split[6].kind, Some(Kind::Raw));
     }
 
-    !output.is_empty() && flush(&mut output)
+    #[tokio::test]
+    async fn test_run_client_negotiates_before_registering() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let port = listener.local_addr().unwrap().port();
+
+        let settings = Arc::new(
+            Settings::parse(
+                r#"
+                [flood]
+                burst = 20
+
+                [capabilities]
+                request = ["server-time"]
+
+                [capabilities.sasl]
+                mechanism = "plain"
+                account = "RustKick"
+                password = "hunter2"
+                "#,
+            )
+            .unwrap(),
+        );
+        let plugin_manager = PluginManager::new(
+            color_ffi,
+            settings.clone(),
+            Arc::new(Permissions::new(&settings)),
+            Arc::new(Ignores::new(&settings)),
+            Arc::new(Connections::new()),
+        );
+        let config = Config {
+            nickname: Some("RustKick".to_string()),
+            ..Default::default()
+        };
+        let server = Server {
+            host: "127.0.0.1".to_string(),
+            port: Some(port),
+            use_tls: Some(false),
+            password: None,
+        };
+
+        let script = [
+            ("USER ", ":irc.example CAP * LS :server-time sasl=PLAIN"),
+            (
+                "CAP REQ :server-time sasl",
+                ":irc.example CAP * ACK :server-time sasl",
+            ),
+            ("AUTHENTICATE PLAIN", "AUTHENTICATE +"),
+            (
+                "AUTHENTICATE UnVzdEtpY2sAUnVzdEtpY2sAaHVudGVyMg==",
+                ":irc.example 903 RustKick :SASL authentication successful",
+            ),
+            ("CAP END", ":irc.example 001 RustKick :Welcome"),
+            ("CAP END", "ERROR :Closing Link: RustKick (Quit)"),
+        ];
+        let server_side = tokio::spawn(async move { scripted_server(listener, &script).await });
+
+        let outcome = time::timeout(
+            Duration::from_secs(10),
+            run_client(&config, &server, plugin_manager, color_ffi),
+        )
+        .await
+        .expect("run_client never returned");
+        assert!(
+            matches!(outcome, Outcome::Disconnected(..)),
+            "{:?}",
+            outcome
+        );
+
+        let received = server_side.await.unwrap();
+        let position = |line: &str| {
+            received
+                .iter()
+                .position(|sent| sent.starts_with(line))
+                .unwrap_or_else(|| panic!("never sent '{}' in {:?}", line, received))
+        };
+        assert_eq!(position("CAP LS 302"), 0);
+        assert!(position("NICK RustKick") < position("CAP REQ"));
+        assert!(position("USER ") < position("CAP REQ"));
+        assert!(position("AUTHENTICATE PLAIN") < position("CAP END"));
+    }
 }
@@ -0,0 +1,480 @@
+use irc::client::prelude::{Command, Message, Mode, Response};
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::sync::RwLock;
+
+// Until the server's ISUPPORT says otherwise
+const DEFAULT_PREFIX: &str = "(qaohv)~&@%+";
+const DEFAULT_CHANMODES: &str = "beI,k,l,imnpst";
+
+/// Someone in a channel.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Member {
+    pub nick: String,
+    /// Prefix modes held, highest first, e.g. "ov".
+    pub modes: String,
+}
+
+impl Member {
+    pub fn has(&self, mode: char) -> bool {
+        self.modes.contains(mode)
+    }
+}
+
+/// A channel the bot is in.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Channel {
+    pub name: String,
+    /// Channel modes set, with their parameter if they take one.
+    pub modes: BTreeMap<char, Option<String>>,
+    // Keyed by lowercased nick
+    members: BTreeMap<String, Member>,
+}
+
+impl Channel {
+    fn new(name: &str) -> Self {
+        Self {
+            name: name.to_string(),
+            modes: BTreeMap::new(),
+            members: BTreeMap::new(),
+        }
+    }
+
+    pub fn member(&self, nick: &str) -> Option<&Member> {
+        self.members.get(&nick.to_lowercase())
+    }
+
+    pub fn members(&self) -> impl Iterator<Item = &Member> {
+        self.members.values()
+    }
+
+    /// The modes as the server would show them, e.g. `+klnt 50`, except that
+    /// the channel key is never shown.
+    pub fn mode_string(&self) -> String {
+        let letters = self.modes.keys().collect::<String>();
+        let params = self
+            .modes
+            .iter()
+            .filter(|(mode, _)| **mode != 'k')
+            .filter_map(|(_, param)| param.as_ref());
+        std::iter::once(format!("+{}", letters))
+            .chain(params.cloned())
+            .collect::<Vec<String>>()
+            .join(" ")
+    }
+}
+
+#[derive(Default)]
+struct State {
+    channels: HashMap<String, Channel>,
+    // Prefix modes and their symbols, highest first
+    prefixes: Vec<(char, char)>,
+    // Type A modes: lists like bans, which aren't kept
+    lists: String,
+    // Modes that take a parameter when set
+    params: String,
+    // Channels whose NAMES are coming in
+    syncing: HashSet<String>,
+}
+
+impl State {
+    fn new() -> Self {
+        let mut state = Self::default();
+        state.prefix(DEFAULT_PREFIX);
+        state.chanmodes(DEFAULT_CHANMODES);
+        state
+    }
+
+    fn prefix(&mut self, value: &str) {
+        if let Some((modes, symbols)) = value
+            .strip_prefix('(')
+            .and_then(|value| value.split_once(')'))
+        {
+            self.prefixes = modes.chars().zip(symbols.chars()).collect();
+        }
+    }
+
+    fn chanmodes(&mut self, value: &str) {
+        let groups = value.split(',').collect::<Vec<&str>>();
+        if groups.len() < 3 {
+            return;
+        }
+        self.lists = groups[0].to_string();
+        self.params = [groups[1], groups[2]].concat();
+    }
+
+    fn is_prefix(&self, mode: char) -> bool {
+        self.prefixes.iter().any(|(prefix, _)| *prefix == mode)
+    }
+
+    // Splits `@+nick` into its prefix modes and the nick, dropping any
+    // `!user@host` from `userhost-in-names`
+    fn name(&self, name: &str) -> (String, String) {
+        let mut modes = String::new();
+        let mut rest = name;
+        while let Some(symbol) = rest.chars().next() {
+            match self.prefixes.iter().find(|(_, prefix)| *prefix == symbol) {
+                Some((mode, _)) => modes.push(*mode),
+                None => break,
+            }
+            rest = &rest[symbol.len_utf8()..];
+        }
+        let nick = rest.split('!').next().unwrap_or(rest);
+        (self.ranked(&modes), nick.to_string())
+    }
+
+    // `modes` in the server's order, highest first
+    fn ranked(&self, modes: &str) -> String {
+        self.prefixes
+            .iter()
+            .map(|(mode, _)| *mode)
+            .filter(|mode| modes.contains(*mode))
+            .collect()
+    }
+
+    fn channel(&mut self, name: &str) -> Option<&mut Channel> {
+        self.channels.get_mut(&name.to_lowercase())
+    }
+
+    fn set_mode(&mut self, channel: &str, mode: char, plus: bool, param: Option<String>) {
+        let lists = self.lists.contains(mode);
+        let prefix = self.is_prefix(mode);
+        let ranked = match (prefix, &param) {
+            (true, Some(nick)) => self
+                .channels
+                .get(&channel.to_lowercase())
+                .and_then(|channel| channel.member(nick))
+                .map(|member| {
+                    let mut modes = member.modes.replace(mode, "");
+                    if plus {
+                        modes.push(mode);
+                    }
+                    self.ranked(&modes)
+                }),
+            _ => None,
+        };
+
+        let channel = match self.channel(channel) {
+            Some(channel) => channel,
+            None => return,
+        };
+
+        if prefix {
+            if let (Some(modes), Some(nick)) = (ranked, param)
+                && let Some(member) = channel.members.get_mut(&nick.to_lowercase())
+            {
+                member.modes = modes;
+            }
+        } else if lists {
+            // Ban lists and the like aren't tracked
+        } else if plus {
+            channel.modes.insert(mode, param);
+        } else {
+            channel.modes.remove(&mode);
+        }
+    }
+}
+
+/// Which channels the bot is in, who's in them and their modes, kept up to
+/// date from NAMES, JOIN, PART, QUIT, KICK, NICK and MODE. Reset on every
+/// reconnect.
+pub struct Channels {
+    state: RwLock<State>,
+}
+
+impl Default for Channels {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Channels {
+    pub fn new() -> Self {
+        Self {
+            state: RwLock::new(State::new()),
+        }
+    }
+
+    /// Forgets everything, for a new connection.
+    pub fn reset(&self) {
+        if let Ok(mut state) = self.state.write() {
+            *state = State::new();
+        }
+    }
+
+    /// Updates the state from a message the server sent, `me` being the
+    /// bot's nick. Returns the lines to send: a MODE query for each channel
+    /// the bot joins.
+    pub fn observe(&self, message: &Message, me: &str) -> Vec<Command> {
+        let mut state = match self.state.write() {
+            Ok(state) => state,
+            Err(_) => return vec![],
+        };
+        let source = message.source_nickname().unwrap_or_default();
+        let ours = source.eq_ignore_ascii_case(me);
+
+        match &message.command {
+            Command::Response(Response::RPL_ISUPPORT, args) => {
+                for token in args.iter().skip(1) {
+                    match token.split_once('=') {
+                        Some(("PREFIX", value)) => state.prefix(value),
+                        Some(("CHANMODES", value)) => state.chanmodes(value),
+                        _ => (),
+                    }
+                }
+            }
+            Command::JOIN(channel, _, _) if ours => {
+                state
+                    .channels
+                    .insert(channel.to_lowercase(), Channel::new(channel));
+                return vec![Command::Raw("MODE".to_string(), vec![channel.clone()])];
+            }
+            Command::JOIN(channel, _, _) => {
+                if let Some(channel) = state.channel(channel) {
+                    channel.members.insert(
+                        source.to_lowercase(),
+                        Member {
+                            nick: source.to_string(),
+                            modes: String::new(),
+                        },
+                    );
+                }
+            }
+            Command::PART(channel, _) => part(&mut state, channel, source, ours),
+            Command::KICK(channel, nick, _) => {
+                part(&mut state, channel, nick, nick.eq_ignore_ascii_case(me))
+            }
+            Command::QUIT(_) => {
+                for channel in state.channels.values_mut() {
+                    channel.members.remove(&source.to_lowercase());
+                }
+            }
+            Command::NICK(nick) => {
+                for channel in state.channels.values_mut() {
+                    if let Some(mut member) = channel.members.remove(&source.to_lowercase()) {
+                        member.nick = nick.clone();
+                        channel.members.insert(nick.to_lowercase(), member);
+                    }
+                }
+            }
+            Command::ChannelMODE(channel, modes) => {
+                for mode in modes {
+                    let (plus, mode, param) = match mode {
+                        Mode::Plus(mode, param) => (true, mode, param),
+                        Mode::Minus(mode, param) => (false, mode, param),
+                        Mode::NoPrefix(_) => continue,
+                    };
+                    if let Some(letter) = mode.to_string().chars().next() {
+                        state.set_mode(channel, letter, plus, param.clone());
+                    }
+                }
+            }
+            // `<me> <channel> <modes> [params...]`, answering our MODE query
+            Command::Response(Response::RPL_CHANNELMODEIS, args) if args.len() >= 3 => {
+                let params = state.params.clone();
+                let mut values = args[3..].iter();
+                let modes = args[2]
+                    .trim_start_matches('+')
+                    .chars()
+                    .map(|mode| {
+                        let value = params.contains(mode).then(|| values.next().cloned());
+                        (mode, value.flatten())
+                    })
+                    .collect::<BTreeMap<char, Option<String>>>();
+                if let Some(channel) = state.channel(&args[1]) {
+                    channel.modes = modes;
+                }
+            }
+            // `<me> <symbol> <channel> :<names>`; the first reply of a
+            // listing replaces whoever we thought was there
+            Command::Response(Response::RPL_NAMREPLY, args) if args.len() >= 4 => {
+                let key = args[2].to_lowercase();
+                let names = args[3]
+                    .split_whitespace()
+                    .map(|name| state.name(name))
+                    .collect::<Vec<(String, String)>>();
+                let first = state.syncing.insert(key.clone());
+
+                if let Some(channel) = state.channels.get_mut(&key) {
+                    if first {
+                        channel.members.clear();
+                    }
+                    for (modes, nick) in names {
+                        channel
+                            .members
+                            .insert(nick.to_lowercase(), Member { nick, modes });
+                    }
+                }
+            }
+            Command::Response(Response::RPL_ENDOFNAMES, args) if args.len() >= 2 => {
+                state.syncing.remove(&args[1].to_lowercase());
+            }
+            _ => (),
+        }
+
+        vec![]
+    }
+
+    /// Whether the bot is in `channel`.
+    pub fn contains(&self, channel: &str) -> bool {
+        self.state
+            .read()
+            .map(|state| state.channels.contains_key(&channel.to_lowercase()))
+            .unwrap_or(false)
+    }
+
+    pub fn channel(&self, name: &str) -> Option<Channel> {
+        let state = self.state.read().ok()?;
+        state.channels.get(&name.to_lowercase()).cloned()
+    }
+
+    /// The names of the channels the bot is in, sorted.
+    pub fn names(&self) -> Vec<String> {
+        let mut names = match self.state.read() {
+            Ok(state) => state
+                .channels
+                .values()
+                .map(|channel| channel.name.clone())
+                .collect::<Vec<String>>(),
+            Err(_) => vec![],
+        };
+        names.sort_by_key(|name| name.to_lowercase());
+        names
+    }
+
+    /// The prefix modes `nick` holds in `channel`, highest first; `None` if
+    /// they aren't in it.
+    pub fn member_modes(&self, channel: &str, nick: &str) -> Option<String> {
+        let state = self.state.read().ok()?;
+        let channel = state.channels.get(&channel.to_lowercase())?;
+        channel.member(nick).map(|member| member.modes.clone())
+    }
+}
+
+// `nick` left `channel`, or was kicked; the bot leaving drops the channel
+fn part(state: &mut State, channel: &str, nick: &str, ours: bool) {
+    if ours {
+        state.channels.remove(&channel.to_lowercase());
+        state.syncing.remove(&channel.to_lowercase());
+    } else if let Some(channel) = state.channel(channel) {
+        channel.members.remove(&nick.to_lowercase());
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Feeds the server lines to a fresh store as the bot `reinze`
+    fn observed(lines: &[&str]) -> Channels {
+        let channels = Channels::new();
+        for line in lines {
+            channels.observe(&line.parse().unwrap(), "reinze");
+        }
+        channels
+    }
+
+    const JOINED: [&str; 4] = [
+        ":reinze!bot@host JOIN #rshelp",
+        ":irc.example 353 reinze = #rshelp :reinze @alice +bob @+carol!c@host dave",
+        ":irc.example 366 reinze #rshelp :End of /NAMES list.",
+        ":irc.example 324 reinze #rshelp +lnt 50",
+    ];
+
+    #[test]
+    fn test_tracks_names_and_modes() {
+        let channels = Channels::new();
+        let query = channels.observe(&JOINED[0].parse().unwrap(), "reinze");
+        assert_eq!(
+            query,
+            vec![Command::Raw(
+                "MODE".to_string(),
+                vec!["#rshelp".to_string()]
+            )]
+        );
+        for line in &JOINED[1..] {
+            channels.observe(&line.parse().unwrap(), "reinze");
+        }
+
+        let channel = channels.channel("#RSHelp").unwrap();
+        assert_eq!(channel.members().count(), 5);
+        assert_eq!(channels.member_modes("#rshelp", "Carol").unwrap(), "ov");
+        assert_eq!(channels.member_modes("#rshelp", "carol!c@host"), None);
+        assert_eq!(channels.member_modes("#rshelp", "dave").unwrap(), "");
+        assert_eq!(channel.mode_string(), "+lnt 50");
+        assert_eq!(channels.names(), vec!["#rshelp"]);
+    }
+
+    #[test]
+    fn test_follows_changes() {
+        let mut lines = JOINED.to_vec();
+        lines.extend([
+            ":alice!a@host MODE #rshelp +o-t+v reinze dave",
+            ":alice!a@host MODE #rshelp +b *!*@spam",
+            ":alice!a@host MODE #rshelp +c",
+            ":alice!a@host MODE #rshelp +k sekrit",
+            ":erin!e@host JOIN #rshelp",
+            ":bob!b@host NICK robert",
+            ":dave!d@host PART #rshelp",
+            ":alice!a@host KICK #rshelp erin :bye",
+            ":carol!c@host QUIT :gone",
+        ]);
+        let channels = observed(&lines);
+
+        assert_eq!(channels.member_modes("#rshelp", "reinze").unwrap(), "o");
+        assert_eq!(channels.member_modes("#rshelp", "robert").unwrap(), "v");
+        assert_eq!(channels.member_modes("#rshelp", "bob"), None);
+        let channel = channels.channel("#rshelp").unwrap();
+        let nicks = channel
+            .members()
+            .map(|member| member.nick.as_str())
+            .collect::<Vec<&str>>();
+        assert_eq!(nicks, vec!["alice", "reinze", "robert"]);
+        // The key is set, but never shown
+        assert_eq!(channel.mode_string(), "+ckln 50");
+        assert_eq!(channel.modes.get(&'k'), Some(&Some("sekrit".to_string())));
+    }
+
+    #[test]
+    fn test_names_replace_members() {
+        let mut lines = JOINED.to_vec();
+        lines.extend([
+            ":irc.example 353 reinze = #rshelp :reinze @alice",
+            ":irc.example 353 reinze = #rshelp :frank",
+            ":irc.example 366 reinze #rshelp :End of /NAMES list.",
+        ]);
+        let channels = observed(&lines);
+        assert_eq!(channels.channel("#rshelp").unwrap().members().count(), 3);
+    }
+
+    #[test]
+    fn test_isupport_prefix() {
+        let channels = observed(&[
+            ":irc.example 005 reinze PREFIX=(Yov)!@+ CHANMODES=b,k,lj,mnt :are supported",
+            ":reinze!bot@host JOIN #rshelp",
+            ":irc.example 353 reinze = #rshelp :!alice reinze",
+            ":irc.example 324 reinze #rshelp +jnt 3:5",
+            ":alice!a@host MODE #rshelp +v reinze",
+        ]);
+        assert_eq!(channels.member_modes("#rshelp", "alice").unwrap(), "Y");
+        assert_eq!(
+            channels.channel("#rshelp").unwrap().mode_string(),
+            "+jnt 3:5"
+        );
+        assert_eq!(channels.member_modes("#rshelp", "reinze").unwrap(), "v");
+    }
+
+    #[test]
+    fn test_leaving_and_reset() {
+        let mut lines = JOINED.to_vec();
+        lines.extend([
+            ":reinze!bot@host JOIN #drops",
+            ":alice!a@host KICK #rshelp reinze :out",
+        ]);
+        let channels = observed(&lines);
+        assert!(!channels.contains("#rshelp"));
+        assert!(channels.contains("#Drops"));
+
+        channels.reset();
+        assert!(channels.names().is_empty());
+    }
+}
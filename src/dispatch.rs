@@ -0,0 +1,358 @@
+use crate::permissions::Level;
+use crate::plugins::Plugin;
+use regex::{Regex, RegexSet};
+use std::sync::LazyLock;
+
+/// Commands the host answers itself, before any plugin is asked. A plugin
+/// trigger matching one is reported when the table is built, since the plugin
+/// will never see that command.
+pub const BUILTINS: &[&str] = &[
+    "help", "more", "plugins", "access", "ignore", "queue", "servers", "lag", "channel",
+];
+
+// A trigger that only ever matches one command, like `^ge$`
+static LITERAL: LazyLock<Regex> = LazyLock::new(|| Regex::new(r"^\^?([a-zA-Z\d-]+)\$?$").unwrap());
+
+/// Every active plugin's triggers compiled into one `RegexSet`, so a command
+/// is matched against all of them in a single pass. Built whenever the active
+/// plugin list changes and swapped in whole, so a message never sees a
+/// half-updated table.
+///
+/// Plugins are kept in priority order, highest first, falling back to load
+/// order, and commands are offered to them in that order.
+pub struct Dispatch {
+    pub plugins: Vec<Plugin>,
+    /// Commands more than one plugin would answer, found when the table was
+    /// built.
+    pub conflicts: Vec<Conflict>,
+    /// Built-in commands plugins have matching triggers for, which the host
+    /// answers itself instead.
+    pub shadowed: Vec<Conflict>,
+    triggers: RegexSet,
+    // Index into `plugins`, and into that plugin's triggers, for each pattern
+    // in `triggers`
+    owners: Vec<(usize, usize)>,
+}
+
+/// A command several plugins have matching triggers for.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Conflict {
+    pub command: String,
+    /// The plugins' names and priorities, in the order they run.
+    pub plugins: Vec<(String, i32)>,
+}
+
+impl Dispatch {
+    pub fn new(mut plugins: Vec<Plugin>) -> Result<Self, regex::Error> {
+        // Stable, so equal priorities keep their load order
+        plugins.sort_by_key(|plugin| std::cmp::Reverse(plugin.priority));
+
+        let mut patterns = Vec::new();
+        let mut owners = Vec::new();
+
+        for (index, plugin) in plugins.iter().enumerate() {
+            for (trigger_index, trigger) in plugin.triggers.iter().enumerate() {
+                patterns.push(trigger.as_str());
+                owners.push((index, trigger_index));
+            }
+        }
+
+        let mut dispatch = Self {
+            triggers: RegexSet::new(patterns)?,
+            plugins,
+            conflicts: vec![],
+            shadowed: vec![],
+            owners,
+        };
+        dispatch.conflicts = dispatch.find_conflicts();
+        dispatch.shadowed = dispatch.find_shadowed();
+
+        Ok(dispatch)
+    }
+
+    /// Builds the table from as many plugins as compile together, leaving out
+    /// any whose triggers would stop the set from building, with why.
+    pub fn build(plugins: Vec<Plugin>) -> (Self, Vec<(String, regex::Error)>) {
+        if let Ok(dispatch) = Self::new(plugins.clone()) {
+            return (dispatch, vec![]);
+        }
+
+        let mut included: Vec<Plugin> = Vec::new();
+        let mut left_out = Vec::new();
+        for plugin in plugins {
+            included.push(plugin);
+            if let Err(e) = Self::new(included.clone()) {
+                let plugin = included.pop().unwrap();
+                left_out.push((plugin.name, e));
+            }
+        }
+
+        let dispatch = Self::new(included).unwrap_or_else(|_| Self::empty());
+        (dispatch, left_out)
+    }
+
+    pub fn empty() -> Self {
+        Self {
+            plugins: vec![],
+            conflicts: vec![],
+            shadowed: vec![],
+            triggers: RegexSet::empty(),
+            owners: vec![],
+        }
+    }
+
+    /// Plugins with a trigger matching `cmd`, in priority order, with the
+    /// level needed to run them. A plugin with several matching triggers is
+    /// still only returned once, needing the highest of their levels.
+    pub fn matching(&self, cmd: &str) -> Vec<(&Plugin, Level)> {
+        let mut matched: Vec<(usize, Level)> = Vec::new();
+
+        for pattern in self.triggers.matches(cmd).into_iter() {
+            let (index, trigger) = self.owners[pattern];
+            let plugin = &self.plugins[index];
+            let level = plugin.required_level(&plugin.triggers[trigger]);
+
+            // Patterns are in plugin order, so a plugin's matches are adjacent
+            match matched.last_mut() {
+                Some((last, required)) if *last == index => *required = (*required).max(level),
+                _ => matched.push((index, level)),
+            }
+        }
+
+        matched
+            .into_iter()
+            .map(|(index, level)| (&self.plugins[index], level))
+            .collect()
+    }
+
+    /// Whether anything answers `cmd`: a built-in, or a plugin with a
+    /// matching trigger, whether or not the caller may run it.
+    pub fn answers(&self, cmd: &str) -> bool {
+        BUILTINS.contains(&cmd) || self.triggers.is_match(cmd)
+    }
+
+    /// Whether `cmd` would run a plugin for a caller at `level`: it isn't a
+    /// built-in, and a plugin the caller may use has a matching trigger.
+    pub fn runs_plugin(&self, cmd: &str, level: Level) -> bool {
+        !BUILTINS.contains(&cmd)
+            && self
+                .matching(cmd)
+                .iter()
+                .any(|(_, required)| level >= *required)
+    }
+
+    // Overlap between arbitrary regexes can't be decided in general, so this
+    // tries every command a plugin advertises or matches literally.
+    fn find_conflicts(&self) -> Vec<Conflict> {
+        let mut commands = self
+            .plugins
+            .iter()
+            .flat_map(|plugin| candidates(&plugin.commands, &plugin.triggers))
+            .collect::<Vec<String>>();
+        commands.sort();
+        commands.dedup();
+
+        commands
+            .into_iter()
+            .filter_map(|command| {
+                let plugins = self.matching(&command);
+                if plugins.len() < 2 {
+                    return None;
+                }
+
+                Some(Conflict {
+                    plugins: plugins
+                        .iter()
+                        .map(|(plugin, _)| (plugin.name.clone(), plugin.priority))
+                        .collect(),
+                    command,
+                })
+            })
+            .collect()
+    }
+
+    fn find_shadowed(&self) -> Vec<Conflict> {
+        BUILTINS
+            .iter()
+            .filter_map(|builtin| {
+                let plugins = self.matching(builtin);
+                if plugins.is_empty() {
+                    return None;
+                }
+
+                Some(Conflict {
+                    command: builtin.to_string(),
+                    plugins: plugins
+                        .iter()
+                        .map(|(plugin, _)| (plugin.name.clone(), plugin.priority))
+                        .collect(),
+                })
+            })
+            .collect()
+    }
+}
+
+/// Command names a plugin is known to answer: its advertised commands (minus
+/// the `+`/`-` prefix and any usage text) and its literal triggers.
+fn candidates(commands: &[String], triggers: &[String]) -> Vec<String> {
+    let advertised = commands.iter().filter_map(|command| {
+        let name = command
+            .trim_start_matches(['+', '-'])
+            .split_whitespace()
+            .next()?;
+        LITERAL.is_match(name).then(|| name.to_string())
+    });
+
+    let literal = triggers
+        .iter()
+        .filter_map(|trigger| Some(LITERAL.captures(trigger)?[1].to_string()));
+
+    advertised.chain(literal).collect()
+}
+
+/// Compiles each trigger once to report every invalid one by name, so a
+/// plugin can be rejected at load time instead of failing on each message.
+pub fn check_triggers(triggers: &[String]) -> Result<(), String> {
+    let errors = triggers
+        .iter()
+        .filter_map(|trigger| {
+            Regex::new(trigger)
+                .err()
+                .map(|e| format!("invalid trigger '{}': {}", trigger, e))
+        })
+        .collect::<Vec<String>>();
+
+    if errors.is_empty() {
+        Ok(())
+    } else {
+        Err(errors.join("; "))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::plugins::tests::legacy_plugin;
+
+    fn strings(items: &[&str]) -> Vec<String> {
+        items.iter().map(|s| s.to_string()).collect()
+    }
+
+    fn plugin(name: &str, priority: i32, triggers: &[&str]) -> Plugin {
+        Plugin {
+            name: name.to_string(),
+            priority,
+            triggers: strings(triggers),
+            ..legacy_plugin()
+        }
+    }
+
+    fn names(matched: &[(&Plugin, Level)]) -> Vec<(String, Level)> {
+        matched
+            .iter()
+            .map(|(plugin, level)| (plugin.name.clone(), *level))
+            .collect()
+    }
+
+    #[test]
+    fn test_matching() {
+        let runescape = plugin("runescape", 0, &["^ge$", "^(price|ge)$"]);
+        let mut admin = plugin("admin", 10, &["^ge$", "^reset$"]);
+        admin.levels = vec![("^reset$".to_string(), Level::Admin)];
+        let dispatch = Dispatch::new(vec![runescape, admin]).unwrap();
+
+        // Highest priority first, and each plugin once
+        assert_eq!(
+            names(&dispatch.matching("ge")),
+            vec![
+                ("admin".to_string(), Level::User),
+                ("runescape".to_string(), Level::User)
+            ]
+        );
+        assert_eq!(
+            names(&dispatch.matching("reset")),
+            vec![("admin".to_string(), Level::Admin)]
+        );
+        assert!(dispatch.matching("stats").is_empty());
+
+        assert_eq!(dispatch.conflicts.len(), 1);
+        assert_eq!(dispatch.conflicts[0].command, "ge");
+    }
+
+    #[test]
+    fn test_runs_plugin() {
+        let mut admin = plugin("admin", 0, &["^reset$", "^more$"]);
+        admin.levels = vec![("^reset$".to_string(), Level::Admin)];
+        let dispatch = Dispatch::new(vec![admin]).unwrap();
+
+        assert!(dispatch.runs_plugin("reset", Level::Admin));
+        assert!(!dispatch.runs_plugin("reset", Level::User));
+        assert!(!dispatch.runs_plugin("lol", Level::Owner));
+        // Built-ins are answered before any plugin
+        assert!(!dispatch.runs_plugin("more", Level::User));
+    }
+
+    #[test]
+    fn test_shadowed_builtins() {
+        let prices = plugin("prices", 0, &["^(more|price)$"]);
+        let dispatch = Dispatch::new(vec![prices, plugin("ge", 0, &["^ge$"])]).unwrap();
+
+        assert_eq!(
+            dispatch.shadowed,
+            vec![Conflict {
+                command: "more".to_string(),
+                plugins: vec![("prices".to_string(), 0)],
+            }]
+        );
+        assert!(dispatch.conflicts.is_empty());
+    }
+
+    #[test]
+    fn test_answers() {
+        let dispatch = Dispatch::new(vec![plugin("runescape", 0, &["^ge$"])]).unwrap();
+
+        assert!(dispatch.answers("ge"));
+        assert!(dispatch.answers("more"));
+        // Chat like "+1" or "-lol"
+        assert!(!dispatch.answers("1"));
+        assert!(!dispatch.answers("lol"));
+    }
+
+    #[test]
+    fn test_build_leaves_out_failing_plugin() {
+        let good = plugin("good", 0, &["^ge$"]);
+        let broken = plugin("broken", 0, &["^(price$"]);
+        let (dispatch, left_out) = Dispatch::build(vec![good, broken]);
+
+        assert_eq!(left_out.len(), 1);
+        assert_eq!(left_out[0].0, "broken");
+        assert_eq!(names(&dispatch.matching("ge")).len(), 1);
+        assert_eq!(dispatch.plugins.len(), 1);
+    }
+
+    #[test]
+    fn test_check_triggers() {
+        assert!(check_triggers(&strings(&["^ge$", "^price$"])).is_ok());
+
+        let err = check_triggers(&strings(&["^ge$", "^(price$"])).unwrap_err();
+        assert!(err.contains("^(price$"));
+        assert!(!err.contains("'^ge$'"));
+    }
+
+    #[test]
+    fn test_candidates() {
+        let commands = strings(&["+ge <item>", "-price", "", "+???"]);
+        let triggers = strings(&["^ge$", "^(stats|hiscores)$", "^lvl$"]);
+        assert_eq!(
+            candidates(&commands, &triggers),
+            vec!["ge", "price", "ge", "lvl"]
+        );
+    }
+
+    #[test]
+    fn test_empty() {
+        let dispatch = Dispatch::empty();
+        assert!(dispatch.matching("ge").is_empty());
+        assert!(dispatch.conflicts.is_empty());
+    }
+}
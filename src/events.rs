@@ -0,0 +1,276 @@
+use crate::host::HostApi;
+use crate::ircv3;
+use common::ColorResult;
+use irc::client::prelude::{Command, Message};
+use std::os::raw::c_char;
+
+/// Named events a plugin may subscribe to. Numeric replies are subscribed to
+/// by their three-digit code, e.g. `001` or `332`.
+pub const KINDS: [&str; 9] = [
+    "JOIN", "PART", "QUIT", "NICK", "KICK", "TOPIC", "MODE", "INVITE", "AWAY",
+];
+
+/// Whether `kind` is something `Event::from_message` can produce.
+pub fn is_known(kind: &str) -> bool {
+    KINDS.contains(&kind) || (kind.len() == 3 && kind.bytes().all(|b| b.is_ascii_digit()))
+}
+
+/// An IRC event delivered to subscribed plugins.
+///
+/// `args` holds whatever the event carries beyond its source and channel:
+///
+/// - `JOIN`: with `extended-join`, the account (`*` if none) and real name
+/// - `PART`, `QUIT`: the reason, if any
+/// - `NICK`: the new nick
+/// - `KICK`: the kicked nick, then the reason if any
+/// - `TOPIC`: the new topic, if any
+/// - `MODE`: each mode change as `+o nick`; for user modes the nick comes first
+/// - `INVITE`: the invited nick
+/// - `AWAY`: the away message, or nothing when they're back (`away-notify`)
+/// - numerics: the reply's parameters, starting with the bot's own nick
+#[derive(Clone, Debug, PartialEq)]
+pub struct Event {
+    pub kind: String,
+    /// `nick!user@host` for users, or the server name.
+    pub source: String,
+    /// The channel the event happened in, or empty if it has none.
+    pub channel: String,
+    pub args: Vec<String>,
+    /// The message's IRCv3 tags.
+    pub tags: Vec<(String, String)>,
+}
+
+impl Event {
+    /// The event a message represents, or `None` for messages plugins can't
+    /// subscribe to (including PRIVMSG and NOTICE, which go through commands).
+    pub fn from_message(message: &Message) -> Option<Self> {
+        let (kind, channel, args) = match &message.command {
+            Command::JOIN(channel, account, realname) => (
+                "JOIN".to_string(),
+                channel.clone(),
+                [account.clone(), realname.clone()]
+                    .into_iter()
+                    .flatten()
+                    .collect(),
+            ),
+            Command::PART(channel, reason) => (
+                "PART".to_string(),
+                channel.clone(),
+                reason.iter().cloned().collect(),
+            ),
+            Command::QUIT(reason) => (
+                "QUIT".to_string(),
+                String::new(),
+                reason.iter().cloned().collect(),
+            ),
+            Command::NICK(nick) => ("NICK".to_string(), String::new(), vec![nick.clone()]),
+            Command::KICK(channel, nick, reason) => (
+                "KICK".to_string(),
+                channel.clone(),
+                [Some(nick.clone()), reason.clone()]
+                    .into_iter()
+                    .flatten()
+                    .collect(),
+            ),
+            Command::TOPIC(channel, topic) => (
+                "TOPIC".to_string(),
+                channel.clone(),
+                topic.iter().cloned().collect(),
+            ),
+            Command::ChannelMODE(channel, modes) => (
+                "MODE".to_string(),
+                channel.clone(),
+                modes.iter().map(|mode| mode.to_string()).collect(),
+            ),
+            Command::UserMODE(nick, modes) => (
+                "MODE".to_string(),
+                String::new(),
+                [nick.clone()]
+                    .into_iter()
+                    .chain(modes.iter().map(|mode| mode.to_string()))
+                    .collect(),
+            ),
+            Command::INVITE(nick, channel) => {
+                ("INVITE".to_string(), channel.clone(), vec![nick.clone()])
+            }
+            Command::AWAY(message) => (
+                "AWAY".to_string(),
+                String::new(),
+                message.iter().cloned().collect(),
+            ),
+            Command::Response(response, args) => {
+                // Replies about a channel carry it after the bot's own nick
+                let channel = args
+                    .get(1)
+                    .filter(|arg| is_channel(arg))
+                    .cloned()
+                    .unwrap_or_default();
+                (format!("{:03}", *response as u16), channel, args.clone())
+            }
+            _ => return None,
+        };
+
+        let source = match &message.prefix {
+            Some(prefix) => prefix.to_string(),
+            None => String::new(),
+        };
+
+        Some(Self {
+            kind,
+            source,
+            channel,
+            args,
+            tags: ircv3::tags(message),
+        })
+    }
+
+    /// Where output lines go unless they say otherwise: the event's channel,
+    /// or the user behind it. Server events without a channel have nowhere
+    /// to reply to.
+    pub fn reply_target(&self) -> Option<&str> {
+        if !self.channel.is_empty() {
+            return Some(&self.channel);
+        }
+
+        match self.source.split_once('!') {
+            Some((nick, _)) if !nick.is_empty() => Some(nick),
+            _ => None,
+        }
+    }
+}
+
+/// Whether `name` is a channel rather than a nick.
+pub fn is_channel(name: &str) -> bool {
+    name.starts_with(['#', '&', '+', '!'])
+}
+
+/// The context passed to a plugin's `plugin_event` entry point. `args` is
+/// newline-separated, and `host` is null when the plugin has no host
+/// callbacks.
+#[repr(C)]
+pub struct EventContext {
+    pub event: *const c_char,
+    pub source: *const c_char,
+    pub channel: *const c_char,
+    pub args: *const c_char,
+    pub color: extern "C" fn(*const c_char, *const c_char) -> ColorResult,
+    pub host: *const HostApi,
+    /// The message's IRCv3 tags, as in `PluginContextV2`. Added in ABI v7.
+    pub tags: *const c_char,
+}
+
+pub type EventFn = extern "C" fn(context: &EventContext) -> *mut c_char;
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use irc::client::prelude::{Prefix, Response};
+    use irc::proto::message::Tag;
+
+    fn message(command: Command) -> Message {
+        Message {
+            tags: None,
+            prefix: Some(Prefix::Nickname(
+                "Zezima".to_string(),
+                "zezima".to_string(),
+                "lumbridge.example".to_string(),
+            )),
+            command,
+        }
+    }
+
+    #[test]
+    fn test_join() {
+        let event = Event::from_message(&message(Command::JOIN("#rshelp".to_string(), None, None)))
+            .unwrap();
+        assert_eq!(event.kind, "JOIN");
+        assert_eq!(event.source, "Zezima!zezima@lumbridge.example");
+        assert_eq!(event.channel, "#rshelp");
+        assert!(event.args.is_empty());
+        assert_eq!(event.reply_target(), Some("#rshelp"));
+    }
+
+    #[test]
+    fn test_extended_join_and_away() {
+        let mut join = message(Command::JOIN(
+            "#rshelp".to_string(),
+            Some("zezima".to_string()),
+            Some("Zezima the Great".to_string()),
+        ));
+        join.tags = Some(vec![Tag("account".to_string(), Some("zezima".to_string()))]);
+
+        let event = Event::from_message(&join).unwrap();
+        assert_eq!(event.args, vec!["zezima", "Zezima the Great"]);
+        assert_eq!(
+            event.tags,
+            vec![("account".to_string(), "zezima".to_string())]
+        );
+
+        let away = Event::from_message(&message(Command::AWAY(None))).unwrap();
+        assert_eq!(away.kind, "AWAY");
+        assert!(away.args.is_empty());
+        assert!(is_known("AWAY"));
+    }
+
+    #[test]
+    fn test_kick() {
+        let event = Event::from_message(&message(Command::KICK(
+            "#rshelp".to_string(),
+            "Durial321".to_string(),
+            Some("no pking".to_string()),
+        )))
+        .unwrap();
+        assert_eq!(event.args, vec!["Durial321", "no pking"]);
+    }
+
+    #[test]
+    fn test_nick_replies_to_user() {
+        let event = Event::from_message(&message(Command::NICK("Zezima2".to_string()))).unwrap();
+        assert_eq!(event.channel, "");
+        assert_eq!(event.args, vec!["Zezima2"]);
+        assert_eq!(event.reply_target(), Some("Zezima"));
+    }
+
+    #[test]
+    fn test_numeric() {
+        let event = Event::from_message(&Message {
+            tags: None,
+            prefix: Some(Prefix::ServerName("irc.example".to_string())),
+            command: Command::Response(
+                Response::RPL_TOPIC,
+                vec![
+                    "RustKick".to_string(),
+                    "#rshelp".to_string(),
+                    "Welcome".to_string(),
+                ],
+            ),
+        })
+        .unwrap();
+        assert_eq!(event.kind, "332");
+        assert_eq!(event.source, "irc.example");
+        assert_eq!(event.channel, "#rshelp");
+
+        let welcome = Event::from_message(&Message {
+            tags: None,
+            prefix: Some(Prefix::ServerName("irc.example".to_string())),
+            command: Command::Response(Response::RPL_WELCOME, vec!["RustKick".to_string()]),
+        })
+        .unwrap();
+        assert_eq!(welcome.kind, "001");
+        assert_eq!(welcome.reply_target(), None);
+    }
+
+    #[test]
+    fn test_privmsg_is_not_an_event() {
+        let privmsg = Command::PRIVMSG("#rshelp".to_string(), "+ge whip".to_string());
+        assert!(Event::from_message(&message(privmsg)).is_none());
+    }
+
+    #[test]
+    fn test_is_known() {
+        assert!(is_known("JOIN"));
+        assert!(is_known("433"));
+        assert!(!is_known("PRIVMSG"));
+        assert!(!is_known("43"));
+    }
+}
@@ -0,0 +1,200 @@
+use crate::events::Event;
+use crate::permissions::Caller;
+use crate::plugins::Plugin;
+use crate::settings::Settings;
+use common::ColorResult;
+use std::collections::HashMap;
+use std::fmt;
+use std::os::raw::c_char;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use tokio::sync::{AcquireError, Semaphore};
+
+/// Why a plugin call produced no output.
+#[derive(Debug)]
+pub enum CallError {
+    /// The call didn't finish within the plugin's timeout. The plugin keeps
+    /// its concurrency slot until the call actually returns.
+    TimedOut(Duration),
+    /// No slot came free within the plugin's timeout, so the call never ran.
+    Busy,
+    Failed(String),
+}
+
+impl fmt::Display for CallError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::TimedOut(timeout) => write!(f, "timed out after {}s", timeout.as_secs()),
+            Self::Busy => write!(f, "busy, try again shortly"),
+            Self::Failed(e) => write!(f, "{}", e),
+        }
+    }
+}
+
+/// Runs plugin calls on Tokio's blocking pool so a slow plugin can't stall the
+/// message loop. Calls are bounded globally and per plugin, and each one, once
+/// it has its slots, is raced against the plugin's configured timeout. Waiting
+/// for the slots is bounded by the same timeout, so a plugin stuck holding all
+/// of its slots can't hold up its callers forever.
+pub struct Executor {
+    settings: Arc<Settings>,
+    pool: Arc<Semaphore>,
+    limits: Mutex<HashMap<String, Arc<Semaphore>>>,
+}
+
+impl Executor {
+    pub fn new(settings: Arc<Settings>) -> Self {
+        Self {
+            pool: Arc::new(Semaphore::new(settings.plugin_threads())),
+            settings,
+            limits: Mutex::new(HashMap::new()),
+        }
+    }
+
+    pub async fn call(
+        &self,
+        plugin: &Plugin,
+        cmd: &str,
+        param: &str,
+        caller: &Caller,
+        channel: &str,
+        color: extern "C" fn(*const c_char, *const c_char) -> ColorResult,
+    ) -> Result<Vec<String>, CallError> {
+        let cmd = cmd.to_string();
+        let param = param.to_string();
+        let caller = caller.clone();
+        let channel = channel.to_string();
+
+        self.execute(plugin, move |plugin| {
+            plugin.call(&cmd, &param, &caller, &channel, color)
+        })
+        .await
+    }
+
+    /// Delivers an event under the same limits and timeout as a call.
+    pub async fn event(
+        &self,
+        plugin: &Plugin,
+        event: &Event,
+        color: extern "C" fn(*const c_char, *const c_char) -> ColorResult,
+    ) -> Result<Vec<String>, CallError> {
+        let event = event.clone();
+
+        self.execute(plugin, move |plugin| plugin.event(&event, color))
+            .await
+    }
+
+    /// Delivers a listener match under the same limits and timeout as a call.
+    pub async fn listen(
+        &self,
+        plugin: &Plugin,
+        listener: &str,
+        captures: Vec<String>,
+        caller: &Caller,
+        channel: &str,
+        color: extern "C" fn(*const c_char, *const c_char) -> ColorResult,
+    ) -> Result<Vec<String>, CallError> {
+        let listener = listener.to_string();
+        let caller = caller.clone();
+        let channel = channel.to_string();
+
+        self.execute(plugin, move |plugin| {
+            plugin.listen(&listener, &captures, &caller, &channel, color)
+        })
+        .await
+    }
+
+    async fn execute<F>(&self, plugin: &Plugin, job: F) -> Result<Vec<String>, CallError>
+    where
+        F: FnOnce(&Plugin) -> Result<Vec<String>, String> + Send + 'static,
+    {
+        let settings = self.settings.plugin(&plugin.name);
+        let timeout = settings.timeout();
+        let limit = self.limit(&plugin.name, settings.concurrency());
+        let pool = self.pool.clone();
+        let plugin = plugin.clone();
+
+        let permits = async {
+            let slot = limit.acquire_owned().await?;
+            let thread = pool.acquire_owned().await?;
+            Ok((slot, thread))
+        };
+        let (slot, thread) = match tokio::time::timeout(timeout, permits).await {
+            Ok(permits) => permits.map_err(|_: AcquireError| {
+                CallError::Failed("plugin executor is shut down".to_string())
+            })?,
+            Err(_) => return Err(CallError::Busy),
+        };
+
+        // Timed from here, so waiting for a slot doesn't count against the
+        // call itself
+        let task = tokio::task::spawn_blocking(move || {
+            // Permits are released when the call returns, not when the
+            // caller stops waiting for it
+            let _permits = (slot, thread);
+            job(&plugin)
+        });
+
+        match tokio::time::timeout(timeout, task).await {
+            Ok(result) => result
+                .map_err(|e| CallError::Failed(format!("plugin panicked: {}", e)))?
+                .map_err(CallError::Failed),
+            Err(_) => Err(CallError::TimedOut(timeout)),
+        }
+    }
+
+    /// Drops the concurrency limit of an unloaded plugin. Calls still running
+    /// keep the slots they hold.
+    pub fn forget(&self, name: &str) {
+        if let Ok(mut limits) = self.limits.lock() {
+            limits.remove(name);
+        }
+    }
+
+    fn limit(&self, name: &str, concurrency: usize) -> Arc<Semaphore> {
+        let mut limits = self.limits.lock().unwrap();
+        limits
+            .entry(name.to_string())
+            .or_insert_with(|| Arc::new(Semaphore::new(concurrency)))
+            .clone()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::plugins::tests::legacy_plugin;
+    use common::author::cache::color_ffi;
+
+    #[tokio::test]
+    async fn test_busy_when_slots_stay_taken() {
+        let settings = Settings::parse(
+            r#"
+            [plugins.liblegacy]
+            timeout = "1s"
+            concurrency = 1
+            "#,
+        )
+        .unwrap();
+        let executor = Executor::new(Arc::new(settings));
+        let plugin = legacy_plugin();
+        let caller = Caller::internal("a!a@a");
+
+        // A call that never returns would hold its slot like this
+        let stuck = executor
+            .limit(&plugin.name, 1)
+            .acquire_owned()
+            .await
+            .unwrap();
+        let result = executor
+            .call(&plugin, "ping", "", &caller, "#rshelp", color_ffi)
+            .await;
+        assert!(matches!(result, Err(CallError::Busy)));
+
+        drop(stuck);
+        let result = executor
+            .call(&plugin, "ping", "", &caller, "#rshelp", color_ffi)
+            .await;
+        assert_eq!(result.unwrap(), vec!["pong"]);
+    }
+}
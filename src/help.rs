@@ -0,0 +1,272 @@
+use crate::plugins::Plugin;
+use common::author::Author;
+use serde::{Deserialize, Serialize};
+use std::path::Path;
+
+/// How long a `+help` line may get before the rest goes on another page.
+const PAGE_LENGTH: usize = 400;
+
+/// What a plugin declares about one of its commands, read from the JSON array
+/// in its manifest's `command_info`, e.g.
+/// `[{"name": "ge", "usage": "+ge <item>", "category": "Grand Exchange"}]`.
+#[derive(Clone, Debug, Default, Deserialize, PartialEq, Serialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct CommandInfo {
+    pub name: String,
+    pub usage: String,
+    pub description: String,
+    pub examples: Vec<String>,
+    pub aliases: Vec<String>,
+    /// Groups the command in `+help`; defaults to the plugin's name.
+    pub category: String,
+}
+
+impl CommandInfo {
+    fn answers_to(&self, name: &str) -> bool {
+        self.name.eq_ignore_ascii_case(name)
+            || self
+                .aliases
+                .iter()
+                .any(|alias| alias.eq_ignore_ascii_case(name))
+    }
+}
+
+/// Parses a manifest's `command_info`. Empty means the plugin declared none.
+pub fn parse_command_info(json: &str) -> Result<Vec<CommandInfo>, String> {
+    if json.trim().is_empty() {
+        return Ok(vec![]);
+    }
+
+    let info: Vec<CommandInfo> =
+        serde_json::from_str(json).map_err(|e| format!("invalid command_info: {}", e))?;
+
+    match info.iter().find(|command| command.name.trim().is_empty()) {
+        Some(_) => Err("command_info has a command without a name".to_string()),
+        None => Ok(info),
+    }
+}
+
+pub fn serialize_command_info(info: &[CommandInfo]) -> String {
+    if info.is_empty() {
+        return String::new();
+    }
+
+    serde_json::to_string(info).unwrap_or_default()
+}
+
+/// `+help`: every command grouped by category, over as many lines as it takes,
+/// for the caller to page like any long output. Plugins with only a legacy
+/// command list are grouped under their name.
+pub fn overview(plugins: &[Plugin], author: &Author) -> Vec<String> {
+    let mut groups: Vec<(String, Vec<String>)> = Vec::new();
+
+    for plugin in plugins {
+        let commands = if plugin.command_info.is_empty() {
+            plugin
+                .commands
+                .iter()
+                .filter(|command| !command.trim().is_empty())
+                .map(|command| (plugin_name(plugin), command.clone()))
+                .collect::<Vec<_>>()
+        } else {
+            plugin
+                .command_info
+                .iter()
+                .map(|command| {
+                    let category = match command.category.trim() {
+                        "" => plugin_name(plugin),
+                        category => category.to_string(),
+                    };
+                    (category, command.name.clone())
+                })
+                .collect()
+        };
+
+        for (category, command) in commands {
+            match groups.iter_mut().find(|(name, _)| *name == category) {
+                Some((_, group)) => group.push(command),
+                None => groups.push((category, vec![command])),
+            }
+        }
+    }
+
+    let groups = groups
+        .into_iter()
+        .map(|(category, commands)| {
+            [author.l(&category), author.c1(&commands.join(", "))].join(" ")
+        })
+        .collect::<Vec<String>>();
+
+    pages("Commands", groups, author)
+}
+
+/// Packs `items` into as few lines as fit, each labelled `label`, numbered
+/// `label (i/n)` when there's more than one.
+pub fn pages(label: &str, items: Vec<String>, author: &Author) -> Vec<String> {
+    let pages = pack(items, PAGE_LENGTH);
+    let count = pages.len();
+
+    if count == 0 {
+        return vec![[author.l(label), author.c1("none")].join(" ")];
+    }
+
+    pages
+        .into_iter()
+        .enumerate()
+        .map(|(index, page)| match count {
+            1 => format!("{} {}", author.l(label), page),
+            _ => format!(
+                "{} {}",
+                author.l(&format!("{} ({}/{})", label, index + 1, count)),
+                page
+            ),
+        })
+        .collect()
+}
+
+/// `+help <command>`: the declared details of one command, looked up by name
+/// or alias with or without its `+`/`-` prefix.
+pub fn detail(plugins: &[Plugin], name: &str, author: &Author) -> String {
+    let name = name.trim().trim_start_matches(['+', '-']);
+
+    let found = plugins.iter().find_map(|plugin| {
+        plugin
+            .command_info
+            .iter()
+            .find(|command| command.answers_to(name))
+            .map(|command| (plugin, command))
+    });
+
+    let (plugin, command) = match found {
+        Some(found) => found,
+        None => {
+            let legacy = plugins.iter().any(|plugin| {
+                plugin.commands.iter().any(|command| {
+                    command
+                        .trim_start_matches(['+', '-'])
+                        .split_whitespace()
+                        .next()
+                        .is_some_and(|command| command.eq_ignore_ascii_case(name))
+                })
+            });
+
+            return if legacy {
+                [author.l(name), author.c1("no details available")].join(" ")
+            } else {
+                [
+                    author.l("Help"),
+                    author.c1(&format!("no command '{}'", name)),
+                ]
+                .join(" ")
+            };
+        }
+    };
+
+    let category = match command.category.trim() {
+        "" => plugin_name(plugin),
+        category => category.to_string(),
+    };
+
+    let mut parts = vec![format!(
+        "{} {}",
+        author.l(&command.name),
+        author.c1(&format!("({})", category))
+    )];
+
+    if !command.usage.is_empty() {
+        parts.push(format!(
+            "{} {}",
+            author.l("Usage"),
+            author.c1(&command.usage)
+        ));
+    }
+    if !command.description.is_empty() {
+        parts.push(author.c1(&command.description));
+    }
+    if !command.aliases.is_empty() {
+        parts.push(format!(
+            "{} {}",
+            author.l("Aliases"),
+            author.c1(&command.aliases.join(", "))
+        ));
+    }
+    if !command.examples.is_empty() {
+        parts.push(format!(
+            "{} {}",
+            author.l("Examples"),
+            author.c1(&command.examples.join(", "))
+        ));
+    }
+
+    parts.join(" | ")
+}
+
+// The manifest name, or the library's file stem for legacy plugins.
+fn plugin_name(plugin: &Plugin) -> String {
+    match &plugin.manifest {
+        Some(manifest) => manifest.name.clone(),
+        None => Path::new(&plugin.name)
+            .file_stem()
+            .map(|stem| stem.to_string_lossy().to_string())
+            .unwrap_or_else(|| plugin.name.clone()),
+    }
+}
+
+// Joins items with spaces into lines of at most `limit` bytes where possible;
+// an item longer than `limit` gets a line to itself.
+fn pack(items: Vec<String>, limit: usize) -> Vec<String> {
+    let mut lines: Vec<String> = Vec::new();
+
+    for item in items {
+        match lines.last_mut() {
+            Some(line) if line.len() + 1 + item.len() <= limit => {
+                line.push(' ');
+                line.push_str(&item);
+            }
+            _ => lines.push(item),
+        }
+    }
+
+    lines
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_command_info() {
+        let info = parse_command_info(
+            r#"[{"name": "ge", "usage": "+ge <item>", "aliases": ["price"], "category": "Grand Exchange"}]"#,
+        )
+        .unwrap();
+        assert_eq!(info[0].usage, "+ge <item>");
+        assert!(info[0].answers_to("PRICE"));
+        assert!(info[0].examples.is_empty());
+
+        assert!(parse_command_info("").unwrap().is_empty());
+        assert!(parse_command_info("[{}]").is_err());
+        assert!(parse_command_info(r#"[{"name": "ge", "colour": "red"}]"#).is_err());
+    }
+
+    #[test]
+    fn test_command_info_roundtrip() {
+        let info = vec![CommandInfo {
+            name: "ge".to_string(),
+            examples: vec!["+ge whip".to_string()],
+            ..Default::default()
+        }];
+        assert_eq!(
+            parse_command_info(&serialize_command_info(&info)).unwrap(),
+            info
+        );
+        assert_eq!(serialize_command_info(&[]), "");
+    }
+
+    #[test]
+    fn test_pack() {
+        let items = vec!["aaaa".to_string(), "bb".to_string(), "cccccccc".to_string()];
+        assert_eq!(pack(items, 7), vec!["aaaa bb", "cccccccc"]);
+        assert!(pack(vec![], 7).is_empty());
+    }
+}
@@ -0,0 +1,406 @@
+use crate::application::process_item;
+use crate::channels::Channels;
+use crate::executor::Executor;
+use crate::outbound::{Outbound, Priority};
+use crate::permissions::{Caller, Level};
+use crate::plugins::Plugin;
+use crate::reconnect::Connections;
+use crate::response::{Item, Kind};
+use common::ColorResult;
+use std::collections::HashMap;
+use std::ffi::CStr;
+use std::os::raw::c_char;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, LazyLock, RwLock};
+use std::time::Duration;
+
+/// The context passed to a plugin's `exported_v2` entry point: the same fields
+/// as `PluginContext`, plus the host callback table.
+#[repr(C)]
+pub struct PluginContextV2 {
+    pub cmd: *const c_char,
+    pub param: *const c_char,
+    pub author: *const c_char,
+    pub color: extern "C" fn(*const c_char, *const c_char) -> ColorResult,
+    pub channel: *const c_char,
+    pub host: *const HostApi,
+    /// The caller's permission level; see `Level`. Added in ABI v6.
+    pub level: u32,
+    /// The message's IRCv3 tags as `key=value` lines, with values escaped as
+    /// on the wire. Added in ABI v7.
+    pub tags: *const c_char,
+}
+
+/// Functions a plugin may call back into the host with.
+///
+/// `handle` identifies the plugin and is passed back as the first argument.
+/// The table and handle may be copied and used from any thread, for as long as
+/// the plugin likes: once the plugin is unloaded every call returns `false`
+/// instead of acting. Strings are borrowed for the duration of the call only.
+///
+/// Callbacks that write into `buffer` work like `snprintf`: they write at most
+/// `capacity` bytes including the NUL and return the full length, so a plugin
+/// whose buffer was too small can call again with a bigger one. They return
+/// -1 when there is nothing to write: unloaded, not connected, or the bot or
+/// nick isn't in the channel.
+#[repr(C)]
+#[derive(Clone, Copy)]
+pub struct HostApi {
+    pub handle: u64,
+    /// Sends a PRIVMSG to a channel or nick.
+    pub send_message:
+        extern "C" fn(handle: u64, target: *const c_char, text: *const c_char) -> bool,
+    /// Sends a NOTICE to a channel or nick.
+    pub send_notice: extern "C" fn(handle: u64, target: *const c_char, text: *const c_char) -> bool,
+    /// Calls the plugin again with `cmd` and `param` after `delay_ms`, sending
+    /// whatever it returns to `channel` like command output.
+    pub schedule: extern "C" fn(
+        handle: u64,
+        delay_ms: u64,
+        cmd: *const c_char,
+        param: *const c_char,
+        channel: *const c_char,
+    ) -> bool,
+    /// Whether the bot is currently in `channel`.
+    pub in_channel: extern "C" fn(handle: u64, channel: *const c_char) -> bool,
+    /// The round trip to the server in milliseconds, or -1 if not connected
+    /// or not measured yet. Added in ABI v8.
+    pub lag: extern "C" fn(handle: u64) -> i64,
+    /// Writes `channel`'s modes, e.g. `+lnt 50`, into `buffer`. Added in
+    /// ABI v9.
+    pub channel_modes: extern "C" fn(
+        handle: u64,
+        channel: *const c_char,
+        buffer: *mut c_char,
+        capacity: usize,
+    ) -> i64,
+    /// Writes the prefix modes `nick` holds in `channel` into `buffer`,
+    /// highest first, e.g. `ov`; empty for an ordinary member. A null `nick`
+    /// means the bot. Added in ABI v9.
+    pub member_modes: extern "C" fn(
+        handle: u64,
+        channel: *const c_char,
+        nick: *const c_char,
+        buffer: *mut c_char,
+        capacity: usize,
+    ) -> i64,
+    /// Writes the nicks in `channel` into `buffer`, one per line. Added in
+    /// ABI v9.
+    pub members: extern "C" fn(
+        handle: u64,
+        channel: *const c_char,
+        buffer: *mut c_char,
+        capacity: usize,
+    ) -> i64,
+}
+
+pub type ExportedV2Fn = extern "C" fn(context: &PluginContextV2) -> *mut c_char;
+
+/// The network connection host callbacks act on. The outbound queue is only
+/// present while `run_client` is connected, so callbacks made between
+/// connections fail instead of queueing.
+pub struct Host {
+    pub outbound: RwLock<Option<Arc<Outbound>>>,
+    connections: Arc<Connections>,
+    channels: Arc<Channels>,
+    runtime: Option<tokio::runtime::Handle>,
+    executor: Arc<Executor>,
+    color_ffi: extern "C" fn(*const c_char, *const c_char) -> ColorResult,
+}
+
+impl Host {
+    pub fn new(
+        executor: Arc<Executor>,
+        connections: Arc<Connections>,
+        channels: Arc<Channels>,
+        color_ffi: extern "C" fn(*const c_char, *const c_char) -> ColorResult,
+    ) -> Self {
+        Self {
+            outbound: RwLock::new(None),
+            connections,
+            channels,
+            runtime: tokio::runtime::Handle::try_current().ok(),
+            executor,
+            color_ffi,
+        }
+    }
+
+    pub fn connect(&self, outbound: Arc<Outbound>) {
+        *self.outbound.write().unwrap() = Some(outbound);
+    }
+
+    pub fn disconnect(&self) {
+        *self.outbound.write().unwrap() = None;
+    }
+
+    fn outbound(&self) -> Option<Arc<Outbound>> {
+        self.outbound.read().ok()?.clone()
+    }
+}
+
+struct Registration {
+    host: Arc<Host>,
+    plugin: Plugin,
+}
+
+static NEXT_HANDLE: AtomicU64 = AtomicU64::new(1);
+static REGISTRY: LazyLock<RwLock<HashMap<u64, Registration>>> =
+    LazyLock::new(|| RwLock::new(HashMap::new()));
+
+/// Gives `plugin` a handle bound to `host`. Handle 0 is never issued and means
+/// "no host callbacks".
+pub fn register(host: Arc<Host>, plugin: &mut Plugin) {
+    plugin.host_handle = NEXT_HANDLE.fetch_add(1, Ordering::Relaxed);
+
+    let registration = Registration {
+        host,
+        plugin: plugin.clone(),
+    };
+
+    if let Ok(mut registry) = REGISTRY.write() {
+        registry.insert(plugin.host_handle, registration);
+    }
+}
+
+/// Invalidates a plugin's handle; later callbacks with it return `false`.
+pub fn revoke(handle: u64) {
+    if let Ok(mut registry) = REGISTRY.write() {
+        registry.remove(&handle);
+    }
+}
+
+/// The callback table for a plugin's handle.
+pub fn api(handle: u64) -> HostApi {
+    HostApi {
+        handle,
+        send_message,
+        send_notice,
+        schedule,
+        in_channel,
+        lag,
+        channel_modes,
+        member_modes,
+        members,
+    }
+}
+
+fn lookup(handle: u64) -> Option<(Arc<Host>, Plugin)> {
+    let registry = REGISTRY.read().ok()?;
+    let registration = registry.get(&handle)?;
+    Some((registration.host.clone(), registration.plugin.clone()))
+}
+
+fn read(ptr: *const c_char) -> Option<String> {
+    if ptr.is_null() {
+        return None;
+    }
+
+    unsafe { CStr::from_ptr(ptr) }
+        .to_str()
+        .ok()
+        .map(|s| s.to_string())
+}
+
+fn send(handle: u64, kind: Kind, target: *const c_char, text: *const c_char) -> bool {
+    let (host, _) = match lookup(handle) {
+        Some(found) => found,
+        None => return false,
+    };
+
+    let (target, text) = match (read(target), read(text)) {
+        (Some(target), Some(text)) => (target, text),
+        _ => return false,
+    };
+
+    match host.outbound() {
+        Some(outbound) => process_item(
+            &outbound,
+            Priority::Bulk,
+            Level::User,
+            kind,
+            &target,
+            &Item::plain(&text),
+        ),
+        None => false,
+    }
+}
+
+extern "C" fn send_message(handle: u64, target: *const c_char, text: *const c_char) -> bool {
+    send(handle, Kind::Privmsg, target, text)
+}
+
+extern "C" fn send_notice(handle: u64, target: *const c_char, text: *const c_char) -> bool {
+    send(handle, Kind::Notice, target, text)
+}
+
+extern "C" fn schedule(
+    handle: u64,
+    delay_ms: u64,
+    cmd: *const c_char,
+    param: *const c_char,
+    channel: *const c_char,
+) -> bool {
+    let (host, _) = match lookup(handle) {
+        Some(found) => found,
+        None => return false,
+    };
+
+    let (cmd, param, channel) = match (read(cmd), read(param), read(channel)) {
+        (Some(cmd), Some(param), Some(channel)) => (cmd, param, channel),
+        _ => return false,
+    };
+
+    let runtime = match &host.runtime {
+        Some(runtime) => runtime.clone(),
+        None => return false,
+    };
+
+    runtime.spawn(async move {
+        tokio::time::sleep(Duration::from_millis(delay_ms)).await;
+
+        // The plugin may have been unloaded while we slept
+        let (host, plugin) = match lookup(handle) {
+            Some(found) => found,
+            None => return,
+        };
+
+        let results = match host
+            .executor
+            .call(
+                &plugin,
+                &cmd,
+                &param,
+                &Caller::internal("host!host@reinze.internal"),
+                &channel,
+                host.color_ffi,
+            )
+            .await
+        {
+            Ok(results) => results,
+            Err(e) => {
+                println!(
+                    "Error running scheduled '{}' for {}: {}",
+                    cmd, plugin.name, e
+                );
+                return;
+            }
+        };
+
+        if let Some(outbound) = host.outbound() {
+            for line in results.iter().filter(|line| !line.is_empty()) {
+                process_item(
+                    &outbound,
+                    Priority::Bulk,
+                    Level::Owner,
+                    Kind::Privmsg,
+                    &channel,
+                    &Item::parse(line, plugin.structured_output()),
+                );
+            }
+        }
+    });
+
+    true
+}
+
+extern "C" fn in_channel(handle: u64, channel: *const c_char) -> bool {
+    let (host, _) = match lookup(handle) {
+        Some(found) => found,
+        None => return false,
+    };
+
+    match (read(channel), host.outbound()) {
+        (Some(channel), Some(_)) => host.channels.contains(&channel),
+        _ => false,
+    }
+}
+
+extern "C" fn lag(handle: u64) -> i64 {
+    let (host, _) = match lookup(handle) {
+        Some(found) => found,
+        None => return -1,
+    };
+
+    match host.connections.lag() {
+        Some(lag) => lag.as_millis() as i64,
+        None => -1,
+    }
+}
+
+// Copies `value` into a plugin's buffer, truncated to fit with its NUL, and
+// returns its full length
+fn write(value: &str, buffer: *mut c_char, capacity: usize) -> i64 {
+    if !buffer.is_null() && capacity > 0 {
+        let length = value.len().min(capacity - 1);
+        unsafe {
+            std::ptr::copy_nonoverlapping(value.as_ptr() as *const c_char, buffer, length);
+            *buffer.add(length) = 0;
+        }
+    }
+
+    value.len() as i64
+}
+
+extern "C" fn channel_modes(
+    handle: u64,
+    channel: *const c_char,
+    buffer: *mut c_char,
+    capacity: usize,
+) -> i64 {
+    let (host, _) = match lookup(handle) {
+        Some(found) => found,
+        None => return -1,
+    };
+
+    match read(channel).and_then(|channel| host.channels.channel(&channel)) {
+        Some(channel) => write(&channel.mode_string(), buffer, capacity),
+        None => -1,
+    }
+}
+
+extern "C" fn member_modes(
+    handle: u64,
+    channel: *const c_char,
+    nick: *const c_char,
+    buffer: *mut c_char,
+    capacity: usize,
+) -> i64 {
+    let (host, _) = match lookup(handle) {
+        Some(found) => found,
+        None => return -1,
+    };
+
+    let (channel, outbound) = match (read(channel), host.outbound()) {
+        (Some(channel), Some(outbound)) => (channel, outbound),
+        _ => return -1,
+    };
+    let nick = read(nick).unwrap_or_else(|| outbound.nickname());
+
+    match host.channels.member_modes(&channel, &nick) {
+        Some(modes) => write(&modes, buffer, capacity),
+        None => -1,
+    }
+}
+
+extern "C" fn members(
+    handle: u64,
+    channel: *const c_char,
+    buffer: *mut c_char,
+    capacity: usize,
+) -> i64 {
+    let (host, _) = match lookup(handle) {
+        Some(found) => found,
+        None => return -1,
+    };
+
+    let channel = match read(channel).and_then(|channel| host.channels.channel(&channel)) {
+        Some(channel) => channel,
+        None => return -1,
+    };
+    let nicks = channel
+        .members()
+        .map(|member| member.nick.as_str())
+        .collect::<Vec<&str>>();
+
+    write(&nicks.join("\n"), buffer, capacity)
+}
@@ -0,0 +1,318 @@
+use crate::permissions::{Level, matches_sender};
+use crate::settings::{IgnoreSettings, Settings};
+use std::collections::HashMap;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+/// One ignored mask, forever or until `expires`.
+#[derive(Clone, Debug)]
+pub struct Ignore {
+    pub mask: String,
+    pub expires: Option<Instant>,
+    pub reason: String,
+}
+
+// How often a sender has sent the same command line in a row.
+struct Repeat {
+    line: String,
+    count: usize,
+    since: Instant,
+}
+
+/// Senders the bot never answers, from `[ignore]` and `+ignore`, plus the
+/// bookkeeping that catches another client stuck in a loop with the bot. The
+/// one place ignores live: permission levels only ever grant more.
+pub struct Ignores {
+    settings: IgnoreSettings,
+    entries: Mutex<Vec<Ignore>>,
+    recent: Mutex<HashMap<String, Repeat>>,
+}
+
+impl Ignores {
+    pub fn new(settings: &Settings) -> Self {
+        let entries = settings
+            .ignore_masks()
+            .iter()
+            .map(|mask| Ignore {
+                mask: normalize(mask),
+                expires: None,
+                reason: "configured".to_string(),
+            })
+            .collect();
+
+        Self {
+            settings: settings.ignore.clone(),
+            entries: Mutex::new(entries),
+            recent: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// Whether NOTICEs may trigger commands.
+    pub fn notice_commands(&self) -> bool {
+        self.settings.notice_commands
+    }
+
+    /// Whether `hostmask`, logged in to `account` and at `level`, is ignored
+    /// right now. Owners never are, so they can always undo an ignore that
+    /// caught them. Expired ignores are dropped.
+    pub fn is_ignored(&self, hostmask: &str, account: Option<&str>, level: Level) -> bool {
+        if level == Level::Owner {
+            return false;
+        }
+
+        let mut entries = match self.entries.lock() {
+            Ok(entries) => entries,
+            Err(_) => return false,
+        };
+
+        let now = Instant::now();
+        entries.retain(|ignore| ignore.expires.is_none_or(|expires| expires > now));
+
+        entries
+            .iter()
+            .any(|ignore| matches_sender(&ignore.mask, hostmask, account))
+    }
+
+    /// Ignores `mask` (a nick, a `nick!user@host` mask or an `$a:account`
+    /// mask) for `duration`, or forever. An existing ignore of the same mask is replaced.
+    pub fn add(&self, mask: &str, duration: Option<Duration>, reason: &str) -> String {
+        let ignore = Ignore {
+            mask: normalize(mask),
+            expires: duration.map(|duration| Instant::now() + duration),
+            reason: reason.to_string(),
+        };
+        let mask = ignore.mask.clone();
+
+        if let Ok(mut entries) = self.entries.lock() {
+            entries.retain(|existing| !existing.mask.eq_ignore_ascii_case(&mask));
+            entries.push(ignore);
+        }
+
+        mask
+    }
+
+    /// Stops ignoring `mask`. `false` if it wasn't ignored.
+    pub fn remove(&self, mask: &str) -> bool {
+        let mask = normalize(mask);
+        let mut entries = match self.entries.lock() {
+            Ok(entries) => entries,
+            Err(_) => return false,
+        };
+
+        let before = entries.len();
+        entries.retain(|existing| !existing.mask.eq_ignore_ascii_case(&mask));
+        entries.len() != before
+    }
+
+    pub fn list(&self) -> Vec<Ignore> {
+        let now = Instant::now();
+        self.entries
+            .lock()
+            .map(|entries| {
+                entries
+                    .iter()
+                    .filter(|ignore| ignore.expires.is_none_or(|expires| expires > now))
+                    .cloned()
+                    .collect()
+            })
+            .unwrap_or_default()
+    }
+
+    /// Records a command line from `hostmask`. Sending the same line
+    /// `loop_threshold` times in a row, each within `loop_window` of the
+    /// first, looks like another bot answering the bot, so the sender's host
+    /// is ignored for `loop_ignore`. Returns the mask ignored, if any. Admins
+    /// and owners repeat themselves on purpose, so aren't counted.
+    pub fn record(&self, hostmask: &str, level: Level, line: &str) -> Option<String> {
+        if level >= Level::Admin {
+            return None;
+        }
+
+        let threshold = self.settings.loop_threshold();
+        let window = self.settings.loop_window();
+        let now = Instant::now();
+
+        let looped = {
+            let mut recent = self.recent.lock().ok()?;
+            recent.retain(|_, repeat| now.duration_since(repeat.since) <= window);
+
+            let repeat = recent
+                .entry(hostmask.to_lowercase())
+                .or_insert_with(|| Repeat {
+                    line: line.to_string(),
+                    count: 0,
+                    since: now,
+                });
+
+            if repeat.line == line {
+                repeat.count += 1;
+            } else {
+                *repeat = Repeat {
+                    line: line.to_string(),
+                    count: 1,
+                    since: now,
+                };
+            }
+
+            let looped = repeat.count >= threshold;
+            if looped {
+                recent.remove(&hostmask.to_lowercase());
+            }
+            looped
+        };
+
+        if !looped {
+            return None;
+        }
+
+        let mask = match hostmask.split_once('@') {
+            Some((_, host)) => format!("*!*@{}", host),
+            None => hostmask.to_string(),
+        };
+
+        Some(self.add(
+            &mask,
+            Some(self.settings.loop_ignore()),
+            &format!("sent '{}' {} times", line, threshold),
+        ))
+    }
+}
+
+/// A bare nick means that nick from anywhere, and `user@host` any nick there.
+/// Account masks are left alone.
+pub fn normalize(mask: &str) -> String {
+    let mask = mask.trim();
+    if mask.starts_with("$a:") {
+        return mask.to_string();
+    }
+    match (mask.contains('!'), mask.contains('@')) {
+        (false, false) => format!("{}!*@*", mask),
+        (false, true) => format!("*!{}", mask),
+        _ => mask.to_string(),
+    }
+}
+
+/// A duration as its two largest units, e.g. `1h5m` or `45s`.
+pub fn describe_duration(duration: Duration) -> String {
+    let secs = duration.as_secs();
+    let units = [(86400, 'd'), (3600, 'h'), (60, 'm'), (1, 's')];
+
+    let parts = units
+        .iter()
+        .scan(secs, |left, &(size, unit)| {
+            let count = *left / size;
+            *left %= size;
+            Some((count, unit))
+        })
+        .filter(|(count, _)| *count > 0)
+        .take(2)
+        .map(|(count, unit)| format!("{}{}", count, unit))
+        .collect::<Vec<String>>();
+
+    if parts.is_empty() {
+        "0s".to_string()
+    } else {
+        parts.concat()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn ignores() -> Ignores {
+        Ignores::new(&Settings {
+            ignore: IgnoreSettings {
+                masks: vec!["OtherBot".to_string(), "$a:spammer".to_string()],
+                loop_threshold: Some(3),
+                ..Default::default()
+            },
+            ..Default::default()
+        })
+    }
+
+    #[test]
+    fn test_normalize() {
+        assert_eq!(normalize("OtherBot"), "OtherBot!*@*");
+        assert_eq!(normalize("~bot@host"), "*!~bot@host");
+        assert_eq!(normalize("*!*@host"), "*!*@host");
+        assert_eq!(normalize("$a:spammer"), "$a:spammer");
+    }
+
+    #[test]
+    fn test_configured_and_runtime_ignores() {
+        let ignores = ignores();
+        assert!(ignores.is_ignored("otherbot!bot@example.com", None, Level::User));
+        assert!(!ignores.is_ignored("zezima!z@example.com", None, Level::User));
+        // Logging in doesn't get around an account ignore
+        assert!(ignores.is_ignored("new!n@example.com", Some("Spammer"), Level::Trusted));
+
+        ignores.add("*!*@example.com", None, "spam");
+        assert!(ignores.is_ignored("zezima!z@example.com", None, Level::User));
+        assert!(ignores.remove("*!*@example.com"));
+        assert!(!ignores.remove("*!*@example.com"));
+        assert!(!ignores.is_ignored("zezima!z@example.com", None, Level::User));
+    }
+
+    #[test]
+    fn test_expiry() {
+        let ignores = ignores();
+        ignores.add("zezima", Some(Duration::ZERO), "");
+        assert!(!ignores.is_ignored("zezima!z@example.com", None, Level::User));
+        assert_eq!(ignores.list().len(), 2);
+    }
+
+    #[test]
+    fn test_loop_detection() {
+        let ignores = ignores();
+        assert_eq!(
+            ignores.record("bot!b@bots.example", Level::User, "+ge whip"),
+            None
+        );
+        assert_eq!(
+            ignores.record("bot!b@bots.example", Level::User, "+ge whip"),
+            None
+        );
+        // A different line starts the count over
+        assert_eq!(
+            ignores.record("bot!b@bots.example", Level::User, "+ge bond"),
+            None
+        );
+        assert_eq!(
+            ignores.record("bot!b@bots.example", Level::User, "+ge bond"),
+            None
+        );
+        assert_eq!(
+            ignores
+                .record("bot!b@bots.example", Level::User, "+ge bond")
+                .as_deref(),
+            Some("*!*@bots.example")
+        );
+        assert!(ignores.is_ignored("bot2!b@bots.example", None, Level::User));
+    }
+
+    #[test]
+    fn test_staff_exempt() {
+        let ignores = ignores();
+        for _ in 0..5 {
+            assert_eq!(
+                ignores.record("kick!k@staff.example", Level::Admin, "+lag"),
+                None
+            );
+        }
+        assert_eq!(ignores.list().len(), 2);
+
+        // An owner caught by someone else's ignore can still lift it
+        ignores.add("*!*@shared.example", None, "spam");
+        assert!(ignores.is_ignored("zezima!z@shared.example", None, Level::Admin));
+        assert!(!ignores.is_ignored("kick!k@shared.example", None, Level::Owner));
+    }
+
+    #[test]
+    fn test_describe_duration() {
+        assert_eq!(describe_duration(Duration::from_secs(45)), "45s");
+        assert_eq!(describe_duration(Duration::from_secs(3905)), "1h5m");
+        assert_eq!(describe_duration(Duration::from_secs(90061)), "1d1h");
+        assert_eq!(describe_duration(Duration::ZERO), "0s");
+    }
+}
@@ -0,0 +1,562 @@
+use crate::settings::{CapabilitySettings, SaslMechanism, SaslSettings};
+use base64::Engine;
+use base64::engine::general_purpose::STANDARD;
+use chrono::{DateTime, Local};
+use irc::client::prelude::{CapSubCommand, Command, Config, Message, Response};
+
+// AUTHENTICATE payloads are sent in chunks of this many bytes
+const SASL_CHUNK: usize = 400;
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum Phase {
+    // Collecting `CAP LS` replies
+    Listing,
+    // Waiting for the server to ACK or NAK our `CAP REQ`
+    Requesting,
+    // Between `AUTHENTICATE <mechanism>` and the SASL result
+    Authenticating,
+    Done,
+}
+
+/// IRCv3 capability negotiation for one connection, from `CAP LS` to
+/// `CAP END`, including SASL. Feed it every message the server sends until
+/// it's done, and send whatever it returns.
+pub struct Negotiation {
+    wanted: Vec<String>,
+    sasl: Option<SaslSettings>,
+    // Capabilities the server offered, with their values, e.g. `sasl=PLAIN`
+    offered: Vec<String>,
+    enabled: Vec<String>,
+    phase: Phase,
+    // Why we quit, when SASL was required and failed
+    refused: Option<String>,
+}
+
+impl Negotiation {
+    pub fn new(settings: &CapabilitySettings) -> Self {
+        Self {
+            wanted: settings.request(),
+            sasl: settings.sasl.clone(),
+            offered: vec![],
+            enabled: vec![],
+            phase: Phase::Listing,
+            refused: None,
+        }
+    }
+
+    /// Whether there's anything to negotiate. If not, registration goes
+    /// through `Client::identify` as usual.
+    pub fn wanted(&self) -> bool {
+        !self.wanted.is_empty() || self.sasl.is_some()
+    }
+
+    /// What to send instead of `Client::identify`: `CAP LS`, then the usual
+    /// registration, which the server holds until `CAP END`.
+    pub fn start(&self, config: &Config) -> Vec<Command> {
+        let mut commands = vec![Command::CAP(
+            None,
+            CapSubCommand::LS,
+            Some("302".to_string()),
+            None,
+        )];
+
+        if !config.password().is_empty() {
+            commands.push(Command::PASS(config.password().to_string()));
+        }
+        commands.push(Command::NICK(
+            config.nickname().unwrap_or_default().to_string(),
+        ));
+        commands.push(Command::USER(
+            config.username().to_string(),
+            "0".to_string(),
+            config.real_name().to_string(),
+        ));
+
+        commands
+    }
+
+    pub fn is_done(&self) -> bool {
+        self.phase == Phase::Done
+    }
+
+    /// Why negotiation quit the connection, if SASL was required and failed.
+    /// Connecting again won't go any better.
+    pub fn refused(&self) -> Option<&str> {
+        self.refused.as_deref()
+    }
+
+    /// Takes the next message from the server, returning what to send back.
+    pub fn handle(&mut self, message: &Message) -> Vec<Command> {
+        match (&message.command, self.phase) {
+            (Command::CAP(_, CapSubCommand::LS, arg, param), Phase::Listing) => {
+                let (more, caps) = cap_list(arg, param);
+                self.offered
+                    .extend(caps.split_whitespace().map(|cap| cap.to_string()));
+
+                if more { vec![] } else { self.request() }
+            }
+            (Command::CAP(_, CapSubCommand::ACK, arg, param), Phase::Requesting) => {
+                let (_, caps) = cap_list(arg, param);
+                for cap in caps.split_whitespace() {
+                    match cap.strip_prefix('-') {
+                        Some(disabled) => self.enabled.retain(|enabled| enabled != disabled),
+                        None => self.enabled.push(cap.to_string()),
+                    }
+                }
+
+                match &self.sasl {
+                    Some(sasl) if self.enabled.iter().any(|cap| cap == "sasl") => {
+                        self.phase = Phase::Authenticating;
+                        vec![Command::AUTHENTICATE(sasl.mechanism.name().to_string())]
+                    }
+                    _ => self.end(),
+                }
+            }
+            (Command::CAP(_, CapSubCommand::NAK, arg, param), Phase::Requesting) => {
+                let (_, caps) = cap_list(arg, param);
+                // A request is all or nothing, so nothing was enabled
+                if caps.split_whitespace().any(|cap| cap == "sasl") {
+                    self.sasl_failed(&format!("server refused '{}'", caps))
+                } else {
+                    println!("Server refused capabilities '{}'", caps);
+                    self.end()
+                }
+            }
+            (Command::AUTHENTICATE(data), Phase::Authenticating) if data == "+" => {
+                self.credentials()
+            }
+            (Command::Response(response, args), Phase::Authenticating) => match response {
+                Response::RPL_SASLSUCCESS | Response::ERR_SASLALREADY => {
+                    println!("SASL authentication succeeded");
+                    self.end()
+                }
+                Response::ERR_SASLFAIL
+                | Response::ERR_SASLTOOLONG
+                | Response::ERR_SASLABORTED
+                | Response::ERR_NICKLOCKED => {
+                    let reason = args.last().cloned().unwrap_or_default();
+                    self.sasl_failed(&reason)
+                }
+                // Sent when the mechanism we asked for isn't one of these
+                Response::RPL_SASLMECHS => {
+                    let mechanisms = args.get(1).cloned().unwrap_or_default();
+                    self.sasl_failed(&format!("server only offers {}", mechanisms))
+                }
+                _ => vec![],
+            },
+            // Servers without CAP register us without ever answering `CAP LS`
+            (Command::Response(Response::RPL_WELCOME, _), _) => {
+                self.phase = Phase::Done;
+                vec![]
+            }
+            _ => vec![],
+        }
+    }
+
+    // Asks for everything wanted that the server offered
+    fn request(&mut self) -> Vec<Command> {
+        let offered = |name: &str| {
+            self.offered
+                .iter()
+                .find(|cap| cap.split('=').next() == Some(name))
+        };
+
+        let mut request = self
+            .wanted
+            .iter()
+            .filter(|cap| offered(cap).is_some())
+            .cloned()
+            .collect::<Vec<String>>();
+
+        if let Some(sasl) = &self.sasl {
+            // `sasl=PLAIN,EXTERNAL` lists mechanisms; a bare `sasl` doesn't say
+            let supported = offered("sasl").map(|cap| match cap.split_once('=') {
+                Some((_, mechanisms)) => mechanisms
+                    .split(',')
+                    .any(|mechanism| mechanism.eq_ignore_ascii_case(sasl.mechanism.name())),
+                None => true,
+            });
+
+            match supported {
+                Some(true) => request.push("sasl".to_string()),
+                Some(false) => {
+                    let reason = format!("server doesn't offer {}", sasl.mechanism.name());
+                    return self.sasl_failed(&reason);
+                }
+                None => return self.sasl_failed("server doesn't offer SASL"),
+            }
+        }
+
+        if request.is_empty() {
+            return self.end();
+        }
+
+        self.phase = Phase::Requesting;
+        vec![Command::CAP(
+            None,
+            CapSubCommand::REQ,
+            None,
+            Some(request.join(" ")),
+        )]
+    }
+
+    fn credentials(&mut self) -> Vec<Command> {
+        let sasl = match &self.sasl {
+            Some(sasl) => sasl,
+            None => return self.end(),
+        };
+
+        let payload = match sasl.mechanism {
+            SaslMechanism::Plain => STANDARD.encode(format!(
+                "{}\0{}\0{}",
+                sasl.account, sasl.account, sasl.password
+            )),
+            // The server takes the identity from the client certificate
+            SaslMechanism::External => String::new(),
+        };
+
+        authenticate(&payload)
+    }
+
+    // Carries on without SASL, or gives up on the connection if it's required
+    fn sasl_failed(&mut self, reason: &str) -> Vec<Command> {
+        println!("SASL authentication failed: {}", reason);
+
+        match &self.sasl {
+            Some(sasl) if sasl.required => {
+                self.phase = Phase::Done;
+                self.refused = Some(format!("SASL authentication failed: {}", reason));
+                vec![Command::QUIT(Some(
+                    "SASL authentication failed".to_string(),
+                ))]
+            }
+            _ => self.end(),
+        }
+    }
+
+    fn end(&mut self) -> Vec<Command> {
+        self.phase = Phase::Done;
+        if !self.enabled.is_empty() {
+            println!("Capabilities enabled: {}", self.enabled.join(" "));
+        }
+
+        vec![Command::CAP(None, CapSubCommand::END, None, None)]
+    }
+}
+
+// The capability list in a CAP reply, and whether more lines of it follow
+// (`CAP * LS * :...` in 302 negotiation)
+fn cap_list<'a>(arg: &'a Option<String>, param: &'a Option<String>) -> (bool, &'a str) {
+    match (arg.as_deref(), param.as_deref()) {
+        (Some("*"), Some(caps)) => (true, caps),
+        (_, Some(caps)) | (Some(caps), None) => (false, caps),
+        (None, None) => (false, ""),
+    }
+}
+
+// Splits a base64 payload into AUTHENTICATE lines. A payload that ends on a
+// chunk boundary (or is empty) is finished with a lone `+`.
+fn authenticate(payload: &str) -> Vec<Command> {
+    let mut commands = payload
+        .as_bytes()
+        .chunks(SASL_CHUNK)
+        .map(|chunk| Command::AUTHENTICATE(String::from_utf8_lossy(chunk).to_string()))
+        .collect::<Vec<Command>>();
+
+    if payload.len().is_multiple_of(SASL_CHUNK) {
+        commands.push(Command::AUTHENTICATE("+".to_string()));
+    }
+
+    commands
+}
+
+/// A message's IRCv3 tags as `(key, value)`, with an empty value for tags
+/// that have none.
+pub fn tags(message: &Message) -> Vec<(String, String)> {
+    message
+        .tags
+        .iter()
+        .flatten()
+        .map(|tag| (tag.0.clone(), tag.1.clone().unwrap_or_default()))
+        .collect()
+}
+
+/// Tags as plugins see them: one `key=value` per line, with values escaped
+/// as on the wire so they can't contain a newline.
+pub fn serialize_tags(tags: &[(String, String)]) -> String {
+    tags.iter()
+        .map(|(key, value)| format!("{}={}", key, escape(value)))
+        .collect::<Vec<String>>()
+        .join("\n")
+}
+
+pub fn parse_tags(text: &str) -> Vec<(String, String)> {
+    text.lines()
+        .filter(|line| !line.is_empty())
+        .map(|line| match line.split_once('=') {
+            Some((key, value)) => (key.to_string(), unescape(value)),
+            None => (line.to_string(), String::new()),
+        })
+        .collect()
+}
+
+/// When the server says a message was sent (`server-time`), if it does.
+pub fn server_time(message: &Message) -> Option<DateTime<Local>> {
+    let (_, time) = tags(message).into_iter().find(|(key, _)| key == "time")?;
+    DateTime::parse_from_rfc3339(&time)
+        .ok()
+        .map(|time| time.with_timezone(&Local))
+}
+
+fn escape(value: &str) -> String {
+    value
+        .chars()
+        .map(|c| match c {
+            ';' => "\\:".to_string(),
+            ' ' => "\\s".to_string(),
+            '\\' => "\\\\".to_string(),
+            '\r' => "\\r".to_string(),
+            '\n' => "\\n".to_string(),
+            c => c.to_string(),
+        })
+        .collect()
+}
+
+fn unescape(value: &str) -> String {
+    let mut unescaped = String::new();
+    let mut chars = value.chars();
+
+    while let Some(c) = chars.next() {
+        if c != '\\' {
+            unescaped.push(c);
+            continue;
+        }
+
+        match chars.next() {
+            Some(':') => unescaped.push(';'),
+            Some('s') => unescaped.push(' '),
+            Some('r') => unescaped.push('\r'),
+            Some('n') => unescaped.push('\n'),
+            Some(other) => unescaped.push(other),
+            None => (),
+        }
+    }
+
+    unescaped
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::settings::Settings;
+
+    fn configured(toml: &str) -> Negotiation {
+        Negotiation::new(&Settings::parse(toml).unwrap().capabilities)
+    }
+
+    // Plays a scripted server: each line it sends, and the raw lines the
+    // client should answer with
+    fn converse(negotiation: &mut Negotiation, script: &[(&str, &[&str])]) {
+        for (line, expected) in script {
+            let message = line.parse::<Message>().unwrap();
+            let answer = negotiation
+                .handle(&message)
+                .into_iter()
+                .map(|command| Message::from(command).to_string().trim_end().to_string())
+                .collect::<Vec<String>>();
+            assert_eq!(&answer, expected, "after '{}'", line);
+        }
+    }
+
+    #[test]
+    fn test_sasl_plain() {
+        let mut negotiation = configured(
+            r#"
+            [capabilities]
+            request = ["server-time", "account-tag", "echo-message"]
+
+            [capabilities.sasl]
+            mechanism = "plain"
+            account = "RustKick"
+            password = "hunter2"
+            "#,
+        );
+
+        converse(
+            &mut negotiation,
+            &[
+                (
+                    ":irc.example CAP * LS * :multi-prefix sasl=PLAIN,EXTERNAL",
+                    &[],
+                ),
+                (
+                    ":irc.example CAP * LS :server-time account-tag",
+                    &["CAP REQ :server-time account-tag sasl"],
+                ),
+                (
+                    ":irc.example CAP * ACK :server-time account-tag sasl",
+                    &["AUTHENTICATE PLAIN"],
+                ),
+                (
+                    "AUTHENTICATE +",
+                    &["AUTHENTICATE UnVzdEtpY2sAUnVzdEtpY2sAaHVudGVyMg=="],
+                ),
+                (
+                    ":irc.example 900 * RustKick!k@host RustKick :You are now logged in",
+                    &[],
+                ),
+                (
+                    ":irc.example 903 * :SASL authentication successful",
+                    &["CAP END"],
+                ),
+            ],
+        );
+
+        assert!(negotiation.is_done());
+        assert_eq!(negotiation.enabled, ["server-time", "account-tag", "sasl"]);
+    }
+
+    #[test]
+    fn test_sasl_external_required_and_refused() {
+        let mut negotiation = configured(
+            r#"
+            [capabilities]
+            request = []
+
+            [capabilities.sasl]
+            mechanism = "external"
+            required = true
+            "#,
+        );
+
+        converse(
+            &mut negotiation,
+            &[
+                (":irc.example CAP * LS :sasl", &["CAP REQ sasl"]),
+                (":irc.example CAP * ACK :sasl", &["AUTHENTICATE EXTERNAL"]),
+                ("AUTHENTICATE +", &["AUTHENTICATE +"]),
+                (
+                    ":irc.example 904 * :SASL authentication failed",
+                    &["QUIT :SASL authentication failed"],
+                ),
+            ],
+        );
+        assert!(negotiation.is_done());
+        assert!(negotiation.refused().is_some());
+    }
+
+    #[test]
+    fn test_sasl_nick_locked() {
+        let mut negotiation = configured(
+            r#"
+            [capabilities]
+            request = []
+
+            [capabilities.sasl]
+            account = "RustKick"
+            password = "hunter2"
+            "#,
+        );
+
+        converse(
+            &mut negotiation,
+            &[
+                (":irc.example CAP * LS :sasl", &["CAP REQ sasl"]),
+                (":irc.example CAP * ACK :sasl", &["AUTHENTICATE PLAIN"]),
+                (
+                    ":irc.example 902 * :You must use a nick assigned to you",
+                    &["CAP END"],
+                ),
+            ],
+        );
+        assert!(negotiation.is_done());
+        assert!(negotiation.refused().is_none());
+    }
+
+    #[test]
+    fn test_sasl_mechanism_unsupported() {
+        let mut negotiation = configured(
+            r#"
+            [capabilities]
+            request = []
+
+            [capabilities.sasl]
+            mechanism = "external"
+            required = true
+            "#,
+        );
+
+        // A bare `sasl` doesn't list mechanisms, so we only find out here
+        converse(
+            &mut negotiation,
+            &[
+                (":irc.example CAP * LS :sasl", &["CAP REQ sasl"]),
+                (":irc.example CAP * ACK :sasl", &["AUTHENTICATE EXTERNAL"]),
+                (
+                    ":irc.example 908 * PLAIN :are available SASL mechanisms",
+                    &["QUIT :SASL authentication failed"],
+                ),
+            ],
+        );
+        assert!(negotiation.is_done());
+    }
+
+    #[test]
+    fn test_nothing_offered() {
+        let mut negotiation = configured("");
+        assert!(negotiation.wanted());
+
+        converse(
+            &mut negotiation,
+            &[(":irc.example CAP * LS :userhost-in-names", &["CAP END"])],
+        );
+        assert!(negotiation.enabled.is_empty());
+
+        let nothing = configured("[capabilities]\nrequest = []");
+        assert!(!nothing.wanted());
+    }
+
+    #[test]
+    fn test_nak_carries_on() {
+        let mut negotiation = configured("");
+        converse(
+            &mut negotiation,
+            &[
+                (
+                    ":irc.example CAP * LS :server-time away-notify",
+                    &["CAP REQ :server-time away-notify"],
+                ),
+                (
+                    ":irc.example CAP * NAK :server-time away-notify",
+                    &["CAP END"],
+                ),
+            ],
+        );
+    }
+
+    #[test]
+    fn test_authenticate_chunks() {
+        assert_eq!(authenticate("").len(), 1);
+        assert_eq!(authenticate(&"a".repeat(400)).len(), 2);
+        assert_eq!(authenticate(&"a".repeat(401)).len(), 2);
+    }
+
+    #[test]
+    fn test_tags() {
+        let message =
+            "@time=2026-01-02T03:04:05.000Z;account=kick;+draft/x=a\\sb :k!k@h PRIVMSG #c :hi"
+                .parse::<Message>()
+                .unwrap();
+
+        let tags = tags(&message);
+        assert!(tags.contains(&("account".to_string(), "kick".to_string())));
+        assert_eq!(parse_tags(&serialize_tags(&tags)), tags);
+        assert_eq!(
+            serialize_tags(&[("msg".to_string(), "a b;c\n".to_string())]),
+            "msg=a\\sb\\:c\\n"
+        );
+
+        let time = server_time(&message).unwrap();
+        assert_eq!(time.to_utc().to_rfc3339(), "2026-01-02T03:04:05+00:00");
+    }
+}
@@ -0,0 +1,756 @@
+use crate::events::Event;
+use crate::help::{CommandInfo, parse_command_info, serialize_command_info};
+use crate::ircv3;
+use crate::listeners::{ListenerDef, parse_listener_declarations};
+use crate::manifest::{Manifest, split_lines};
+use crate::permissions::{Caller, Level, parse_level_declarations, serialize_level_declarations};
+use crate::plugins::Plugin;
+use crate::timers::{TimerDef, parse_timer_declarations};
+use common::author::cache::{color_ffi, init};
+use std::io::{self, BufReader, BufWriter, Read, Write};
+use std::process::{Child, ChildStdin, Command, Stdio};
+use std::sync::Mutex;
+use std::sync::mpsc::{Receiver, RecvTimeoutError, channel};
+use std::thread;
+use std::time::Duration;
+
+/// First argument that makes the `reinze` binary act as a plugin host.
+pub const HOST_ARG: &str = "--plugin-host";
+
+/// Process name the child runs under, so it's recognisable in `ps`.
+pub const HOST_NAME: &str = "reinze-plugin-host";
+
+// Frames refuse to allocate more than this, so a corrupted length can't
+// exhaust memory.
+const MAX_FRAME: u32 = 16 * 1024 * 1024;
+
+/// Writes one frame: a big-endian field count followed by each field as a
+/// big-endian byte length and its UTF-8 bytes.
+pub fn write_frame<W: Write, S: AsRef<str>>(writer: &mut W, fields: &[S]) -> io::Result<()> {
+    writer.write_all(&(fields.len() as u32).to_be_bytes())?;
+    for field in fields {
+        let bytes = field.as_ref().as_bytes();
+        writer.write_all(&(bytes.len() as u32).to_be_bytes())?;
+        writer.write_all(bytes)?;
+    }
+    writer.flush()
+}
+
+/// Reads one frame written by `write_frame`.
+pub fn read_frame<R: Read>(reader: &mut R) -> io::Result<Vec<String>> {
+    let count = read_u32(reader)?;
+    if count > MAX_FRAME {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            "frame too large",
+        ));
+    }
+
+    let mut fields = Vec::with_capacity(count as usize);
+    for _ in 0..count {
+        let len = read_u32(reader)?;
+        if len > MAX_FRAME {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                "field too large",
+            ));
+        }
+        let mut bytes = vec![0; len as usize];
+        reader.read_exact(&mut bytes)?;
+        let field =
+            String::from_utf8(bytes).map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
+        fields.push(field);
+    }
+
+    Ok(fields)
+}
+
+fn read_u32<R: Read>(reader: &mut R) -> io::Result<u32> {
+    let mut bytes = [0; 4];
+    reader.read_exact(&mut bytes)?;
+    Ok(u32::from_be_bytes(bytes))
+}
+
+/// What an isolated plugin reported about itself when its child started.
+pub struct Description {
+    pub commands: Vec<String>,
+    pub triggers: Vec<String>,
+    pub timers: Vec<TimerDef>,
+    pub events: Vec<String>,
+    pub listeners: Vec<ListenerDef>,
+    pub priority: i32,
+    pub command_info: Vec<CommandInfo>,
+    pub levels: Vec<(String, Level)>,
+    pub manifest: Option<Manifest>,
+}
+
+// Description frame layout: name, version, abi, features, triggers, commands,
+// timers, events, listeners, priority, command info, trigger levels. An empty
+// name means the plugin was discovered by the legacy probe.
+fn describe(plugin: &Plugin) -> Vec<String> {
+    let timers = plugin
+        .timers
+        .iter()
+        .map(|timer| format!("{}:{}s", timer.command, timer.interval.as_secs()))
+        .collect::<Vec<String>>()
+        .join("\n");
+
+    let listeners = plugin
+        .listeners
+        .iter()
+        .map(|listener| match listener.cooldown.as_secs() {
+            0 => format!("{}::{}", listener.name, listener.pattern),
+            secs => format!("{}:{}s:{}", listener.name, secs, listener.pattern),
+        })
+        .collect::<Vec<String>>()
+        .join("\n");
+
+    let (name, version, abi, features) = match &plugin.manifest {
+        Some(manifest) => (
+            manifest.name.clone(),
+            manifest.version.clone(),
+            manifest.abi_version.to_string(),
+            manifest.features.join("\n"),
+        ),
+        None => (String::new(), String::new(), String::new(), String::new()),
+    };
+
+    vec![
+        name,
+        version,
+        abi,
+        features,
+        plugin.triggers.join("\n"),
+        plugin.commands.join("\n"),
+        timers,
+        plugin.events.join("\n"),
+        listeners,
+        plugin.priority.to_string(),
+        serialize_command_info(&plugin.command_info),
+        serialize_level_declarations(&plugin.levels),
+    ]
+}
+
+fn parse_description(fields: Vec<String>) -> Result<Description, String> {
+    let [
+        name,
+        version,
+        abi,
+        features,
+        triggers,
+        commands,
+        timers,
+        events,
+        listeners,
+        priority,
+        command_info,
+        levels,
+    ]: [String; 12] = fields
+        .try_into()
+        .map_err(|_| "malformed description frame".to_string())?;
+
+    let triggers = split_lines(&triggers);
+    let commands = split_lines(&commands);
+    let timers = parse_timer_declarations(&timers);
+    let events: Vec<String> = events.lines().map(|s| s.to_string()).collect();
+    let listeners = parse_listener_declarations(&listeners)?;
+    let priority: i32 = priority
+        .parse()
+        .map_err(|_| "malformed priority".to_string())?;
+    let command_info = parse_command_info(&command_info)?;
+    let levels = parse_level_declarations(&levels)?;
+
+    let manifest = if name.is_empty() {
+        None
+    } else {
+        let manifest = Manifest {
+            name,
+            version,
+            abi_version: abi
+                .parse()
+                .map_err(|_| "malformed ABI version".to_string())?,
+            triggers: triggers.clone(),
+            commands: commands.clone(),
+            timers: timers.clone(),
+            features: features.lines().map(|s| s.to_string()).collect(),
+            events: events.clone(),
+            listeners: listeners.clone(),
+            priority,
+            command_info: command_info.clone(),
+            levels: levels.clone(),
+        };
+        manifest.validate()?;
+        Some(manifest)
+    };
+
+    Ok(Description {
+        commands,
+        triggers,
+        timers,
+        events,
+        listeners,
+        priority,
+        command_info,
+        levels,
+        manifest,
+    })
+}
+
+// Request frame layouts: `call, cmd, param, author, level, channel, tags`
+// for commands, `event, kind, source, channel, args, tags` for events and
+// `listen, listener, captures, author, level, channel, tags` for listener
+// matches. Lists are newline-separated, levels are by name and tags are
+// serialized as for in-process plugins.
+fn request_call(cmd: &str, param: &str, caller: &Caller, channel: &str) -> Vec<String> {
+    [
+        "call",
+        cmd,
+        param,
+        &caller.author,
+        caller.level.name(),
+        channel,
+        &ircv3::serialize_tags(&caller.tags),
+    ]
+    .iter()
+    .map(|s| s.to_string())
+    .collect()
+}
+
+fn request_event(event: &Event) -> Vec<String> {
+    vec![
+        "event".to_string(),
+        event.kind.clone(),
+        event.source.clone(),
+        event.channel.clone(),
+        event.args.join("\n"),
+        ircv3::serialize_tags(&event.tags),
+    ]
+}
+
+fn request_listen(
+    listener: &str,
+    captures: &[String],
+    caller: &Caller,
+    channel: &str,
+) -> Vec<String> {
+    vec![
+        "listen".to_string(),
+        listener.to_string(),
+        captures.join("\n"),
+        caller.author.clone(),
+        caller.level.name().to_string(),
+        channel.to_string(),
+        ircv3::serialize_tags(&caller.tags),
+    ]
+}
+
+fn parse_caller(author: &str, level: &str, tags: &str) -> Caller {
+    Caller {
+        author: author.to_string(),
+        level: Level::parse(level).unwrap_or_default(),
+        tags: ircv3::parse_tags(tags),
+    }
+}
+
+fn parse_event(kind: String, source: String, channel: String, args: String, tags: String) -> Event {
+    Event {
+        kind,
+        source,
+        channel,
+        args: args.lines().map(|s| s.to_string()).collect(),
+        tags: ircv3::parse_tags(&tags),
+    }
+}
+
+/// Entry point of the child process: loads a single plugin and answers
+/// request frames on stdin until the bot closes the pipe.
+pub fn serve(path: &str) {
+    init();
+
+    let mut output = protocol_output();
+    let mut input = BufReader::new(io::stdin());
+
+    let plugin = match Plugin::load(path) {
+        Ok(plugin) => plugin,
+        Err(e) => {
+            println!("Error loading plugin {}: {}", path, e);
+            return;
+        }
+    };
+
+    if write_frame(&mut output, &describe(&plugin)).is_err() {
+        return;
+    }
+
+    while let Ok(request) = read_frame(&mut input) {
+        let result = match request.as_slice() {
+            [tag, cmd, param, author, level, channel, tags] if tag == "call" => {
+                let caller = parse_caller(author, level, tags);
+                plugin.call(cmd, param, &caller, channel, color_ffi)
+            }
+            [tag, kind, source, channel, args, tags] if tag == "event" => {
+                let event = parse_event(
+                    kind.clone(),
+                    source.clone(),
+                    channel.clone(),
+                    args.clone(),
+                    tags.clone(),
+                );
+                plugin.event(&event, color_ffi)
+            }
+            [tag, listener, captures, author, level, channel, tags] if tag == "listen" => {
+                let captures = captures
+                    .split('\n')
+                    .map(|s| s.to_string())
+                    .collect::<Vec<_>>();
+                let caller = parse_caller(author, level, tags);
+                plugin.listen(listener, &captures, &caller, channel, color_ffi)
+            }
+            _ => {
+                eprintln!("{}: malformed request frame", HOST_NAME);
+                return;
+            }
+        };
+
+        let response = match result {
+            Ok(lines) => [vec!["ok".to_string()], lines].concat(),
+            Err(e) => vec!["err".to_string(), e],
+        };
+
+        if write_frame(&mut output, &response).is_err() {
+            return;
+        }
+    }
+}
+
+// Plugins are free to print, so the protocol gets a private duplicate of
+// stdout and anything written to fd 1 afterwards lands on stderr instead.
+#[cfg(unix)]
+fn protocol_output() -> BufWriter<Box<dyn Write>> {
+    use std::fs::File;
+    use std::os::unix::io::FromRawFd;
+
+    let fd = unsafe { libc::dup(libc::STDOUT_FILENO) };
+    if fd < 0 || unsafe { libc::dup2(libc::STDERR_FILENO, libc::STDOUT_FILENO) } < 0 {
+        return BufWriter::new(Box::new(io::stdout()));
+    }
+
+    BufWriter::new(Box::new(unsafe { File::from_raw_fd(fd) }))
+}
+
+#[cfg(not(unix))]
+fn protocol_output() -> BufWriter<Box<dyn Write>> {
+    BufWriter::new(Box::new(io::stdout()))
+}
+
+struct Process {
+    child: Child,
+    stdin: BufWriter<ChildStdin>,
+    frames: Receiver<io::Result<Vec<String>>>,
+    // How long the plugin gets to describe itself or answer a call before
+    // the child is considered wedged and restarted
+    timeout: Duration,
+}
+
+impl Process {
+    fn spawn(path: &str, timeout: Duration) -> io::Result<Self> {
+        let mut command = Command::new(std::env::current_exe()?);
+        #[cfg(unix)]
+        std::os::unix::process::CommandExt::arg0(&mut command, HOST_NAME);
+
+        let mut child = command
+            .arg(HOST_ARG)
+            .arg(path)
+            .stdin(Stdio::piped())
+            .stdout(Stdio::piped())
+            .stderr(Stdio::inherit())
+            .spawn()?;
+
+        let stdin = child.stdin.take().ok_or(io::ErrorKind::BrokenPipe)?;
+        let stdout = child.stdout.take().ok_or(io::ErrorKind::BrokenPipe)?;
+
+        // Reads happen on their own thread so a wedged plugin can be timed out
+        let (tx, frames) = channel();
+        thread::spawn(move || {
+            let mut reader = BufReader::new(stdout);
+            loop {
+                let frame = read_frame(&mut reader);
+                let done = frame.is_err();
+                if tx.send(frame).is_err() || done {
+                    break;
+                }
+            }
+        });
+
+        Ok(Self {
+            child,
+            stdin: BufWriter::new(stdin),
+            frames,
+            timeout,
+        })
+    }
+
+    fn receive(&self) -> io::Result<Vec<String>> {
+        match self.frames.recv_timeout(self.timeout) {
+            Ok(frame) => frame,
+            Err(RecvTimeoutError::Timeout) => Err(io::Error::new(
+                io::ErrorKind::TimedOut,
+                format!("no reply within {:?}", self.timeout),
+            )),
+            Err(RecvTimeoutError::Disconnected) => Err(io::ErrorKind::UnexpectedEof.into()),
+        }
+    }
+
+    fn exit_reason(&mut self, e: &io::Error) -> String {
+        // Kill first so a wedged child can be reaped
+        _ = self.child.kill();
+        match self.child.wait() {
+            Ok(status) if e.kind() != io::ErrorKind::TimedOut => status.to_string(),
+            _ => e.to_string(),
+        }
+    }
+}
+
+impl Drop for Process {
+    fn drop(&mut self) {
+        _ = self.child.kill();
+        _ = self.child.wait();
+    }
+}
+
+/// Bot-side handle to a plugin running in `reinze-plugin-host` children. A
+/// child answers one request at a time, so there's one per call the plugin
+/// may run at once, started as they're first needed.
+pub struct IsolatedHost {
+    path: String,
+    timeout: Duration,
+    processes: Vec<Mutex<Option<Process>>>,
+}
+
+impl IsolatedHost {
+    /// Starts the first child for the plugin at `path` and reads its
+    /// description. Children get `timeout`, the plugin's own call timeout, to
+    /// answer anything before they're restarted, and up to `concurrency` of
+    /// them run at once.
+    pub fn start(
+        path: &str,
+        timeout: Duration,
+        concurrency: usize,
+    ) -> Result<(Self, Description), String> {
+        let mut process = Process::spawn(path, timeout).map_err(|e| e.to_string())?;
+
+        let description = match process.receive() {
+            Ok(fields) => parse_description(fields)?,
+            Err(e) => return Err(format!("plugin host exited: {}", process.exit_reason(&e))),
+        };
+
+        let processes = std::iter::once(Some(process))
+            .chain(std::iter::repeat_with(|| None))
+            .take(concurrency.max(1))
+            .map(Mutex::new)
+            .collect();
+
+        let host = Self {
+            path: path.to_string(),
+            timeout,
+            processes,
+        };
+
+        Ok((host, description))
+    }
+
+    /// Sends one call to the child. If the child crashes or stops answering it
+    /// is restarted, and the failing command is reported in the error.
+    pub fn call(
+        &self,
+        cmd: &str,
+        param: &str,
+        caller: &Caller,
+        channel: &str,
+    ) -> Result<Vec<String>, String> {
+        self.request(cmd, &request_call(cmd, param, caller, channel))
+    }
+
+    /// Delivers one event to the child, restarting it like `call` does.
+    pub fn event(&self, event: &Event) -> Result<Vec<String>, String> {
+        self.request(&event.kind, &request_event(event))
+    }
+
+    /// Delivers one listener match to the child, restarting it like `call`
+    /// does.
+    pub fn listen(
+        &self,
+        listener: &str,
+        captures: &[String],
+        caller: &Caller,
+        channel: &str,
+    ) -> Result<Vec<String>, String> {
+        let request = request_listen(listener, captures, caller, channel);
+        self.request(listener, &request)
+    }
+
+    // `what` names the command, event or listener in logs and errors.
+    fn request(&self, what: &str, request: &[String]) -> Result<Vec<String>, String> {
+        // The executor lets no more calls in than there are children, so
+        // one is free unless the host is called from elsewhere
+        let mut guard = match self
+            .processes
+            .iter()
+            .find_map(|process| process.try_lock().ok())
+        {
+            Some(guard) => guard,
+            None => self.processes[0]
+                .lock()
+                .map_err(|_| format!("plugin host for {} is poisoned", self.path))?,
+        };
+
+        if guard.is_none() {
+            *guard = Some(self.respawn()?);
+        }
+
+        let process = match guard.as_mut() {
+            Some(process) => process,
+            None => return Err(format!("plugin host for {} is not running", self.path)),
+        };
+
+        let result = write_frame(&mut process.stdin, request).and_then(|_| process.receive());
+
+        let mut response = match result {
+            Ok(response) => response,
+            Err(e) => {
+                let reason = process.exit_reason(&e);
+                println!(
+                    "Plugin host for {} failed while running '{}': {}. Restarting...",
+                    self.path, what, reason
+                );
+                *guard = self.respawn().ok();
+                return Err(format!("'{}' crashed the plugin ({})", what, reason));
+            }
+        };
+
+        match response.first().map(|status| status.as_str()) {
+            Some("ok") => Ok(response.split_off(1)),
+            Some("err") => Err(response.get(1).cloned().unwrap_or_default()),
+            _ => Err(format!(
+                "malformed reply from plugin host for {}",
+                self.path
+            )),
+        }
+    }
+
+    fn respawn(&self) -> Result<Process, String> {
+        let mut process = Process::spawn(&self.path, self.timeout).map_err(|e| e.to_string())?;
+
+        // The description was read at start; a restarted child only needs to
+        // prove it loaded the plugin again.
+        match process.receive() {
+            Ok(_) => Ok(process),
+            Err(e) => {
+                let reason = process.exit_reason(&e);
+                println!("Error restarting plugin host for {}: {}", self.path, reason);
+                Err(reason)
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Cursor;
+
+    #[test]
+    fn test_frame_roundtrip() {
+        let mut buffer = Vec::new();
+        write_frame(&mut buffer, &["ge", "abyssal whip", "", "#rshelp"]).unwrap();
+
+        let fields = read_frame(&mut Cursor::new(buffer)).unwrap();
+        assert_eq!(fields, vec!["ge", "abyssal whip", "", "#rshelp"]);
+    }
+
+    #[test]
+    fn test_frame_truncated() {
+        let mut buffer = Vec::new();
+        write_frame(&mut buffer, &["ge"]).unwrap();
+        buffer.pop();
+
+        assert!(read_frame(&mut Cursor::new(buffer)).is_err());
+    }
+
+    #[test]
+    fn test_frame_too_large() {
+        let buffer = u32::MAX.to_be_bytes().to_vec();
+        assert!(read_frame(&mut Cursor::new(buffer)).is_err());
+    }
+
+    #[test]
+    fn test_parse_legacy_description() {
+        let fields = vec![
+            "",
+            "",
+            "",
+            "",
+            "^ge$\n^price$",
+            "+ge",
+            "tracksnapshot:21600s",
+            "",
+            "",
+            "0",
+            "",
+            "",
+        ]
+        .into_iter()
+        .map(|s| s.to_string())
+        .collect();
+
+        let description = parse_description(fields).unwrap();
+        assert!(description.manifest.is_none());
+        assert_eq!(description.triggers, vec!["^ge$", "^price$"]);
+        assert_eq!(description.timers[0].interval, Duration::from_secs(21600));
+    }
+
+    #[test]
+    fn test_parse_description_events() {
+        let fields = vec![
+            "greeter",
+            "1.0",
+            "2",
+            "",
+            "",
+            "",
+            "",
+            "JOIN\n001",
+            "",
+            "0",
+            "",
+            "",
+        ]
+        .into_iter()
+        .map(|s| s.to_string())
+        .collect();
+
+        let description = parse_description(fields).unwrap();
+        assert_eq!(description.events, vec!["JOIN", "001"]);
+        assert_eq!(description.manifest.unwrap().events, vec!["JOIN", "001"]);
+    }
+
+    #[test]
+    fn test_parse_description_without_triggers() {
+        let fields = vec![
+            "prices",
+            "1.0",
+            "3",
+            "",
+            "",
+            "",
+            "",
+            "",
+            "price:30s:^price (.+)$",
+            "0",
+            "",
+            "",
+        ]
+        .into_iter()
+        .map(|s| s.to_string())
+        .collect();
+
+        // No empty trigger to match every command
+        let description = parse_description(fields).unwrap();
+        assert!(description.triggers.is_empty());
+        assert!(description.commands.is_empty());
+        assert!(description.manifest.unwrap().triggers.is_empty());
+    }
+
+    #[test]
+    fn test_describe_listeners_roundtrip() {
+        let fields = vec![
+            "prices",
+            "1.0",
+            "3",
+            "",
+            "",
+            "",
+            "",
+            "",
+            "price:30s:^price (.+)$\nurl::(https?://\\S+)",
+            "-5",
+            r#"[{"name": "price"}]"#,
+            "",
+        ]
+        .into_iter()
+        .map(|s| s.to_string())
+        .collect();
+
+        let description = parse_description(fields).unwrap();
+        assert_eq!(description.listeners.len(), 2);
+        assert_eq!(description.listeners[0].cooldown, Duration::from_secs(30));
+        assert_eq!(description.listeners[1].pattern.as_str(), r"(https?://\S+)");
+        assert_eq!(description.priority, -5);
+        assert_eq!(description.command_info[0].name, "price");
+    }
+
+    #[test]
+    fn test_event_request_roundtrip() {
+        let event = Event {
+            kind: "KICK".to_string(),
+            source: "Zezima!zezima@lumbridge.example".to_string(),
+            channel: "#rshelp".to_string(),
+            args: vec!["Durial321".to_string(), "no pking".to_string()],
+            tags: vec![("account".to_string(), "zezima".to_string())],
+        };
+
+        let [_, kind, source, channel, args, tags]: [String; 6] =
+            request_event(&event).try_into().unwrap();
+        assert_eq!(parse_event(kind, source, channel, args, tags), event);
+    }
+
+    #[test]
+    fn test_parse_description_levels() {
+        let fields = vec![
+            "admin",
+            "1.0",
+            "6",
+            "",
+            "^reset$",
+            "",
+            "",
+            "",
+            "",
+            "0",
+            "",
+            "admin:^reset$",
+        ]
+        .into_iter()
+        .map(|s| s.to_string())
+        .collect();
+
+        let description = parse_description(fields).unwrap();
+        assert_eq!(
+            description.levels,
+            vec![("^reset$".to_string(), Level::Admin)]
+        );
+    }
+
+    #[test]
+    fn test_call_request_carries_level() {
+        let caller = Caller {
+            author: "Zezima!zezima@lumbridge.example".to_string(),
+            level: Level::Trusted,
+            tags: vec![("time".to_string(), "2026-01-02T03:04:05.000Z".to_string())],
+        };
+
+        let [_, _, _, author, level, _, tags]: [String; 7] =
+            request_call("ge", "whip", &caller, "#rshelp")
+                .try_into()
+                .unwrap();
+        let parsed = parse_caller(&author, &level, &tags);
+        assert_eq!(parsed.author, caller.author);
+        assert_eq!(parsed.level, Level::Trusted);
+        assert_eq!(parsed.tags, caller.tags);
+    }
+
+    #[test]
+    fn test_parse_description_malformed() {
+        assert!(parse_description(vec!["only".to_string()]).is_err());
+    }
+}
@@ -0,0 +1,210 @@
+use crate::host::HostApi;
+use crate::settings::Settings;
+use crate::timers::parse_duration;
+use common::ColorResult;
+use regex::Regex;
+use std::collections::HashMap;
+use std::os::raw::c_char;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+/// A passive listener: a regex matched against ordinary channel lines, and how
+/// long it stays quiet after firing.
+#[derive(Clone, Debug)]
+pub struct ListenerDef {
+    pub name: String,
+    pub pattern: Regex,
+    pub cooldown: Duration,
+}
+
+/// Parses a single listener declaration like `price:30s:^what's the price of (.+)\?$`.
+/// The cooldown may be left empty, or zero, for none. Everything after the second colon
+/// is the regex, so it may contain colons itself.
+pub fn parse_listener_line(line: &str) -> Result<ListenerDef, String> {
+    let mut parts = line.splitn(3, ':');
+    let (name, cooldown, pattern) = match (parts.next(), parts.next(), parts.next()) {
+        (Some(name), Some(cooldown), Some(pattern)) => (name.trim(), cooldown.trim(), pattern),
+        _ => return Err(format!("malformed listener '{}'", line)),
+    };
+
+    if name.is_empty() {
+        return Err(format!("listener '{}' has no name", line));
+    }
+
+    let cooldown = if cooldown.is_empty() {
+        Duration::ZERO
+    } else {
+        parse_duration(cooldown)
+            .ok_or_else(|| format!("listener {} has an invalid cooldown '{}'", name, cooldown))?
+    };
+
+    let pattern = Regex::new(pattern)
+        .map_err(|e| format!("listener {} has an invalid regex: {}", name, e))?;
+
+    Ok(ListenerDef {
+        name: name.to_string(),
+        pattern,
+        cooldown,
+    })
+}
+
+/// Parses newline-separated listener declarations. Unlike timers, one bad
+/// declaration rejects them all, since a listener that silently never fires
+/// is hard to notice.
+pub fn parse_listener_declarations(output: &str) -> Result<Vec<ListenerDef>, String> {
+    output
+        .lines()
+        .filter(|line| !line.trim().is_empty())
+        .map(parse_listener_line)
+        .collect()
+}
+
+/// The capture groups of a match, starting with the whole match. Groups that
+/// didn't participate are empty.
+pub fn captures(listener: &ListenerDef, line: &str) -> Option<Vec<String>> {
+    let captures = listener.pattern.captures(line)?;
+
+    Some(
+        captures
+            .iter()
+            .map(|group| group.map(|m| m.as_str().to_string()).unwrap_or_default())
+            .collect(),
+    )
+}
+
+/// Tracks when each listener last fired in each channel, and which channels
+/// it's enabled in.
+pub struct Listeners {
+    settings: Arc<Settings>,
+    fired: Mutex<HashMap<(String, String, String), Instant>>,
+}
+
+impl Listeners {
+    pub fn new(settings: Arc<Settings>) -> Self {
+        Self {
+            settings,
+            fired: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// Whether `listener` may fire in `channel` right now. If it may, the
+    /// firing is recorded and the cooldown starts.
+    pub fn try_fire(&self, plugin: &str, listener: &ListenerDef, channel: &str) -> bool {
+        let settings = self.settings.plugin(plugin).listener(&listener.name);
+        if !settings.enabled_in(channel) {
+            return false;
+        }
+
+        let cooldown = settings.cooldown().unwrap_or(listener.cooldown);
+        let key = (
+            plugin.to_string(),
+            listener.name.clone(),
+            channel.to_lowercase(),
+        );
+
+        let mut fired = match self.fired.lock() {
+            Ok(fired) => fired,
+            Err(_) => return false,
+        };
+
+        let now = Instant::now();
+        match fired.get(&key) {
+            Some(last) if now.duration_since(*last) < cooldown => false,
+            _ => {
+                fired.insert(key, now);
+                true
+            }
+        }
+    }
+
+    /// Drops the cooldowns of an unloaded plugin's listeners.
+    pub fn forget(&self, plugin: &str) {
+        if let Ok(mut fired) = self.fired.lock() {
+            fired.retain(|(name, _, _), _| name != plugin);
+        }
+    }
+}
+
+/// The context passed to a plugin's `plugin_listen` entry point. `captures` is
+/// newline-separated, starting with the whole match, and `host` is null when
+/// the plugin has no host callbacks.
+#[repr(C)]
+pub struct ListenerContext {
+    pub listener: *const c_char,
+    pub captures: *const c_char,
+    pub author: *const c_char,
+    pub color: extern "C" fn(*const c_char, *const c_char) -> ColorResult,
+    pub channel: *const c_char,
+    pub host: *const HostApi,
+    /// The caller's permission level; see `Level`. Added in ABI v6.
+    pub level: u32,
+    /// The message's IRCv3 tags, as in `PluginContextV2`. Added in ABI v7.
+    pub tags: *const c_char,
+}
+
+pub type ListenFn = extern "C" fn(context: &ListenerContext) -> *mut c_char;
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_listener_line() {
+        let listener = parse_listener_line(r"price:30s:^what's the price of (.+)\?$").unwrap();
+        assert_eq!(listener.name, "price");
+        assert_eq!(listener.cooldown, Duration::from_secs(30));
+        assert_eq!(
+            captures(&listener, "what's the price of abyssal whip?").unwrap(),
+            vec!["what's the price of abyssal whip?", "abyssal whip"]
+        );
+        assert!(captures(&listener, "hello").is_none());
+    }
+
+    #[test]
+    fn test_parse_listener_line_colons_and_no_cooldown() {
+        let listener = parse_listener_line(r"url::(https?://\S+)").unwrap();
+        assert_eq!(listener.cooldown, Duration::ZERO);
+        assert_eq!(
+            captures(&listener, "see https://example.com").unwrap()[1],
+            "https://example.com"
+        );
+    }
+
+    #[test]
+    fn test_parse_listener_line_zero_cooldown() {
+        let listener = parse_listener_line(r"url:0s:(https?://\S+)").unwrap();
+        assert_eq!(listener.cooldown, Duration::ZERO);
+    }
+
+    #[test]
+    fn test_parse_listener_invalid() {
+        assert!(parse_listener_line("price").is_err());
+        assert!(parse_listener_line(":30s:x").is_err());
+        assert!(parse_listener_line("price:soon:x").is_err());
+        assert!(parse_listener_line("price:30s:(").is_err());
+        assert!(parse_listener_declarations("url::x\nprice:30s:(").is_err());
+    }
+
+    #[test]
+    fn test_try_fire() {
+        let settings = Settings::parse(
+            r##"
+            [plugins.libprices.listeners.price]
+            channels = ["#rshelp"]
+            "##,
+        )
+        .unwrap();
+        let listeners = Listeners::new(Arc::new(settings));
+        let price = parse_listener_line("price:1h:price").unwrap();
+
+        assert!(listeners.try_fire("plugins/libprices.so", &price, "#RSHelp"));
+        // Cooling down in this channel
+        assert!(!listeners.try_fire("plugins/libprices.so", &price, "#rshelp"));
+        // Not enabled here
+        assert!(!listeners.try_fire("plugins/libprices.so", &price, "#other"));
+        assert!(!listeners.try_fire("plugins/libother.so", &price, "#rshelp"));
+
+        listeners.forget("plugins/libprices.so");
+        assert!(listeners.try_fire("plugins/libprices.so", &price, "#rshelp"));
+    }
+}
@@ -1,6 +1,28 @@
+mod admin;
 mod application;
+mod channels;
+mod dispatch;
+mod events;
+mod executor;
+mod help;
+mod host;
+mod ignores;
+mod ircv3;
+mod isolation;
+mod listeners;
+mod manifest;
+mod outbound;
+mod pager;
+mod permissions;
 mod plugins;
+mod ratelimit;
+mod reconnect;
+mod response;
+mod services;
+mod settings;
+mod split;
 mod timers;
+mod watchdog;
 
 extern crate chrono;
 extern crate common;
@@ -16,6 +38,14 @@ use tokio::task::JoinHandle;
 #[tokio::main(flavor = "multi_thread")]
 async fn main() {
     env_logger::init();
+
+    // Re-executed as a child by `isolation::IsolatedHost` to run one plugin
+    let args = std::env::args().collect::<Vec<String>>();
+    if args.len() == 3 && args[1] == isolation::HOST_ARG {
+        isolation::serve(&args[2]);
+        return;
+    }
+
     let mut threads = vec![];
     init();
 
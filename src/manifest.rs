@@ -0,0 +1,412 @@
+use crate::events;
+use crate::help::{CommandInfo, parse_command_info};
+use crate::listeners::{ListenerDef, parse_listener_declarations};
+use crate::permissions::{Level, parse_level_declarations};
+use crate::timers::{TimerDef, parse_timer_declarations};
+use std::ffi::CStr;
+use std::os::raw::c_char;
+
+/// The newest plugin ABI version this host understands. Bump whenever
+/// `RawManifest`, `PluginContext` or the calling convention of `exported`
+/// changes shape.
+pub const ABI_VERSION: u32 = 9;
+
+/// The oldest plugin ABI version this host still loads.
+pub const MIN_ABI_VERSION: u32 = 1;
+
+/// The ABI version from which plugins must export `plugin_free`. Older
+/// plugins, and ones only speaking the probe protocol, may leave it out and
+/// have their output copied and leaked instead.
+pub const FREE_ABI_VERSION: u32 = 5;
+
+/// Host features a plugin may list as required in its manifest.
+pub const HOST_FEATURES: &[&str] = &["color", "host-api", "timers", STRUCTURED_OUTPUT];
+
+/// The feature a plugin lists to have its output lines read as JSON items
+/// (see `response::Item`). Other plugins' output is always plain text.
+pub const STRUCTURED_OUTPUT: &str = "structured-output";
+
+/// The C layout a plugin returns from its `plugin_manifest` symbol.
+///
+/// `abi_version` must stay the first field: the host reads it before touching
+/// anything else, so a plugin built against a different layout is rejected
+/// without interpreting the rest of the struct. List fields are
+/// newline-separated, matching the legacy probe output. Null pointers are
+/// treated as empty strings. The memory is owned by the plugin and must stay
+/// valid for as long as the library is loaded.
+///
+/// Fields are only ever appended, and fields newer than the plugin's
+/// `abi_version` are never read, so older plugins can declare a shorter struct.
+#[repr(C)]
+pub struct RawManifest {
+    pub abi_version: u32,
+    pub name: *const c_char,
+    pub version: *const c_char,
+    pub triggers: *const c_char,
+    pub commands: *const c_char,
+    pub timers: *const c_char,
+    pub features: *const c_char,
+    /// IRC events delivered to the plugin's `plugin_event` symbol, e.g. `JOIN`
+    /// or a numeric like `001`. Added in ABI v2.
+    pub events: *const c_char,
+    /// Passive listeners delivered to the plugin's `plugin_listen` symbol, as
+    /// `name:cooldown:regex`. Added in ABI v3.
+    pub listeners: *const c_char,
+    /// Which plugin wins when triggers overlap: higher runs first. Config can
+    /// override it. Added in ABI v4.
+    pub priority: i32,
+    /// A JSON array of per-command details for `+help`; see `CommandInfo`.
+    /// Added in ABI v5.
+    pub command_info: *const c_char,
+    /// The minimum permission level for some of the triggers, one
+    /// `level:trigger` per line, e.g. `admin:^reset$`. Triggers not listed
+    /// are open to users. Added in ABI v6.
+    pub levels: *const c_char,
+}
+
+pub type ManifestFn = extern "C" fn() -> *const RawManifest;
+
+/// Host-owned copy of a plugin's manifest.
+#[derive(Clone, Debug)]
+pub struct Manifest {
+    pub name: String,
+    pub version: String,
+    pub abi_version: u32,
+    pub triggers: Vec<String>,
+    pub commands: Vec<String>,
+    pub timers: Vec<TimerDef>,
+    pub features: Vec<String>,
+    pub events: Vec<String>,
+    pub listeners: Vec<ListenerDef>,
+    pub priority: i32,
+    pub command_info: Vec<CommandInfo>,
+    pub levels: Vec<(String, Level)>,
+}
+
+impl Manifest {
+    /// Copies a manifest out of plugin memory, rejecting incompatible ABIs
+    /// before reading any field other than `abi_version`.
+    ///
+    /// # Safety
+    /// `raw` must be null or point to a `RawManifest` whose string fields are
+    /// null or valid NUL-terminated strings.
+    pub unsafe fn from_raw(raw: *const RawManifest) -> Result<Self, String> {
+        if raw.is_null() {
+            return Err("plugin_manifest returned a null pointer".to_string());
+        }
+
+        let abi_version = unsafe { (*raw).abi_version };
+        check_abi(abi_version)?;
+
+        // Fields are read through the pointer one at a time rather than via a
+        // reference, since an older plugin's struct stops short of the newer
+        // fields.
+        let events = if abi_version >= 2 {
+            split_lines(&unsafe { read_string((*raw).events) }?)
+        } else {
+            vec![]
+        };
+        let listeners = if abi_version >= 3 {
+            parse_listener_declarations(&unsafe { read_string((*raw).listeners) }?)?
+        } else {
+            vec![]
+        };
+        let priority = if abi_version >= 4 {
+            unsafe { (*raw).priority }
+        } else {
+            0
+        };
+        let command_info = if abi_version >= 5 {
+            parse_command_info(&unsafe { read_string((*raw).command_info) }?)?
+        } else {
+            vec![]
+        };
+        let levels = if abi_version >= 6 {
+            parse_level_declarations(&unsafe { read_string((*raw).levels) }?)?
+        } else {
+            vec![]
+        };
+
+        let manifest = Self {
+            name: unsafe { read_string((*raw).name) }?,
+            version: unsafe { read_string((*raw).version) }?,
+            abi_version,
+            triggers: split_lines(&unsafe { read_string((*raw).triggers) }?),
+            commands: split_lines(&unsafe { read_string((*raw).commands) }?),
+            timers: parse_timer_declarations(&unsafe { read_string((*raw).timers) }?),
+            features: split_lines(&unsafe { read_string((*raw).features) }?),
+            events: events
+                .into_iter()
+                .map(|event| event.to_uppercase())
+                .collect(),
+            listeners,
+            priority,
+            command_info,
+            levels,
+        };
+
+        manifest.validate()?;
+
+        Ok(manifest)
+    }
+
+    /// Checks the fields a host needs before activating the plugin.
+    pub fn validate(&self) -> Result<(), String> {
+        check_abi(self.abi_version)?;
+
+        if self.name.trim().is_empty() {
+            return Err("manifest has an empty name".to_string());
+        }
+
+        let missing = self
+            .features
+            .iter()
+            .filter(|feature| !HOST_FEATURES.contains(&feature.as_str()))
+            .cloned()
+            .collect::<Vec<String>>();
+
+        if !missing.is_empty() {
+            return Err(format!(
+                "requires unsupported host features: {}",
+                missing.join(", ")
+            ));
+        }
+
+        let unknown = self
+            .events
+            .iter()
+            .filter(|event| !events::is_known(event))
+            .cloned()
+            .collect::<Vec<String>>();
+
+        if !unknown.is_empty() {
+            return Err(format!(
+                "subscribes to unknown events: {}",
+                unknown.join(", ")
+            ));
+        }
+
+        let undeclared = self
+            .levels
+            .iter()
+            .filter(|(trigger, _)| !self.triggers.contains(trigger))
+            .map(|(trigger, _)| trigger.clone())
+            .collect::<Vec<String>>();
+
+        if !undeclared.is_empty() {
+            return Err(format!(
+                "sets levels for undeclared triggers: {}",
+                undeclared.join(", ")
+            ));
+        }
+
+        Ok(())
+    }
+}
+
+fn check_abi(abi_version: u32) -> Result<(), String> {
+    if !(MIN_ABI_VERSION..=ABI_VERSION).contains(&abi_version) {
+        return Err(format!(
+            "built against plugin ABI v{} but this host supports v{} to v{}",
+            abi_version, MIN_ABI_VERSION, ABI_VERSION
+        ));
+    }
+
+    Ok(())
+}
+
+unsafe fn read_string(ptr: *const c_char) -> Result<String, String> {
+    if ptr.is_null() {
+        return Ok(String::new());
+    }
+
+    match unsafe { CStr::from_ptr(ptr) }.to_str() {
+        Ok(s) => Ok(s.to_string()),
+        Err(e) => Err(format!("manifest contains invalid UTF-8: {}", e)),
+    }
+}
+
+/// Splits a newline-separated list, dropping blank lines, so an empty list
+/// is empty rather than one empty entry.
+pub(crate) fn split_lines(input: &str) -> Vec<String> {
+    input
+        .lines()
+        .map(|line| line.trim())
+        .filter(|line| !line.is_empty())
+        .map(|line| line.to_string())
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::ffi::CString;
+    use std::ptr;
+
+    fn manifest(abi_version: u32, features: Vec<&str>) -> Manifest {
+        Manifest {
+            name: "runescape".to_string(),
+            version: "1.0.0".to_string(),
+            abi_version,
+            triggers: vec!["^ge$".to_string()],
+            commands: vec!["+ge".to_string()],
+            timers: vec![],
+            features: features.iter().map(|f| f.to_string()).collect(),
+            events: vec![],
+            listeners: vec![],
+            priority: 0,
+            command_info: vec![],
+            levels: vec![],
+        }
+    }
+
+    #[test]
+    fn test_validate_ok() {
+        assert!(manifest(ABI_VERSION, vec!["timers"]).validate().is_ok());
+    }
+
+    #[test]
+    fn test_validate_wrong_abi() {
+        let err = manifest(ABI_VERSION + 1, vec![]).validate().unwrap_err();
+        assert!(err.contains("ABI"));
+    }
+
+    #[test]
+    fn test_validate_unsupported_feature() {
+        let err = manifest(ABI_VERSION, vec!["timers", "teleport"])
+            .validate()
+            .unwrap_err();
+        assert!(err.contains("teleport"));
+        assert!(!err.contains("timers"));
+    }
+
+    #[test]
+    fn test_validate_unknown_event() {
+        let mut manifest = manifest(ABI_VERSION, vec![]);
+        manifest.events = vec!["JOIN".to_string(), "SHOUT".to_string()];
+        let err = manifest.validate().unwrap_err();
+        assert!(err.contains("SHOUT"));
+        assert!(!err.contains("JOIN"));
+    }
+
+    #[test]
+    fn test_validate_level_for_undeclared_trigger() {
+        let mut manifest = manifest(ABI_VERSION, vec![]);
+        manifest.levels = vec![
+            ("^ge$".to_string(), Level::Trusted),
+            ("^reset$".to_string(), Level::Admin),
+        ];
+        let err = manifest.validate().unwrap_err();
+        assert!(err.contains("^reset$"));
+        assert!(!err.contains("^ge$"));
+    }
+
+    #[test]
+    fn test_from_raw() {
+        let name = CString::new("runescape").unwrap();
+        let version = CString::new("1.0.0").unwrap();
+        let triggers = CString::new("^ge$\n^price$\n").unwrap();
+        let timers = CString::new("tracksnapshot:6h").unwrap();
+        let events = CString::new("join\n001").unwrap();
+        let listeners = CString::new(r"price:30s:^what's the price of (.+)\?$").unwrap();
+        let command_info = CString::new(r#"[{"name": "ge", "usage": "+ge <item>"}]"#).unwrap();
+        let levels = CString::new("trusted:^price$").unwrap();
+        let raw = RawManifest {
+            abi_version: ABI_VERSION,
+            name: name.as_ptr(),
+            version: version.as_ptr(),
+            triggers: triggers.as_ptr(),
+            commands: ptr::null(),
+            timers: timers.as_ptr(),
+            features: ptr::null(),
+            events: events.as_ptr(),
+            listeners: listeners.as_ptr(),
+            priority: 10,
+            command_info: command_info.as_ptr(),
+            levels: levels.as_ptr(),
+        };
+
+        let parsed = unsafe { Manifest::from_raw(&raw) }.unwrap();
+        assert_eq!(parsed.name, "runescape");
+        assert_eq!(parsed.triggers, vec!["^ge$", "^price$"]);
+        assert!(parsed.commands.is_empty());
+        assert_eq!(parsed.timers.len(), 1);
+        assert_eq!(parsed.events, vec!["JOIN", "001"]);
+        assert_eq!(parsed.listeners[0].name, "price");
+        assert_eq!(parsed.priority, 10);
+        assert_eq!(parsed.command_info[0].usage, "+ge <item>");
+        assert_eq!(parsed.levels, vec![("^price$".to_string(), Level::Trusted)]);
+    }
+
+    #[test]
+    fn test_from_raw_invalid_listener() {
+        let name = CString::new("runescape").unwrap();
+        let listeners = CString::new("price:30s:(").unwrap();
+        let raw = RawManifest {
+            abi_version: ABI_VERSION,
+            name: name.as_ptr(),
+            version: ptr::null(),
+            triggers: ptr::null(),
+            commands: ptr::null(),
+            timers: ptr::null(),
+            features: ptr::null(),
+            events: ptr::null(),
+            listeners: listeners.as_ptr(),
+            priority: 0,
+            command_info: ptr::null(),
+            levels: ptr::null(),
+        };
+
+        let err = unsafe { Manifest::from_raw(&raw) }.unwrap_err();
+        assert!(err.contains("price"));
+    }
+
+    #[test]
+    fn test_from_raw_v1_ignores_events() {
+        let name = CString::new("runescape").unwrap();
+        let raw = RawManifest {
+            abi_version: 1,
+            name: name.as_ptr(),
+            version: ptr::null(),
+            triggers: ptr::null(),
+            commands: ptr::null(),
+            timers: ptr::null(),
+            features: ptr::null(),
+            // Past the end of a v1 struct, so it must not be read
+            events: ptr::dangling(),
+            listeners: ptr::dangling(),
+            priority: 0,
+            command_info: ptr::null(),
+            levels: ptr::null(),
+        };
+
+        let parsed = unsafe { Manifest::from_raw(&raw) }.unwrap();
+        assert!(parsed.events.is_empty());
+    }
+
+    #[test]
+    fn test_from_raw_rejects_abi_before_reading_fields() {
+        // Dangling string pointers must never be touched for a foreign ABI.
+        let raw = RawManifest {
+            abi_version: 0,
+            name: ptr::dangling(),
+            version: ptr::dangling(),
+            triggers: ptr::dangling(),
+            commands: ptr::dangling(),
+            timers: ptr::dangling(),
+            features: ptr::dangling(),
+            events: ptr::dangling(),
+            listeners: ptr::dangling(),
+            priority: 0,
+            command_info: ptr::null(),
+            levels: ptr::null(),
+        };
+
+        assert!(unsafe { Manifest::from_raw(&raw) }.is_err());
+    }
+
+    #[test]
+    fn test_from_raw_null() {
+        assert!(unsafe { Manifest::from_raw(ptr::null()) }.is_err());
+    }
+}
@@ -0,0 +1,557 @@
+use crate::permissions::Level;
+use crate::settings::{FloodSettings, ServicesSettings};
+use crate::split::{self, HOSTLEN, USERLEN};
+use irc::client::prelude::{Client, Command, Message, Prefix, Response};
+use std::collections::VecDeque;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+use tokio::sync::Notify;
+
+/// How urgent an outbound line is. Everything queued in a higher class is
+/// sent before anything in a lower one.
+#[derive(Clone, Copy, Debug, Eq, Ord, PartialEq, PartialOrd)]
+pub enum Priority {
+    /// Plugin output.
+    Bulk,
+    /// Replies from the bot's own commands, like `+help` and `+plugins`.
+    Reply,
+    /// Replies to admins and owners, so they aren't stuck behind a queue of
+    /// replies to everyone else while sorting things out.
+    Staff,
+    /// Protocol and services traffic.
+    Urgent,
+}
+
+impl Priority {
+    pub const ALL: [Priority; 4] = [
+        Priority::Urgent,
+        Priority::Staff,
+        Priority::Reply,
+        Priority::Bulk,
+    ];
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            Self::Bulk => "bulk",
+            Self::Reply => "reply",
+            Self::Staff => "staff",
+            Self::Urgent => "urgent",
+        }
+    }
+}
+
+// The lines waiting for one target in one class
+struct Target {
+    name: String,
+    lines: VecDeque<Message>,
+    // Lines turned away since the queue was last full
+    dropped: usize,
+}
+
+/// Lines waiting to be sent, by class and then by target. Targets within a
+/// class take turns a line at a time, so one channel's long reply doesn't hold
+/// up another's.
+#[derive(Default)]
+struct Queues {
+    // Indexed by `Priority as usize`
+    classes: [VecDeque<Target>; 4],
+}
+
+impl Queues {
+    // Queues a line unless its target already has `cap` waiting in the class.
+    // Returns whether this was the first line turned away.
+    fn push(&mut self, priority: Priority, message: Message, cap: usize) -> bool {
+        let name = target(&message);
+        let class = &mut self.classes[priority as usize];
+
+        let queue = match class.iter().position(|queued| queued.name == name) {
+            Some(index) => &mut class[index],
+            None => {
+                class.push_back(Target {
+                    name,
+                    lines: VecDeque::new(),
+                    dropped: 0,
+                });
+                class.back_mut().unwrap()
+            }
+        };
+
+        if queue.lines.len() >= cap {
+            queue.dropped += 1;
+            return queue.dropped == 1;
+        }
+
+        queue.lines.push_back(message);
+        false
+    }
+
+    // The next line to send: the highest class with anything queued, and
+    // within it the target whose turn it is. A target that turned lines away
+    // is told how many once the rest of its lines are out.
+    fn pop(&mut self) -> Option<Message> {
+        for priority in Priority::ALL {
+            let class = &mut self.classes[priority as usize];
+            let mut queue = match class.pop_front() {
+                Some(queue) => queue,
+                None => continue,
+            };
+
+            let message = match queue.lines.pop_front() {
+                Some(message) => message,
+                None => continue,
+            };
+
+            if queue.lines.is_empty() && queue.dropped > 0 {
+                queue.lines.extend(summary(&message, queue.dropped));
+                queue.dropped = 0;
+            }
+            if !queue.lines.is_empty() {
+                class.push_back(queue);
+            }
+
+            return Some(message);
+        }
+
+        None
+    }
+
+    fn depth(&self) -> Vec<(Priority, String, usize)> {
+        Priority::ALL
+            .into_iter()
+            .flat_map(|priority| {
+                self.classes[priority as usize]
+                    .iter()
+                    .map(move |queue| (priority, queue.name.clone(), queue.lines.len()))
+            })
+            .collect()
+    }
+
+    fn is_empty(&self) -> bool {
+        self.classes.iter().all(VecDeque::is_empty)
+    }
+
+    fn clear(&mut self) {
+        self.classes.iter_mut().for_each(VecDeque::clear);
+    }
+}
+
+struct State {
+    queues: Queues,
+    // Flood control: lines that may go out right now
+    tokens: f64,
+    updated: Instant,
+    closed: bool,
+}
+
+enum Next {
+    Send(Box<Message>),
+    Wait(Duration),
+    Idle,
+    Closed,
+}
+
+/// Everything the bot says on one connection goes through here, paced so the
+/// server never sees more than `burst` lines at once or, after that, one line
+/// per `interval`.
+pub struct Outbound {
+    client: Arc<Client>,
+    burst: u32,
+    interval: Duration,
+    max_queued: usize,
+    state: Mutex<State>,
+    ready: Notify,
+    // Our own `user@host` as the server shows it, once we've seen it
+    userhost: Mutex<Option<String>>,
+    // Our nick as the server last told us. The client only knows the nick
+    // it registered with, and services may move us off it.
+    nickname: Mutex<Option<String>>,
+    // Services nicks, lowercased, whose lines go as `Urgent`
+    services: Vec<String>,
+}
+
+impl Outbound {
+    pub fn new(client: Arc<Client>, settings: &FloodSettings, services: &ServicesSettings) -> Self {
+        let burst = settings.burst();
+
+        Self {
+            client,
+            services: services_nicks(services),
+            burst,
+            interval: settings.interval(),
+            max_queued: settings.max_queued(),
+            state: Mutex::new(State {
+                queues: Queues::default(),
+                tokens: burst as f64,
+                updated: Instant::now(),
+                closed: false,
+            }),
+            ready: Notify::new(),
+            userhost: Mutex::new(None),
+            nickname: Mutex::new(None),
+        }
+    }
+
+    /// Queues a line for a caller at `level`. Replies to admins and owners
+    /// go as `Staff`, and protocol and services lines always go as `Urgent`.
+    /// Returns `false` once the connection is closed.
+    pub fn send(&self, priority: Priority, level: Level, message: Message) -> bool {
+        let priority = class(priority, level, &message, &self.services);
+
+        let mut state = match self.state.lock() {
+            Ok(state) => state,
+            Err(_) => return false,
+        };
+        if state.closed {
+            return false;
+        }
+
+        let target = target(&message);
+        if state.queues.push(priority, message, self.max_queued) {
+            println!(
+                "Outbound queue for '{}' is full, dropping {} lines",
+                target,
+                priority.name()
+            );
+        }
+
+        self.ready.notify_one();
+        true
+    }
+
+    /// Writes a line straight to the connection, ahead of everything queued
+    /// and without waiting on flood control, for the watchdog's PINGs, whose
+    /// round trip shouldn't include time spent in the queue.
+    pub fn send_now(&self, message: Message) -> bool {
+        if self.state.lock().map_or(true, |state| state.closed) {
+            return false;
+        }
+
+        match self.client.send(message) {
+            Ok(()) => true,
+            Err(e) => {
+                println!("Error sending message: {}", e);
+                false
+            }
+        }
+    }
+
+    /// Our current nick, or the configured one until the server says.
+    pub fn nickname(&self) -> String {
+        self.nickname
+            .lock()
+            .ok()
+            .and_then(|nickname| nickname.clone())
+            .unwrap_or_else(|| self.client.current_nickname().to_string())
+    }
+
+    /// Learns our own nick and `user@host` from the welcome, or from
+    /// anything the server echoes back with our prefix, like our JOINs.
+    pub fn observe(&self, message: &Message) {
+        let nick = &self.nickname();
+
+        let renamed = match (&message.prefix, &message.command) {
+            (_, Command::Response(Response::RPL_WELCOME, args)) => args.first().cloned(),
+            (Some(Prefix::Nickname(from, _, _)), Command::NICK(to))
+                if from.eq_ignore_ascii_case(nick) =>
+            {
+                Some(to.clone())
+            }
+            _ => None,
+        };
+
+        if let Some(renamed) = renamed
+            && let Ok(mut nickname) = self.nickname.lock()
+        {
+            *nickname = Some(renamed);
+        }
+
+        let userhost = match (&message.prefix, &message.command) {
+            (_, Command::Response(Response::RPL_WELCOME, args)) => args
+                .last()
+                .and_then(|text| text.split_whitespace().last())
+                .and_then(|mask| mask.split_once('!'))
+                .map(|(_, userhost)| userhost.to_string()),
+            (Some(Prefix::Nickname(from, user, host)), command)
+                if from.eq_ignore_ascii_case(nick)
+                    || matches!(command, Command::NICK(to) if to.eq_ignore_ascii_case(nick)) =>
+            {
+                Some(format!("{}@{}", user, host))
+            }
+            _ => None,
+        };
+
+        if let Some(userhost) = userhost.filter(|userhost| {
+            userhost.contains('@') && !userhost.starts_with('@') && !userhost.ends_with('@')
+        }) && let Ok(mut known) = self.userhost.lock()
+        {
+            *known = Some(userhost);
+        }
+    }
+
+    /// How many bytes of text fit in `command`, built with empty text, once
+    /// the server adds our prefix. Until we know our `user@host`, assume the
+    /// longest a server allows.
+    pub fn budget(&self, command: &Command) -> usize {
+        let userhost = self
+            .userhost
+            .lock()
+            .ok()
+            .and_then(|userhost| userhost.as_ref().map(String::len))
+            .unwrap_or(USERLEN + 1 + HOSTLEN);
+
+        let prefix = self.nickname().len() + 1 + userhost;
+        split::budget(prefix, command)
+    }
+
+    /// How many lines are waiting, by class and target.
+    pub fn depth(&self) -> Vec<(Priority, String, usize)> {
+        self.state
+            .lock()
+            .map(|state| state.queues.depth())
+            .unwrap_or_default()
+    }
+
+    /// Drops whatever is still queued and stops `run`.
+    pub fn close(&self) {
+        if let Ok(mut state) = self.state.lock() {
+            state.closed = true;
+            state.queues.clear();
+        }
+        self.ready.notify_one();
+    }
+
+    /// Sends queued lines as flood control allows, until `close`.
+    pub async fn run(self: Arc<Self>) {
+        loop {
+            match self.next() {
+                Next::Send(message) => {
+                    if let Err(e) = self.client.send(*message) {
+                        println!("Error sending message: {}", e);
+                    }
+                }
+                Next::Wait(wait) => tokio::time::sleep(wait).await,
+                Next::Idle => self.ready.notified().await,
+                Next::Closed => return,
+            }
+        }
+    }
+
+    fn next(&self) -> Next {
+        let mut state = match self.state.lock() {
+            Ok(state) => state,
+            Err(_) => return Next::Closed,
+        };
+        if state.closed {
+            return Next::Closed;
+        }
+
+        let now = Instant::now();
+        let refilled = now.saturating_duration_since(state.updated).as_secs_f64()
+            / self.interval.as_secs_f64().max(f64::EPSILON);
+        state.tokens = (state.tokens + refilled).min(self.burst as f64);
+        state.updated = now;
+
+        if state.tokens < 1.0 {
+            if state.queues.is_empty() {
+                return Next::Idle;
+            }
+            return Next::Wait(self.interval.mul_f64(1.0 - state.tokens));
+        }
+
+        match state.queues.pop() {
+            Some(message) => {
+                state.tokens -= 1.0;
+                Next::Send(Box::new(message))
+            }
+            None => Next::Idle,
+        }
+    }
+}
+
+// Who a line is for, or empty for lines to the server itself
+fn target(message: &Message) -> String {
+    match &message.command {
+        Command::PRIVMSG(target, _) | Command::NOTICE(target, _) => target.to_lowercase(),
+        _ => String::new(),
+    }
+}
+
+// The class a line is queued in
+fn class(priority: Priority, level: Level, message: &Message, services: &[String]) -> Priority {
+    let priority = match priority {
+        Priority::Reply if level >= Level::Admin => Priority::Staff,
+        priority => priority,
+    };
+    priority.max(urgency(message, services))
+}
+
+// The configured NickServ and the usual ChanServ, lowercased
+fn services_nicks(settings: &ServicesSettings) -> Vec<String> {
+    let mut nicks = vec![settings.nickserv().to_lowercase(), "chanserv".to_string()];
+    nicks.dedup();
+    nicks
+}
+
+// Registration, keepalive and services lines can't wait behind chatter
+fn urgency(message: &Message, services: &[String]) -> Priority {
+    match &message.command {
+        Command::PING(..)
+        | Command::PONG(..)
+        | Command::NICK(..)
+        | Command::CAP(..)
+        | Command::AUTHENTICATE(..)
+        | Command::QUIT(..) => Priority::Urgent,
+        Command::PRIVMSG(target, _) | Command::NOTICE(target, _)
+            if services.contains(&target.to_lowercase()) =>
+        {
+            Priority::Urgent
+        }
+        _ => Priority::Bulk,
+    }
+}
+
+// Tells a target how many lines it missed, the same way it got the last one.
+// The server itself has no one to tell.
+fn summary(last: &Message, dropped: usize) -> Option<Message> {
+    let text = format!("({} more lines dropped)", dropped);
+    match &last.command {
+        Command::NOTICE(target, _) => Some(Command::NOTICE(target.clone(), text).into()),
+        Command::PRIVMSG(target, _) => Some(Command::PRIVMSG(target.clone(), text).into()),
+        _ => None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn privmsg(target: &str, text: &str) -> Message {
+        Command::PRIVMSG(target.to_string(), text.to_string()).into()
+    }
+
+    fn text(message: Option<Message>) -> String {
+        match message.map(|message| message.command) {
+            Some(Command::PRIVMSG(target, text)) => format!("{} {}", target, text),
+            other => format!("{:?}", other),
+        }
+    }
+
+    #[test]
+    fn test_priorities_go_first() {
+        let mut queues = Queues::default();
+        queues.push(Priority::Bulk, privmsg("#rshelp", "bulk"), 10);
+        queues.push(Priority::Reply, privmsg("#rshelp", "reply"), 10);
+
+        assert_eq!(text(queues.pop()), "#rshelp reply");
+        assert_eq!(text(queues.pop()), "#rshelp bulk");
+        assert!(queues.pop().is_none());
+    }
+
+    #[test]
+    fn test_staff_replies_overtake() {
+        let services = services_nicks(&ServicesSettings::default());
+        let mut queues = Queues::default();
+        for (level, text) in [(Level::User, "user"), (Level::Admin, "admin")] {
+            let message = privmsg("#rshelp", text);
+            let priority = class(Priority::Reply, level, &message, &services);
+            queues.push(priority, message, 10);
+        }
+
+        assert_eq!(text(queues.pop()), "#rshelp admin");
+        assert_eq!(text(queues.pop()), "#rshelp user");
+        assert_eq!(
+            class(
+                Priority::Bulk,
+                Level::Owner,
+                &privmsg("#rshelp", "plugin"),
+                &services
+            ),
+            Priority::Bulk
+        );
+    }
+
+    #[test]
+    fn test_targets_take_turns() {
+        let mut queues = Queues::default();
+        queues.push(Priority::Bulk, privmsg("#a", "1"), 10);
+        queues.push(Priority::Bulk, privmsg("#a", "2"), 10);
+        queues.push(Priority::Bulk, privmsg("#a", "3"), 10);
+        queues.push(Priority::Bulk, privmsg("#b", "1"), 10);
+
+        assert_eq!(text(queues.pop()), "#a 1");
+        assert_eq!(text(queues.pop()), "#b 1");
+        assert_eq!(text(queues.pop()), "#a 2");
+        assert_eq!(text(queues.pop()), "#a 3");
+    }
+
+    #[test]
+    fn test_cap_summarizes_dropped_lines() {
+        let mut queues = Queues::default();
+        assert!(!queues.push(Priority::Bulk, privmsg("#a", "1"), 2));
+        assert!(!queues.push(Priority::Bulk, privmsg("#a", "2"), 2));
+        assert!(queues.push(Priority::Bulk, privmsg("#a", "3"), 2));
+        assert!(!queues.push(Priority::Bulk, privmsg("#a", "4"), 2));
+        assert_eq!(queues.depth(), vec![(Priority::Bulk, "#a".to_string(), 2)]);
+
+        assert_eq!(text(queues.pop()), "#a 1");
+        assert_eq!(text(queues.pop()), "#a 2");
+        assert_eq!(text(queues.pop()), "#a (2 more lines dropped)");
+        assert!(queues.pop().is_none());
+    }
+
+    #[test]
+    fn test_server_lines_are_not_summarized() {
+        let mut queues = Queues::default();
+        let mode = || Message::from(Command::Raw("MODE".to_string(), vec!["#a".to_string()]));
+        assert!(!queues.push(Priority::Bulk, mode(), 1));
+        assert!(queues.push(Priority::Bulk, mode(), 1));
+
+        assert!(matches!(
+            queues.pop().map(|message| message.command),
+            Some(Command::Raw(..))
+        ));
+        assert!(queues.pop().is_none());
+    }
+
+    #[test]
+    fn test_urgency() {
+        let services = services_nicks(&ServicesSettings::default());
+        assert_eq!(
+            urgency(&privmsg("NickServ", "IDENTIFY x"), &services),
+            Priority::Urgent
+        );
+        assert_eq!(
+            urgency(&privmsg("CHANSERV", "OP #rshelp"), &services),
+            Priority::Urgent
+        );
+        assert_eq!(
+            urgency(
+                &Command::PONG("irc.example".to_string(), None).into(),
+                &services
+            ),
+            Priority::Urgent
+        );
+        assert_eq!(
+            urgency(&privmsg("#rshelp", "hi"), &services),
+            Priority::Bulk
+        );
+        assert_eq!(
+            urgency(&privmsg("#webserv", "hi"), &services),
+            Priority::Bulk
+        );
+        assert_eq!(urgency(&privmsg("Deserv", "hi"), &services), Priority::Bulk);
+
+        let services = services_nicks(&ServicesSettings {
+            nickserv: Some("Q".to_string()),
+            ..Default::default()
+        });
+        assert_eq!(
+            urgency(&privmsg("q", "AUTH x"), &services),
+            Priority::Urgent
+        );
+        assert_eq!(
+            urgency(&privmsg("NickServ", "hi"), &services),
+            Priority::Bulk
+        );
+    }
+}
@@ -0,0 +1,221 @@
+use crate::events;
+use crate::response::{Item, Kind};
+use crate::settings::Settings;
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+use std::time::Instant;
+
+/// The part of a command's output to send now.
+#[derive(Debug, PartialEq)]
+pub struct Page {
+    pub kind: Kind,
+    pub target: String,
+    pub items: Vec<Item>,
+    /// How many lines are still held for `+more`.
+    pub remaining: usize,
+}
+
+// Output held back for `+more`
+struct Held {
+    kind: Kind,
+    target: String,
+    items: Vec<Item>,
+    expires: Instant,
+}
+
+/// Caps how many lines one command sends to a target and holds the rest,
+/// per channel and sender, until they ask for it with `+more`.
+pub struct Pager {
+    settings: Arc<Settings>,
+    held: Mutex<HashMap<(String, String), Held>>,
+}
+
+impl Pager {
+    pub fn new(settings: Arc<Settings>) -> Self {
+        Self {
+            settings,
+            held: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// The first page of a command's output for `target`, already split into
+    /// one item per line sent. Anything past the target's cap replaces
+    /// whatever `hostmask` had held in `channel`.
+    pub fn first(
+        &self,
+        channel: &str,
+        hostmask: &str,
+        kind: Kind,
+        target: &str,
+        items: Vec<Item>,
+    ) -> Page {
+        self.first_at(Instant::now(), channel, hostmask, kind, target, items)
+    }
+
+    fn first_at(
+        &self,
+        now: Instant,
+        channel: &str,
+        hostmask: &str,
+        kind: Kind,
+        target: &str,
+        mut items: Vec<Item>,
+    ) -> Page {
+        let key = key(channel, hostmask);
+        let cap = self.lines(target);
+        let rest = items.split_off(cap.min(items.len()));
+
+        let mut held = match self.held.lock() {
+            Ok(held) => held,
+            Err(_) => return page(kind, target, items, 0),
+        };
+        held.retain(|_, held| held.expires > now);
+
+        if rest.is_empty() {
+            held.remove(&key);
+            return page(kind, target, items, 0);
+        }
+
+        let remaining = rest.len();
+        held.insert(
+            key,
+            Held {
+                kind,
+                target: target.to_string(),
+                items: rest,
+                expires: now + self.settings.paging.expiry(),
+            },
+        );
+
+        page(kind, target, items, remaining)
+    }
+
+    /// The next page held for `hostmask` in `channel`, if any.
+    pub fn more(&self, channel: &str, hostmask: &str) -> Option<Page> {
+        self.more_at(Instant::now(), channel, hostmask)
+    }
+
+    fn more_at(&self, now: Instant, channel: &str, hostmask: &str) -> Option<Page> {
+        let key = key(channel, hostmask);
+
+        let mut held = self.held.lock().ok()?;
+        held.retain(|_, held| held.expires > now);
+
+        let entry = held.get_mut(&key)?;
+        let cap = self.lines(&entry.target);
+        let rest = entry.items.split_off(cap.min(entry.items.len()));
+        let items = std::mem::replace(&mut entry.items, rest);
+        let (kind, target, remaining) = (entry.kind, entry.target.clone(), entry.items.len());
+
+        if remaining == 0 {
+            held.remove(&key);
+        } else {
+            entry.expires = now + self.settings.paging.expiry();
+        }
+
+        Some(page(kind, &target, items, remaining))
+    }
+
+    // How many lines a command may send to `target` at once
+    fn lines(&self, target: &str) -> usize {
+        if events::is_channel(target) {
+            self.settings.paging.channel_lines(target)
+        } else {
+            self.settings.paging.query_lines()
+        }
+    }
+}
+
+fn key(channel: &str, hostmask: &str) -> (String, String) {
+    (channel.to_lowercase(), hostmask.to_lowercase())
+}
+
+fn page(kind: Kind, target: &str, items: Vec<Item>, remaining: usize) -> Page {
+    Page {
+        kind,
+        target: target.to_string(),
+        items,
+        remaining,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Duration;
+
+    fn pager() -> Pager {
+        let settings = Settings::parse(
+            r##"
+            [paging]
+            channel_lines = 2
+            query_lines = 3
+            expiry = "1m"
+
+            [paging.channels]
+            "#drops" = 4
+            "##,
+        )
+        .unwrap();
+        Pager::new(Arc::new(settings))
+    }
+
+    fn items(count: usize) -> Vec<Item> {
+        (1..=count).map(|n| Item::plain(&n.to_string())).collect()
+    }
+
+    fn texts(page: &Page) -> Vec<&str> {
+        page.items.iter().map(|item| item.text.as_str()).collect()
+    }
+
+    #[test]
+    fn test_pages_through_held_output() {
+        let pager = pager();
+        let now = Instant::now();
+
+        let first = pager.first_at(now, "#rshelp", "a!a@a", Kind::Privmsg, "#rshelp", items(5));
+        assert_eq!(texts(&first), vec!["1", "2"]);
+        assert_eq!(first.remaining, 3);
+
+        let second = pager.more_at(now, "#RSHelp", "A!a@a").unwrap();
+        assert_eq!(texts(&second), vec!["3", "4"]);
+        assert_eq!(second.remaining, 1);
+        assert_eq!(second.target, "#rshelp");
+
+        let third = pager.more_at(now, "#rshelp", "a!a@a").unwrap();
+        assert_eq!(texts(&third), vec!["5"]);
+        assert_eq!(third.remaining, 0);
+        assert!(pager.more_at(now, "#rshelp", "a!a@a").is_none());
+    }
+
+    #[test]
+    fn test_caps_by_target() {
+        let pager = pager();
+        let now = Instant::now();
+
+        let drops = pager.first_at(now, "#drops", "a!a@a", Kind::Privmsg, "#drops", items(5));
+        assert_eq!(drops.items.len(), 4);
+
+        // A notice reply from a channel goes to the nick, so gets the query cap
+        let query = pager.first_at(now, "#rshelp", "a!a@a", Kind::Notice, "a", items(5));
+        assert_eq!(query.items.len(), 3);
+        assert_eq!(
+            pager.more_at(now, "#rshelp", "a!a@a").unwrap().kind,
+            Kind::Notice
+        );
+
+        let short = pager.first_at(now, "#rshelp", "b!b@b", Kind::Privmsg, "#rshelp", items(2));
+        assert_eq!(short.remaining, 0);
+        assert!(pager.more_at(now, "#rshelp", "b!b@b").is_none());
+    }
+
+    #[test]
+    fn test_held_pages_expire() {
+        let pager = pager();
+        let now = Instant::now();
+
+        pager.first_at(now, "#rshelp", "a!a@a", Kind::Privmsg, "#rshelp", items(5));
+        let later = now + Duration::from_secs(61);
+        assert!(pager.more_at(later, "#rshelp", "a!a@a").is_none());
+    }
+}
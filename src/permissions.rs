@@ -0,0 +1,369 @@
+use crate::settings::Settings;
+use irc::client::prelude::Message;
+use std::fmt;
+use std::sync::RwLock;
+
+/// How far a user is trusted, lowest first. Plugins receive it as a number:
+/// ignored 0, user 1, trusted 2, admin 3, owner 4.
+#[derive(Clone, Copy, Debug, Default, Eq, Ord, PartialEq, PartialOrd)]
+pub enum Level {
+    Ignored,
+    #[default]
+    User,
+    Trusted,
+    Admin,
+    Owner,
+}
+
+impl Level {
+    pub const ALL: [Level; 5] = [
+        Level::Ignored,
+        Level::User,
+        Level::Trusted,
+        Level::Admin,
+        Level::Owner,
+    ];
+
+    pub fn parse(name: &str) -> Option<Self> {
+        Self::ALL
+            .into_iter()
+            .find(|level| level.name().eq_ignore_ascii_case(name.trim()))
+    }
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            Self::Ignored => "ignored",
+            Self::User => "user",
+            Self::Trusted => "trusted",
+            Self::Admin => "admin",
+            Self::Owner => "owner",
+        }
+    }
+}
+
+impl fmt::Display for Level {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.name())
+    }
+}
+
+/// Who a command or listener match runs for.
+#[derive(Clone, Debug)]
+pub struct Caller {
+    /// `nick!user@host`
+    pub author: String,
+    pub level: Level,
+    /// IRCv3 tags on the message behind the call, e.g. `account` or `time`.
+    pub tags: Vec<(String, String)>,
+}
+
+impl Caller {
+    /// The host itself, for timers and scheduled calls.
+    pub fn internal(author: &str) -> Self {
+        Self {
+            author: author.to_string(),
+            level: Level::Owner,
+            tags: vec![],
+        }
+    }
+}
+
+/// One permission rule: a `nick!user@host` mask, or `$a:` and a services
+/// account mask, and the level it grants.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Rule {
+    pub mask: String,
+    pub level: Level,
+}
+
+impl Rule {
+    pub fn matches(&self, hostmask: &str, account: Option<&str>) -> bool {
+        matches_sender(&self.mask, hostmask, account)
+    }
+}
+
+/// Matches a sender against a `nick!user@host` mask, or a `$a:` one against
+/// their services account.
+pub fn matches_sender(mask: &str, hostmask: &str, account: Option<&str>) -> bool {
+    match mask.strip_prefix("$a:") {
+        Some(mask) => account.is_some_and(|account| matches_mask(mask, account)),
+        None => matches_mask(mask, hostmask),
+    }
+}
+
+/// Whether `mask` is something a rule can match: a full `nick!user@host`
+/// mask or a `$a:account` one.
+pub fn is_valid_mask(mask: &str) -> bool {
+    match mask.strip_prefix("$a:") {
+        Some(account) => !account.is_empty(),
+        None => mask.contains('!') && mask.contains('@') && !mask.contains(' '),
+    }
+}
+
+/// The network's permission rules, read from `[permissions]` (and the old
+/// top-level `admins`) and editable at runtime with `+access`. Runtime edits
+/// last until the bot restarts. Who is ignored is up to `Ignores`, not these
+/// rules.
+pub struct Permissions {
+    rules: RwLock<Vec<Rule>>,
+}
+
+impl Permissions {
+    pub fn new(settings: &Settings) -> Self {
+        let levels = [
+            (Level::Owner, settings.permissions.owner.clone()),
+            (Level::Admin, settings.admin_masks()),
+            (Level::Trusted, settings.permissions.trusted.clone()),
+        ];
+
+        let rules = levels
+            .into_iter()
+            .flat_map(|(level, masks)| masks.into_iter().map(move |mask| Rule { mask, level }))
+            .filter(|rule| {
+                let valid = is_valid_mask(&rule.mask);
+                if !valid {
+                    println!("Ignoring invalid permission mask '{}'", rule.mask);
+                }
+                valid
+            })
+            .collect();
+
+        Self {
+            rules: RwLock::new(rules),
+        }
+    }
+
+    /// The level of a user: the highest level matched, else user.
+    pub fn level(&self, hostmask: &str, account: Option<&str>) -> Level {
+        let rules = match self.rules.read() {
+            Ok(rules) => rules,
+            Err(_) => return Level::User,
+        };
+
+        rules
+            .iter()
+            .filter(|rule| rule.matches(hostmask, account))
+            .map(|rule| rule.level)
+            .max()
+            .unwrap_or_default()
+    }
+
+    /// Adds a rule, or changes the level of the rule with the same mask.
+    pub fn set(&self, mask: &str, level: Level) {
+        if let Ok(mut rules) = self.rules.write() {
+            match rules
+                .iter_mut()
+                .find(|rule| rule.mask.eq_ignore_ascii_case(mask))
+            {
+                Some(rule) => rule.level = level,
+                None => rules.push(Rule {
+                    mask: mask.to_string(),
+                    level,
+                }),
+            }
+        }
+    }
+
+    /// Removes the rule with this mask, returning the level it granted.
+    pub fn remove(&self, mask: &str) -> Option<Level> {
+        let mut rules = self.rules.write().ok()?;
+        let index = rules
+            .iter()
+            .position(|rule| rule.mask.eq_ignore_ascii_case(mask))?;
+
+        Some(rules.remove(index).level)
+    }
+
+    /// The level the rule with this mask grants, if there is one.
+    pub fn rule(&self, mask: &str) -> Option<Level> {
+        let rules = self.rules.read().ok()?;
+        rules
+            .iter()
+            .find(|rule| rule.mask.eq_ignore_ascii_case(mask))
+            .map(|rule| rule.level)
+    }
+
+    pub fn rules(&self) -> Vec<Rule> {
+        self.rules
+            .read()
+            .map(|rules| rules.clone())
+            .unwrap_or_default()
+    }
+}
+
+/// The services account a message was sent from, if the server tagged it
+/// (IRCv3 `account-tag`).
+pub fn account(message: &Message) -> Option<String> {
+    message
+        .tags
+        .as_ref()?
+        .iter()
+        .find(|tag| tag.0 == "account")
+        .and_then(|tag| tag.1.clone())
+        .filter(|account| !account.is_empty() && account != "*")
+}
+
+/// Matches a `nick!user@host` (or an account name) against a mask where `*`
+/// stands for any run of characters and `?` for any one, ignoring case.
+pub fn matches_mask(mask: &str, hostmask: &str) -> bool {
+    let mask = mask.to_lowercase().chars().collect::<Vec<char>>();
+    let hostmask = hostmask.to_lowercase().chars().collect::<Vec<char>>();
+
+    let (mut m, mut h) = (0, 0);
+    // Where the last `*` was, and how much of the hostmask it has taken
+    let mut backtrack: Option<(usize, usize)> = None;
+
+    while h < hostmask.len() {
+        match mask.get(m) {
+            Some('*') => {
+                backtrack = Some((m, h));
+                m += 1;
+            }
+            Some(&c) if c == '?' || c == hostmask[h] => {
+                m += 1;
+                h += 1;
+            }
+            _ => match backtrack {
+                Some((star, taken)) => {
+                    backtrack = Some((star, taken + 1));
+                    m = star + 1;
+                    h = taken + 1;
+                }
+                None => return false,
+            },
+        }
+    }
+
+    mask[m..].iter().all(|&c| c == '*')
+}
+
+/// Parses a manifest's per-trigger minimum levels, one `level:trigger` per
+/// line, e.g. `admin:^reset$`. Everything after the first colon is the
+/// trigger, so it may contain colons itself.
+pub fn parse_level_declarations(output: &str) -> Result<Vec<(String, Level)>, String> {
+    output
+        .lines()
+        .filter(|line| !line.trim().is_empty())
+        .map(|line| {
+            let (level, trigger) = line
+                .split_once(':')
+                .ok_or_else(|| format!("malformed trigger level '{}'", line))?;
+            let level =
+                Level::parse(level).ok_or_else(|| format!("unknown level '{}'", level.trim()))?;
+
+            Ok((trigger.to_string(), level))
+        })
+        .collect()
+}
+
+pub fn serialize_level_declarations(levels: &[(String, Level)]) -> String {
+    levels
+        .iter()
+        .map(|(trigger, level)| format!("{}:{}", level, trigger))
+        .collect::<Vec<String>>()
+        .join("\n")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::settings::PermissionSettings;
+
+    fn permissions() -> Permissions {
+        let settings = Settings {
+            permissions: PermissionSettings {
+                owner: vec!["kick!*@staff.example.net".to_string()],
+                admin: vec!["*!*@staff.example.net".to_string()],
+                trusted: vec!["$a:*".to_string(), "nonsense".to_string()],
+                ignored: vec![],
+            },
+            ..Default::default()
+        };
+        Permissions::new(&settings)
+    }
+
+    #[test]
+    fn test_matches_mask() {
+        assert!(matches_mask(
+            "*!*@staff.example.net",
+            "Kick!~kick@staff.example.net"
+        ));
+        assert!(matches_mask("kick!*@*", "KICK!~kick@host"));
+        assert!(matches_mask("k?ck!*", "kick!u@h"));
+        assert!(matches_mask("*", "anyone!u@h"));
+        assert!(!matches_mask("kick!*@*", "kicker!u@h"));
+        assert!(!matches_mask("*!*@staff.example.net", "kick!u@evil.net"));
+        assert!(!matches_mask("", "kick!u@h"));
+    }
+
+    #[test]
+    fn test_level() {
+        let permissions = permissions();
+        assert_eq!(
+            permissions.level("kick!~kick@staff.example.net", None),
+            Level::Owner
+        );
+        assert_eq!(
+            permissions.level("mod!~mod@staff.example.net", Some("mod")),
+            Level::Admin
+        );
+        assert_eq!(
+            permissions.level("zezima!z@example.com", Some("zezima")),
+            Level::Trusted
+        );
+        assert_eq!(permissions.level("zezima!z@example.com", None), Level::User);
+        // The invalid mask was dropped
+        assert_eq!(permissions.rules().len(), 3);
+    }
+
+    #[test]
+    fn test_set_and_remove() {
+        let permissions = permissions();
+        permissions.set("zezima!*@*", Level::Admin);
+        assert_eq!(
+            permissions.level("Zezima!z@example.com", None),
+            Level::Admin
+        );
+
+        permissions.set("ZEZIMA!*@*", Level::Trusted);
+        assert_eq!(permissions.rule("zezima!*@*"), Some(Level::Trusted));
+
+        assert_eq!(permissions.remove("zezima!*@*"), Some(Level::Trusted));
+        assert_eq!(permissions.remove("zezima!*@*"), None);
+        assert_eq!(permissions.level("zezima!z@example.com", None), Level::User);
+    }
+
+    #[test]
+    fn test_is_valid_mask() {
+        assert!(is_valid_mask("*!*@host"));
+        assert!(is_valid_mask("$a:kick"));
+        assert!(!is_valid_mask("$a:"));
+        assert!(!is_valid_mask("kick"));
+    }
+
+    #[test]
+    fn test_parse_level_declarations() {
+        let levels = parse_level_declarations("admin:^reset$\ntrusted:^(a|b):c$\n").unwrap();
+        assert_eq!(
+            levels,
+            vec![
+                ("^reset$".to_string(), Level::Admin),
+                ("^(a|b):c$".to_string(), Level::Trusted)
+            ]
+        );
+        assert_eq!(
+            parse_level_declarations(&serialize_level_declarations(&levels)).unwrap(),
+            levels
+        );
+        assert!(parse_level_declarations("god:^reset$").is_err());
+        assert!(parse_level_declarations("^reset$").is_err());
+    }
+
+    #[test]
+    fn test_level_order() {
+        assert!(Level::Owner > Level::Admin);
+        assert!(Level::User > Level::Ignored);
+        assert_eq!(Level::parse(" Trusted "), Some(Level::Trusted));
+        assert_eq!(Level::Admin as u32, 3);
+    }
+}
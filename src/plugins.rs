@@ -1,99 +1,320 @@
+use crate::channels::Channels;
+use crate::dispatch::{self, Dispatch};
+use crate::events::{Event, EventContext, EventFn};
+use crate::executor::Executor;
+use crate::help::CommandInfo;
+use crate::host::{self, ExportedV2Fn, Host, HostApi, PluginContextV2};
+use crate::ignores::Ignores;
+use crate::ircv3;
+use crate::isolation::IsolatedHost;
+use crate::listeners::{ListenFn, ListenerContext, ListenerDef, Listeners};
+use crate::manifest::{FREE_ABI_VERSION, Manifest, ManifestFn, STRUCTURED_OUTPUT};
+use crate::pager::Pager;
+use crate::permissions::{Caller, Level, Permissions};
+use crate::ratelimit::RateLimiter;
+use crate::reconnect::Connections;
+use crate::settings::Settings;
 use crate::timers::{TimerDef, TimerManager, parse_timer_declarations};
+use chrono::{DateTime, Local};
 use common::author::cache::color_ffi;
 use common::{ColorResult, PluginContext};
 use libloading::{Library, Symbol};
-use notify::{Event, RecommendedWatcher, RecursiveMode, Result as NotifyResult, Watcher};
+use notify::{
+    Event as NotifyEvent, RecommendedWatcher, RecursiveMode, Result as NotifyResult, Watcher,
+};
+use sha2::{Digest, Sha256};
+use std::collections::{BTreeSet, HashMap, HashSet};
 use std::ffi::{CStr, CString};
 use std::fs;
 use std::os::raw::c_char;
-use std::sync::mpsc::channel;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::mpsc::{RecvTimeoutError, channel};
 use std::sync::{Arc, Mutex, RwLock};
+use std::thread;
+use std::time::{Duration, Instant};
 
 #[derive(Clone)]
 pub struct PluginManager {
     pub active: Arc<RwLock<Vec<Plugin>>>,
+    // Rebuilt from `active` on every change; messages take a snapshot
+    pub dispatch: Arc<RwLock<Arc<Dispatch>>>,
     pub grave: Arc<Mutex<Vec<Plugin>>>,
+    // Paths of plugins kept loaded but out of dispatch and timers
+    disabled: Arc<RwLock<HashSet<String>>>,
+    // Why each library that failed its last load attempt failed, by path
+    failures: Arc<Mutex<HashMap<String, String>>>,
     pub timer_manager: Arc<TimerManager>,
+    pub executor: Arc<Executor>,
+    pub host: Arc<Host>,
+    pub listeners: Arc<Listeners>,
+    pub permissions: Arc<Permissions>,
+    pub ignores: Arc<Ignores>,
+    pub rate_limiter: Arc<RateLimiter>,
+    pub pager: Arc<Pager>,
+    pub connections: Arc<Connections>,
+    pub channels: Arc<Channels>,
+    pub settings: Arc<Settings>,
 }
 
 impl PluginManager {
-    pub fn new(color_ffi: extern "C" fn(*const c_char, *const c_char) -> ColorResult) -> Self {
+    pub fn new(
+        color_ffi: extern "C" fn(*const c_char, *const c_char) -> ColorResult,
+        settings: Arc<Settings>,
+        permissions: Arc<Permissions>,
+        ignores: Arc<Ignores>,
+        connections: Arc<Connections>,
+    ) -> Self {
+        let executor = Arc::new(Executor::new(settings.clone()));
+        let channels = Arc::new(Channels::new());
+
         Self {
             active: Arc::new(RwLock::new(Vec::new())),
+            dispatch: Arc::new(RwLock::new(Arc::new(Dispatch::empty()))),
             grave: Arc::new(Mutex::new(Vec::new())),
-            timer_manager: Arc::new(TimerManager::new(color_ffi)),
+            disabled: Arc::new(RwLock::new(HashSet::new())),
+            failures: Arc::new(Mutex::new(HashMap::new())),
+            timer_manager: Arc::new(TimerManager::new(executor.clone(), color_ffi)),
+            host: Arc::new(Host::new(
+                executor.clone(),
+                connections.clone(),
+                channels.clone(),
+                color_ffi,
+            )),
+            listeners: Arc::new(Listeners::new(settings.clone())),
+            permissions,
+            ignores,
+            rate_limiter: Arc::new(RateLimiter::new(settings.clone())),
+            pager: Arc::new(Pager::new(settings.clone())),
+            connections,
+            channels,
+            executor,
+            settings,
         }
     }
 
-    pub fn add(&self, path: &str) {
-        println!("... Adding plugin {}", path);
-        // Load the dynamic library
-        let lib = match unsafe { Library::new(path) } {
-            Ok(lib) => lib,
+    /// Loads a plugin in-process or isolated, as its settings ask, and binds
+    /// it to this network's host callbacks. A failure is logged and kept
+    /// for `+plugins list` until the next attempt.
+    fn load(&self, path: &str) -> Result<Plugin, String> {
+        let settings = self.settings.plugin(path);
+        let loaded = if settings.isolated {
+            Plugin::load_isolated(path, settings.timeout(), settings.concurrency())
+        } else {
+            Plugin::load(path)
+        };
+
+        let mut plugin = match loaded.and_then(|plugin| {
+            dispatch::check_triggers(&plugin.triggers)?;
+            Ok(plugin)
+        }) {
+            Ok(plugin) => plugin,
             Err(e) => {
-                println!("Error loading plugin: {}", e);
-                return;
+                println!("Error loading plugin {}: {}", path, e);
+                if let Ok(mut failures) = self.failures.lock() {
+                    failures.insert(path.to_string(), e.clone());
+                }
+                return Err(e);
             }
         };
 
-        // Get a reference to the `exported` function
-        let exported: Symbol<extern "C" fn(context: &PluginContext) -> *mut c_char> =
-            match unsafe { lib.get(b"exported\0") } {
-                Ok(exported) => exported,
-                Err(e) => {
-                    println!("Error loading plugin: {}", e);
-                    return;
+        if let Ok(mut failures) = self.failures.lock() {
+            failures.remove(path);
+        }
+
+        if let Some(priority) = settings.priority {
+            plugin.priority = priority;
+        }
+
+        host::register(self.host.clone(), &mut plugin);
+
+        Ok(plugin)
+    }
+
+    pub fn add(&self, path: &str) -> Result<(), String> {
+        println!("... Adding plugin {}", path);
+
+        let plugin = self.load(path)?;
+
+        // A plugin already loaded from the same path is replaced in place
+        let replaced = {
+            let mut active = self.active.write().unwrap();
+            let replaced = match active.iter().position(|loaded| loaded.name == path) {
+                Some(index) => Some(std::mem::replace(&mut active[index], plugin.clone())),
+                None => {
+                    active.push(plugin.clone());
+                    None
                 }
             };
+            self.rebuild_dispatch(&active);
+            replaced
+        };
 
-        let empty = CString::new("").unwrap().into_raw();
-        // Call the `exported` function
-        let raw_triggers = exported(&PluginContext {
-            cmd: empty,
-            param: empty,
-            author: empty,
-            color: color_ffi,
-            channel: empty,
-        });
+        // Only this plugin's timers start over
+        self.timer_manager.cancel(path);
+        if !self.is_disabled(path) {
+            self.timer_manager.start(&plugin);
+        }
 
-        let triggers = match unsafe { CStr::from_ptr(raw_triggers).to_str() } {
-            Ok(triggers) => triggers.split("\n").map(|s| s.to_string()).collect(),
-            Err(_) => return,
+        if let Some(old) = replaced {
+            println!(
+                "Replaced plugin {}: {}",
+                path,
+                describe_changes(&old, &plugin)
+            );
+            host::revoke(old.host_handle);
+            if let Ok(mut grave) = self.grave.lock() {
+                grave.push(old);
+            }
+        }
+        self.prune_grave();
+
+        Ok(())
+    }
+
+    /// Unloads the plugin at `path`: its timers are cancelled, its host
+    /// callbacks revoked and its concurrency limit and listener cooldowns
+    /// dropped. The library itself goes once no call is still running in it.
+    pub fn unload(&self, path: &str) -> Result<(), String> {
+        {
+            let mut active = self
+                .active
+                .write()
+                .map_err(|_| "plugin list is poisoned".to_string())?;
+            let index = active
+                .iter()
+                .position(|plugin| plugin.name == path)
+                .ok_or_else(|| format!("{} isn't loaded", path))?;
+
+            let plugin = active.remove(index);
+            self.rebuild_dispatch(&active);
+
+            self.timer_manager.cancel(&plugin.name);
+            host::revoke(plugin.host_handle);
+            self.executor.forget(&plugin.name);
+            self.listeners.forget(&plugin.name);
+            if let Ok(mut disabled) = self.disabled.write() {
+                disabled.remove(path);
+            }
+
+            if let Ok(mut grave) = self.grave.lock() {
+                grave.push(plugin);
+            }
+        }
+
+        println!("... Unloaded plugin {}", path);
+        self.prune_grave();
+
+        Ok(())
+    }
+
+    /// Keeps the plugin at `path` loaded but stops dispatching to it and
+    /// cancels its timers, until `enable` or the bot restarts.
+    pub fn disable(&self, path: &str) -> Result<(), String> {
+        let active = self
+            .active
+            .read()
+            .map_err(|_| "plugin list is poisoned".to_string())?;
+        if !active.iter().any(|plugin| plugin.name == path) {
+            return Err(format!("{} isn't loaded", path));
+        }
+
+        let inserted = match self.disabled.write() {
+            Ok(mut disabled) => disabled.insert(path.to_string()),
+            Err(_) => return Err("disabled list is poisoned".to_string()),
         };
+        if !inserted {
+            return Err(format!("{} is already disabled", path));
+        }
 
-        let raw_commands = exported(&PluginContext {
-            cmd: CString::new("help").unwrap().into_raw(),
-            param: empty,
-            author: empty,
-            color: color_ffi,
-            channel: empty,
-        });
-        let commands = match unsafe { CStr::from_ptr(raw_commands).to_str() } {
-            Ok(commands) => commands.split("\n").map(|s| s.to_string()).collect(),
-            Err(_) => return,
+        self.rebuild_dispatch(&active);
+        self.timer_manager.cancel(path);
+
+        Ok(())
+    }
+
+    pub fn enable(&self, path: &str) -> Result<(), String> {
+        let active = self
+            .active
+            .read()
+            .map_err(|_| "plugin list is poisoned".to_string())?;
+        let plugin = active
+            .iter()
+            .find(|plugin| plugin.name == path)
+            .ok_or_else(|| format!("{} isn't loaded", path))?;
+
+        let removed = match self.disabled.write() {
+            Ok(mut disabled) => disabled.remove(path),
+            Err(_) => return Err("disabled list is poisoned".to_string()),
         };
+        if !removed {
+            return Err(format!("{} isn't disabled", path));
+        }
 
-        let raw_timers = exported(&PluginContext {
-            cmd: CString::new("timers").unwrap().into_raw(),
-            param: empty,
-            author: empty,
-            color: color_ffi,
-            channel: empty,
-        });
-        let timers = match unsafe { CStr::from_ptr(raw_timers).to_str() } {
-            Ok(timers_str) => parse_timer_declarations(timers_str),
+        self.rebuild_dispatch(&active);
+        self.timer_manager.start(plugin);
+
+        Ok(())
+    }
+
+    pub fn is_disabled(&self, path: &str) -> bool {
+        self.disabled
+            .read()
+            .map(|disabled| disabled.contains(path))
+            .unwrap_or(false)
+    }
+
+    /// Libraries whose last load attempt failed, with why, by path.
+    pub fn failures(&self) -> Vec<(String, String)> {
+        let mut failures = match self.failures.lock() {
+            Ok(failures) => failures
+                .iter()
+                .map(|(path, e)| (path.clone(), e.clone()))
+                .collect::<Vec<_>>(),
             Err(_) => vec![],
         };
+        failures.sort();
+        failures
+    }
 
-        let plugin: Plugin = Plugin {
-            name: path.to_string(),
-            commands,
-            triggers,
-            timers,
-        };
+    /// The path of the loaded plugin `name` refers to, by path, file name or
+    /// file stem.
+    pub fn find_loaded(&self, name: &str) -> Option<String> {
+        let active = self.active.read().ok()?;
+        active
+            .iter()
+            .map(|plugin| plugin.name.clone())
+            .find(|path| refers_to(path, name))
+    }
 
-        self.active.write().unwrap().push(plugin);
-        self.timer_manager.restart(&self.active);
+    /// The path of the library in the plugin directory `name` refers to, by
+    /// file name or file stem.
+    pub fn find_file(name: &str) -> Option<String> {
+        fs::read_dir(PATH)
+            .ok()?
+            .filter_map(|entry| entry.ok())
+            .map(|entry| entry.path())
+            .filter(|path| is_library(path))
+            .filter_map(|path| path.to_str().map(|path| path.to_string()))
+            .find(|path| refers_to(path, name))
+    }
+
+    /// Starts the timers of every enabled plugin again, after a disconnect
+    /// cancelled them.
+    pub fn restart_timers(&self) {
+        self.timer_manager.restart(&self.enabled());
+    }
+
+    // Active plugins that aren't disabled, for timers.
+    fn enabled(&self) -> Vec<Plugin> {
+        match self.active.read() {
+            Ok(active) => active
+                .iter()
+                .filter(|plugin| !self.is_disabled(&plugin.name))
+                .cloned()
+                .collect(),
+            Err(_) => vec![],
+        }
     }
 
     pub fn reload(&self) -> Result<&Self, ()> {
@@ -115,6 +336,11 @@ impl PluginManager {
             }
         };
 
+        // Every library gets a fresh attempt, and deleted ones are forgotten
+        if let Ok(mut failures) = self.failures.lock() {
+            failures.clear();
+        }
+
         let mut new = Vec::new();
 
         for plugin in plugins {
@@ -126,87 +352,18 @@ impl PluginManager {
                 }
             };
 
-            let path = plugin.path();
-            let extension = match path.extension() {
-                Some(ext) => match ext.to_str() {
-                    Some(ext) => ext,
-                    None => continue,
-                },
-                None => continue,
-            };
-
-            if !["so", "dll", "dylib"].contains(&extension) {
+            if !is_library(&plugin.path()) {
                 continue;
             }
 
-            // Load the dynamic library
-            let lib = match unsafe { Library::new(plugin.path()) } {
-                Ok(lib) => lib,
-                Err(e) => {
-                    println!("Error loading plugin: {}", e);
-                    continue;
-                }
-            };
-
-            // Get a reference to the `exported` function
-            let exported: Symbol<extern "C" fn(context: &PluginContext) -> *mut c_char> =
-                match unsafe { lib.get(b"exported\0") } {
-                    Ok(exported) => exported,
-                    Err(e) => {
-                        println!("Error loading plugin: {}", e);
-                        continue;
-                    }
-                };
-
-            let empty = CString::new("").unwrap().into_raw();
-            // Call the `exported` function
-            let raw_triggers = exported(&PluginContext {
-                cmd: empty,
-                param: empty,
-                author: empty,
-                color: color_ffi,
-                channel: empty,
-            });
-            let triggers = match unsafe { CStr::from_ptr(raw_triggers).to_str() } {
-                Ok(triggers) => triggers.split("\n").map(|s| s.to_string()).collect(),
-                Err(_) => continue,
-            };
-
-            let raw_commands = exported(&PluginContext {
-                cmd: CString::new("help").unwrap().into_raw(),
-                param: empty,
-                author: empty,
-                color: color_ffi,
-                channel: empty,
-            });
-
-            let commands = match unsafe { CStr::from_ptr(raw_commands).to_str() } {
-                Ok(commands) => commands.split("\n").map(|s| s.to_string()).collect(),
-                Err(_) => continue,
-            };
-
-            let raw_timers = exported(&PluginContext {
-                cmd: CString::new("timers").unwrap().into_raw(),
-                param: empty,
-                author: empty,
-                color: color_ffi,
-                channel: empty,
-            });
-            let timers = match unsafe { CStr::from_ptr(raw_timers).to_str() } {
-                Ok(timers_str) => parse_timer_declarations(timers_str),
-                Err(_) => vec![],
-            };
-
             let name = match plugin.path().to_str() {
                 Some(name) => name.to_string(),
                 None => continue,
             };
 
-            let loaded_plugin: Plugin = Plugin {
-                name,
-                commands,
-                triggers,
-                timers,
+            let loaded_plugin = match self.load(&name) {
+                Ok(loaded_plugin) => loaded_plugin,
+                Err(_) => continue,
             };
 
             println!(
@@ -215,6 +372,13 @@ impl PluginManager {
                 loaded_plugin.commands.join(", ")
             );
 
+            if let Some(manifest) = &loaded_plugin.manifest {
+                println!(
+                    "\tManifest: {} {} (plugin ABI v{})",
+                    manifest.name, manifest.version, manifest.abi_version
+                );
+            }
+
             if !loaded_plugin.timers.is_empty() {
                 println!(
                     "\tTimers: {}",
@@ -231,51 +395,133 @@ impl PluginManager {
         }
 
         let old = std::mem::replace(&mut *active_ref, new);
+        self.rebuild_dispatch(&active_ref);
+
+        for change in describe_reload(&old, &active_ref) {
+            println!("{}", change);
+        }
 
+        for plugin in &old {
+            host::revoke(plugin.host_handle);
+        }
         grave_ref.extend(old);
 
         // Drop locks before restarting timers — restart() needs to read-lock active
         drop(active_ref);
         drop(grave_ref);
 
-        self.timer_manager.restart(&self.active);
+        self.timer_manager.restart(&self.enabled());
+        self.prune_grave();
 
         Ok(self)
     }
 
+    // Called with `active` still locked, so the table is swapped in the same
+    // step as the plugin list it was built from.
+    // Disabled plugins are left out.
+    fn rebuild_dispatch(&self, active: &[Plugin]) {
+        let enabled = active
+            .iter()
+            .filter(|plugin| !self.is_disabled(&plugin.name))
+            .cloned()
+            .collect();
+
+        let (dispatch, left_out) = Dispatch::build(enabled);
+        for (name, e) in &left_out {
+            println!("Error building dispatch table, leaving out {}: {}", name, e);
+        }
+
+        for conflict in &dispatch.conflicts {
+            println!(
+                "Trigger conflict on '{}': {}",
+                conflict.command,
+                describe_order(&conflict.plugins)
+            );
+        }
+
+        for shadowed in &dispatch.shadowed {
+            println!(
+                "Warning: '{}' is a built-in, so it never reaches {}",
+                shadowed.command,
+                describe_order(&shadowed.plugins)
+            );
+        }
+
+        if let Ok(mut current) = self.dispatch.write() {
+            *current = Arc::new(dispatch);
+        }
+    }
+
+    /// Drops retired plugins whose library is referenced by nothing but the
+    /// grave itself, unloading the library now that no call can still be
+    /// running inside it.
+    pub fn prune_grave(&self) {
+        if let Ok(mut grave) = self.grave.lock() {
+            grave.retain(|plugin| plugin.backend.references() > 1);
+        }
+    }
+
+    /// Applies changes in the plugin directory as they happen. Bursts of
+    /// events, like a build writing a library in several steps, are gathered
+    /// until things go quiet for `DEBOUNCE`, then each changed library is
+    /// loaded once it stops changing, or unloaded if it was deleted. In quiet
+    /// spells the grave is pruned every `PRUNE_INTERVAL`.
     pub fn watch(&self) {
         let (tx, rx) = channel();
         println!("Watching plugin changes...");
         let _watcher = Plugin::watch(tx);
 
         loop {
-            let event = rx.recv();
-
-            match event {
-                Ok(Ok(e)) if e.kind.is_remove() || e.kind.is_modify() => {
-                    let path = e.paths.first().unwrap().to_string_lossy().to_string();
-
-                    println!(
-                        "Plugin change detected! {} {}",
-                        if e.kind.is_remove() {
-                            "Removed"
-                        } else {
-                            "Created"
-                        },
-                        path
-                    );
-
-                    if e.kind.is_remove() {
-                        self.reload().expect("Plugin loading error");
-                    } else {
-                        self.add(&path);
-                    }
+            let mut changed = match rx.recv_timeout(PRUNE_INTERVAL) {
+                Ok(event) => changed_libraries(event),
+                // Calls still running in a retired plugin finish in their
+                // own time, long after whatever retired it pruned
+                Err(RecvTimeoutError::Timeout) => {
+                    self.prune_grave();
+                    continue;
                 }
-                Ok(Err(_)) => continue,
-                _ => continue,
+                Err(RecvTimeoutError::Disconnected) => return,
+            };
+            while let Ok(event) = rx.recv_timeout(DEBOUNCE) {
+                changed.extend(changed_libraries(event));
+            }
+
+            for path in changed {
+                self.apply_change(&path);
             }
         }
     }
+
+    fn apply_change(&self, path: &str) {
+        if !Path::new(path).exists() {
+            if self.find_loaded(path).is_some() {
+                println!("Plugin change detected! Removed {}", path);
+                self.unload(path).ok();
+            }
+            return;
+        }
+
+        if !wait_until_stable(path) {
+            println!(
+                "Plugin {} is still being written, leaving it for the next change",
+                path
+            );
+            return;
+        }
+
+        let loaded = self.active.read().ok().and_then(|active| {
+            active
+                .iter()
+                .find(|plugin| plugin.name == path)
+                .map(|plugin| plugin.hash.clone())
+        });
+        if loaded.is_some() && loaded == hash_file(path).ok() {
+            return;
+        }
+
+        println!("Plugin change detected! Changed {}", path);
+        self.add(path).ok();
+    }
 }
 
 #[derive(Clone)]
@@ -284,15 +530,424 @@ pub struct Plugin {
     pub commands: Vec<String>,
     pub triggers: Vec<String>,
     pub timers: Vec<TimerDef>,
+    // IRC events the plugin subscribes to, e.g. `JOIN` or `001`.
+    pub events: Vec<String>,
+    // Passive listeners matched against ordinary channel lines.
+    pub listeners: Vec<ListenerDef>,
+    // Higher runs first when triggers overlap; see `Dispatch`.
+    pub priority: i32,
+    // Per-command details for `+help`; empty for plugins that only list
+    // their commands.
+    pub command_info: Vec<CommandInfo>,
+    // Minimum levels for some of the triggers; the rest are open to users.
+    pub levels: Vec<(String, Level)>,
+    // `None` for plugins discovered through the legacy probe protocol.
+    pub manifest: Option<Manifest>,
+    pub backend: Backend,
+    // Identifies the plugin to host callbacks; 0 until `host::register`.
+    pub host_handle: u64,
+    pub loaded_at: DateTime<Local>,
+    // SHA-256 of the library file as loaded, in hex
+    pub hash: String,
+}
+
+pub type ExportedFn = extern "C" fn(context: &PluginContext) -> *mut c_char;
+
+/// A plugin's `plugin_free` export. Every non-null string a plugin returns is
+/// passed back to it exactly once, after the host has copied it, so the
+/// plugin frees it with the allocator that made it. Plugins without one are
+/// rejected at load from `FREE_ABI_VERSION` on; older ones have their output
+/// copied and leaked.
+pub type FreeFn = extern "C" fn(ptr: *mut c_char);
+
+// The entry point a call goes through, with what it needs beyond the context.
+enum EntryPoint {
+    V1(ExportedFn),
+    V2(ExportedV2Fn, HostApi, Level),
+}
+
+/// Where a plugin's code runs. Every clone shares the backend, so a library
+/// (or child process) stays alive for as long as any clone — an in-flight
+/// command, a timer — still holds it.
+#[derive(Clone)]
+pub enum Backend {
+    Resident {
+        library: Arc<Library>,
+        // Only held to be dropped, after `library` so the file outlives the
+        // mapping
+        _copy: Arc<PrivateCopy>,
+        exported: ExportedFn,
+        // `None` for older plugins whose output is leaked
+        free: Option<FreeFn>,
+        // Optional `exported_v2`, preferred when the plugin has host callbacks
+        exported_v2: Option<ExportedV2Fn>,
+        // `plugin_event`, required when the manifest subscribes to events
+        plugin_event: Option<EventFn>,
+        // `plugin_listen`, required when the manifest declares listeners
+        plugin_listen: Option<ListenFn>,
+    },
+    Isolated(Arc<IsolatedHost>),
+}
+
+impl Backend {
+    /// Number of live `Plugin` clones sharing this backend.
+    pub fn references(&self) -> usize {
+        match self {
+            Self::Resident { library, .. } => Arc::strong_count(library),
+            Self::Isolated(host) => Arc::strong_count(host),
+        }
+    }
 }
 
 const PATH: &str = "plugins/";
 
+// How long the plugin directory must be quiet before changes are applied
+const DEBOUNCE: Duration = Duration::from_millis(500);
+// How often the watcher checks for retired plugins whose last call finished
+const PRUNE_INTERVAL: Duration = Duration::from_secs(60);
+// How often, and for how long, a changed library is checked for still growing
+const STABLE_INTERVAL: Duration = Duration::from_millis(250);
+const STABLE_TIMEOUT: Duration = Duration::from_secs(30);
+
+static COPIES: AtomicU64 = AtomicU64::new(0);
+
+/// A copy of a plugin library made for the loader to map, deleted once the
+/// last clone of its plugin is gone.
+pub struct PrivateCopy {
+    pub path: PathBuf,
+}
+
+impl PrivateCopy {
+    fn create(path: &str, contents: &[u8]) -> Result<Self, String> {
+        let directory = std::env::temp_dir().join("reinze-plugins");
+        fs::create_dir_all(&directory).map_err(|e| e.to_string())?;
+
+        // Unique across processes, since isolated plugins' hosts copy too
+        let name = Path::new(path)
+            .file_name()
+            .map(|name| name.to_string_lossy().to_string())
+            .unwrap_or_default();
+        let copy = directory.join(format!(
+            "{}-{}-{}",
+            std::process::id(),
+            COPIES.fetch_add(1, Ordering::Relaxed),
+            name
+        ));
+
+        fs::write(&copy, contents).map_err(|e| e.to_string())?;
+
+        Ok(Self { path: copy })
+    }
+}
+
+impl Drop for PrivateCopy {
+    fn drop(&mut self) {
+        _ = fs::remove_file(&self.path);
+    }
+}
+
 impl Plugin {
+    /// Loads the library at `path` and reads its description, preferring the
+    /// `plugin_manifest` symbol and falling back to probing `exported`.
+    /// Returns why if the plugin can't be activated.
+    pub fn load(path: &str) -> Result<Self, String> {
+        // The loader maps a private copy, so a build overwriting `path` can't
+        // change the code under a running plugin, and the hash is of exactly
+        // what got loaded
+        let contents = fs::read(path).map_err(|e| e.to_string())?;
+        let hash = hash(&contents);
+        let copy = Arc::new(PrivateCopy::create(path, &contents)?);
+
+        // Load the dynamic library
+        let lib = unsafe { Library::new(&copy.path) }.map_err(|e| e.to_string())?;
+
+        // Resolve the `exported` entry point once; `library` keeps it valid
+        let exported: ExportedFn = match unsafe { lib.get::<ExportedFn>(b"exported\0") } {
+            Ok(exported) => *exported,
+            Err(e) => return Err(e.to_string()),
+        };
+        // Output is handed back to the plugin to free; the host's allocator
+        // may not be the one that allocated it
+        let free = unsafe { lib.get::<FreeFn>(b"plugin_free\0") }
+            .ok()
+            .map(|free| *free);
+        let exported_v2 = unsafe { lib.get::<ExportedV2Fn>(b"exported_v2\0") }
+            .ok()
+            .map(|exported_v2| *exported_v2);
+        let plugin_event = unsafe { lib.get::<EventFn>(b"plugin_event\0") }
+            .ok()
+            .map(|plugin_event| *plugin_event);
+        let plugin_listen = unsafe { lib.get::<ListenFn>(b"plugin_listen\0") }
+            .ok()
+            .map(|plugin_listen| *plugin_listen);
+        let library = Arc::new(lib);
+
+        let manifest_fn: Result<Symbol<ManifestFn>, _> =
+            unsafe { library.get(b"plugin_manifest\0") };
+        if let Ok(manifest_fn) = manifest_fn {
+            let manifest = unsafe { Manifest::from_raw(manifest_fn()) }?;
+
+            if free.is_none() {
+                if manifest.abi_version >= FREE_ABI_VERSION {
+                    return Err("no plugin_free to release its output with".to_string());
+                }
+                warn_leaking(path);
+            }
+
+            if !manifest.events.is_empty() && plugin_event.is_none() {
+                return Err("subscribes to events but has no plugin_event".to_string());
+            }
+
+            if !manifest.listeners.is_empty() && plugin_listen.is_none() {
+                return Err("declares listeners but has no plugin_listen".to_string());
+            }
+
+            return Ok(Self {
+                name: path.to_string(),
+                commands: manifest.commands.clone(),
+                triggers: manifest.triggers.clone(),
+                timers: manifest.timers.clone(),
+                events: manifest.events.clone(),
+                listeners: manifest.listeners.clone(),
+                priority: manifest.priority,
+                command_info: manifest.command_info.clone(),
+                levels: manifest.levels.clone(),
+                manifest: Some(manifest),
+                backend: Backend::Resident {
+                    library: library.clone(),
+                    _copy: copy.clone(),
+                    exported,
+                    free,
+                    exported_v2,
+                    plugin_event,
+                    plugin_listen,
+                },
+                host_handle: 0,
+                loaded_at: Local::now(),
+                hash,
+            });
+        }
+
+        println!("... {} has no plugin_manifest, probing exported", path);
+        if free.is_none() {
+            warn_leaking(path);
+        }
+
+        // Call the `exported` function with an empty command for the
+        // triggers, then `help` and `timers`
+        let probe = |cmd: &str| {
+            let caller = Caller::internal("");
+            let raw = call_exported(EntryPoint::V1(exported), cmd, "", &caller, "", color_ffi);
+            unsafe { take_string(raw, free) }
+        };
+
+        let triggers = match probe("") {
+            Some(triggers) => triggers.split("\n").map(|s| s.to_string()).collect(),
+            None => return Err("probing exported for triggers returned nothing".to_string()),
+        };
+
+        let commands = match probe("help") {
+            Some(commands) => commands.split("\n").map(|s| s.to_string()).collect(),
+            None => return Err("probing exported for help returned nothing".to_string()),
+        };
+
+        let timers = match probe("timers") {
+            Some(timers_str) => parse_timer_declarations(&timers_str),
+            None => vec![],
+        };
+
+        Ok(Self {
+            name: path.to_string(),
+            commands,
+            triggers,
+            timers,
+            events: vec![],
+            listeners: vec![],
+            priority: 0,
+            command_info: vec![],
+            levels: vec![],
+            manifest: None,
+            backend: Backend::Resident {
+                library,
+                _copy: copy,
+                exported,
+                free,
+                exported_v2,
+                plugin_event,
+                plugin_listen,
+            },
+            host_handle: 0,
+            loaded_at: Local::now(),
+            hash,
+        })
+    }
+
+    /// Starts the plugin at `path` in `reinze-plugin-host` child processes,
+    /// up to `concurrency` of them. Returns why if the child can't load it, or
+    /// stops answering for longer than `timeout`.
+    pub fn load_isolated(
+        path: &str,
+        timeout: Duration,
+        concurrency: usize,
+    ) -> Result<Self, String> {
+        let hash = hash_file(path)?;
+        let (host, description) = IsolatedHost::start(path, timeout, concurrency)?;
+
+        Ok(Self {
+            name: path.to_string(),
+            commands: description.commands,
+            triggers: description.triggers,
+            timers: description.timers,
+            events: description.events,
+            listeners: description.listeners,
+            priority: description.priority,
+            command_info: description.command_info,
+            levels: description.levels,
+            manifest: description.manifest,
+            backend: Backend::Isolated(Arc::new(host)),
+            host_handle: 0,
+            loaded_at: Local::now(),
+            hash,
+        })
+    }
+
+    /// Calls the plugin with a command and returns its output lines, or a
+    /// description of why the call failed.
+    pub fn call(
+        &self,
+        cmd: &str,
+        param: &str,
+        caller: &Caller,
+        channel: &str,
+        color: extern "C" fn(*const c_char, *const c_char) -> ColorResult,
+    ) -> Result<Vec<String>, String> {
+        let (entry, free) = match &self.backend {
+            Backend::Resident {
+                exported_v2: Some(exported_v2),
+                free,
+                ..
+            } if self.host_handle != 0 => (
+                EntryPoint::V2(*exported_v2, host::api(self.host_handle), caller.level),
+                *free,
+            ),
+            Backend::Resident { exported, free, .. } => (EntryPoint::V1(*exported), *free),
+            // Host callbacks don't cross the process boundary
+            Backend::Isolated(host) => return host.call(cmd, param, caller, channel),
+        };
+
+        let raw_results = call_exported(entry, cmd, param, caller, channel, color);
+        Ok(unsafe { take_output(raw_results, free) })
+    }
+
+    /// The level needed to run `trigger`, which must be one of the plugin's.
+    pub fn required_level(&self, trigger: &str) -> Level {
+        self.levels
+            .iter()
+            .find(|(declared, _)| declared == trigger)
+            .map(|(_, level)| *level)
+            .unwrap_or_default()
+    }
+
+    /// Whether the plugin asked for its output to be read as JSON items.
+    pub fn structured_output(&self) -> bool {
+        self.manifest.as_ref().is_some_and(|manifest| {
+            manifest
+                .features
+                .iter()
+                .any(|feature| feature == STRUCTURED_OUTPUT)
+        })
+    }
+
+    /// Whether the plugin subscribed to events of this kind.
+    pub fn subscribes(&self, kind: &str) -> bool {
+        self.events.iter().any(|event| event == kind)
+    }
+
+    /// Delivers an event the plugin subscribed to and returns its output
+    /// lines, or a description of why delivery failed.
+    pub fn event(
+        &self,
+        event: &Event,
+        color: extern "C" fn(*const c_char, *const c_char) -> ColorResult,
+    ) -> Result<Vec<String>, String> {
+        let (plugin_event, free) = match &self.backend {
+            Backend::Resident {
+                plugin_event: Some(plugin_event),
+                free,
+                ..
+            } => (*plugin_event, *free),
+            Backend::Resident { .. } => return Ok(vec![]),
+            Backend::Isolated(host) => return host.event(event),
+        };
+
+        let api = host::api(self.host_handle);
+        let raw_results = deliver_event(plugin_event, event, self.api_ptr(&api), color);
+        Ok(unsafe { take_output(raw_results, free) })
+    }
+
+    /// Delivers a listener match: its capture groups (whole match first) and
+    /// the usual caller and channel.
+    pub fn listen(
+        &self,
+        listener: &str,
+        captures: &[String],
+        caller: &Caller,
+        channel: &str,
+        color: extern "C" fn(*const c_char, *const c_char) -> ColorResult,
+    ) -> Result<Vec<String>, String> {
+        let (plugin_listen, free) = match &self.backend {
+            Backend::Resident {
+                plugin_listen: Some(plugin_listen),
+                free,
+                ..
+            } => (*plugin_listen, *free),
+            Backend::Resident { .. } => return Ok(vec![]),
+            Backend::Isolated(host) => {
+                return host.listen(listener, captures, caller, channel);
+            }
+        };
+
+        let (listener, captures, author, channel, tags) = match (
+            CString::new(listener),
+            CString::new(captures.join("\n")),
+            CString::new(caller.author.as_str()),
+            CString::new(channel),
+            CString::new(ircv3::serialize_tags(&caller.tags)),
+        ) {
+            (Ok(listener), Ok(captures), Ok(author), Ok(channel), Ok(tags)) => {
+                (listener, captures, author, channel, tags)
+            }
+            _ => return Ok(vec![]),
+        };
+
+        let api = host::api(self.host_handle);
+        let raw_results = plugin_listen(&ListenerContext {
+            listener: listener.as_ptr(),
+            captures: captures.as_ptr(),
+            author: author.as_ptr(),
+            color,
+            channel: channel.as_ptr(),
+            host: self.api_ptr(&api),
+            level: caller.level as u32,
+            tags: tags.as_ptr(),
+        });
+
+        Ok(unsafe { take_output(raw_results, free) })
+    }
+
+    // Points at `api` if the plugin has host callbacks, else null.
+    fn api_ptr(&self, api: &HostApi) -> *const HostApi {
+        if self.host_handle != 0 {
+            api
+        } else {
+            std::ptr::null()
+        }
+    }
+
     pub fn watch(
-        tx_plugins: std::sync::mpsc::Sender<NotifyResult<Event>>,
+        tx_plugins: std::sync::mpsc::Sender<NotifyResult<NotifyEvent>>,
     ) -> NotifyResult<RecommendedWatcher> {
-        let mut watcher = notify::recommended_watcher(move |event: NotifyResult<Event>| {
+        let mut watcher = notify::recommended_watcher(move |event: NotifyResult<NotifyEvent>| {
             tx_plugins.send(event).unwrap();
         })?;
 
@@ -301,3 +956,429 @@ impl Plugin {
         Ok(watcher)
     }
 }
+
+// Hex SHA-256 of `contents`.
+fn hash(contents: &[u8]) -> String {
+    Sha256::digest(contents)
+        .iter()
+        .map(|byte| format!("{:02x}", byte))
+        .collect()
+}
+
+fn hash_file(path: &str) -> Result<String, String> {
+    fs::read(path)
+        .map(|contents| hash(&contents))
+        .map_err(|e| e.to_string())
+}
+
+// Libraries created, changed or removed by a filesystem event.
+fn changed_libraries(event: NotifyResult<NotifyEvent>) -> BTreeSet<String> {
+    let event = match event {
+        Ok(event) if event.kind.is_create() || event.kind.is_modify() || event.kind.is_remove() => {
+            event
+        }
+        _ => return BTreeSet::new(),
+    };
+
+    event
+        .paths
+        .iter()
+        .filter(|path| is_library(path))
+        .filter_map(|path| path.to_str().map(|path| path.to_string()))
+        .collect()
+}
+
+// Waits for a library to stop changing: the same, non-zero size and
+// modification time on two checks in a row. `false` if it never settles.
+fn wait_until_stable(path: &str) -> bool {
+    let deadline = Instant::now() + STABLE_TIMEOUT;
+    let mut last = None;
+
+    while Instant::now() < deadline {
+        let current = fs::metadata(path)
+            .ok()
+            .map(|metadata| (metadata.len(), metadata.modified().ok()));
+
+        match current {
+            Some((0, _)) | None => (),
+            Some(_) if current == last => return true,
+            Some(_) => (),
+        }
+
+        last = current;
+        thread::sleep(STABLE_INTERVAL);
+    }
+
+    false
+}
+
+/// What changed between two loads of the same plugin, e.g.
+/// `version 1.0 -> 1.1; sha256 1a2b3c4d5e6f -> 6f5e4d3c2b1a; +commands ge`.
+pub fn describe_changes(old: &Plugin, new: &Plugin) -> String {
+    let mut changes = Vec::new();
+
+    let version = |plugin: &Plugin| plugin.manifest.as_ref().map(|m| m.version.clone());
+    if let (Some(old), Some(new)) = (version(old), version(new))
+        && old != new
+    {
+        changes.push(format!("version {} -> {}", old, new));
+    }
+
+    if old.hash != new.hash {
+        changes.push(format!("sha256 {:.12} -> {:.12}", old.hash, new.hash));
+    }
+
+    let timers = |plugin: &Plugin| {
+        plugin
+            .timers
+            .iter()
+            .map(|timer| format!("{}:{:?}", timer.command, timer.interval))
+            .collect::<Vec<String>>()
+    };
+    let listeners = |plugin: &Plugin| {
+        plugin
+            .listeners
+            .iter()
+            .map(|listener| listener.name.clone())
+            .collect::<Vec<String>>()
+    };
+
+    changes.extend(diff("commands", &old.commands, &new.commands));
+    changes.extend(diff("triggers", &old.triggers, &new.triggers));
+    changes.extend(diff("timers", &timers(old), &timers(new)));
+    changes.extend(diff("events", &old.events, &new.events));
+    changes.extend(diff("listeners", &listeners(old), &listeners(new)));
+
+    if old.priority != new.priority {
+        changes.push(format!("priority {} -> {}", old.priority, new.priority));
+    }
+
+    if changes.is_empty() {
+        "no changes".to_string()
+    } else {
+        changes.join("; ")
+    }
+}
+
+// One line per plugin added, removed or changed by a full reload.
+fn describe_reload(old: &[Plugin], new: &[Plugin]) -> Vec<String> {
+    let mut lines = Vec::new();
+
+    for plugin in new {
+        match old.iter().find(|old| old.name == plugin.name) {
+            Some(old) if old.hash == plugin.hash => (),
+            Some(old) => lines.push(format!(
+                "Changed plugin {}: {}",
+                plugin.name,
+                describe_changes(old, plugin)
+            )),
+            None => lines.push(format!("Added plugin {}", plugin.name)),
+        }
+    }
+
+    for plugin in old {
+        if !new.iter().any(|new| new.name == plugin.name) {
+            lines.push(format!("Removed plugin {}", plugin.name));
+        }
+    }
+
+    lines
+}
+
+// `+label added` and `-label removed`, ignoring empty entries and order.
+fn diff(label: &str, old: &[String], new: &[String]) -> Vec<String> {
+    let filter = |items: &[String], others: &[String]| {
+        items
+            .iter()
+            .filter(|item| !item.is_empty() && !others.contains(item))
+            .cloned()
+            .collect::<Vec<String>>()
+    };
+
+    let added = filter(new, old);
+    let removed = filter(old, new);
+
+    let mut changes = Vec::new();
+    if !added.is_empty() {
+        changes.push(format!("+{} {}", label, added.join(", ")));
+    }
+    if !removed.is_empty() {
+        changes.push(format!("-{} {}", label, removed.join(", ")));
+    }
+    changes
+}
+
+fn is_library(path: &Path) -> bool {
+    path.extension()
+        .and_then(|extension| extension.to_str())
+        .is_some_and(|extension| ["so", "dll", "dylib"].contains(&extension))
+}
+
+// Whether `name` is the path, file name or file stem of `path`.
+fn refers_to(path: &str, name: &str) -> bool {
+    let path = Path::new(path);
+    let matches = |part: Option<&std::ffi::OsStr>| part.is_some_and(|part| part == name);
+
+    path == Path::new(name) || matches(path.file_name()) || matches(path.file_stem())
+}
+
+/// Formats a conflict's resolution, e.g. `plugins/a.so (10) > plugins/b.so (0)`.
+pub fn describe_order(plugins: &[(String, i32)]) -> String {
+    plugins
+        .iter()
+        .map(|(name, priority)| format!("{} ({})", name, priority))
+        .collect::<Vec<String>>()
+        .join(" > ")
+}
+
+// Only valid while the library the entry point came from is loaded.
+// Returns the plugin's raw output, or null if an argument contains a NUL.
+// The host's strings only need to outlive the call, so they're dropped here.
+fn call_exported(
+    entry: EntryPoint,
+    cmd: &str,
+    param: &str,
+    caller: &Caller,
+    channel: &str,
+    color: extern "C" fn(*const c_char, *const c_char) -> ColorResult,
+) -> *mut c_char {
+    // Convert the command, query, author, channel and tags to C strings
+    let (cmd, param, author, channel, tags) = match (
+        CString::new(cmd),
+        CString::new(param),
+        CString::new(caller.author.as_str()),
+        CString::new(channel),
+        CString::new(ircv3::serialize_tags(&caller.tags)),
+    ) {
+        (Ok(cmd), Ok(param), Ok(author), Ok(channel), Ok(tags)) => {
+            (cmd, param, author, channel, tags)
+        }
+        _ => return std::ptr::null_mut(),
+    };
+
+    match entry {
+        EntryPoint::V1(exported) => exported(&PluginContext {
+            cmd: cmd.as_ptr(),
+            param: param.as_ptr(),
+            author: author.as_ptr(),
+            color,
+            channel: channel.as_ptr(),
+        }),
+        EntryPoint::V2(exported_v2, api, level) => exported_v2(&PluginContextV2 {
+            cmd: cmd.as_ptr(),
+            param: param.as_ptr(),
+            author: author.as_ptr(),
+            color,
+            channel: channel.as_ptr(),
+            host: &api,
+            level: level as u32,
+            tags: tags.as_ptr(),
+        }),
+    }
+}
+
+// Returns the plugin's raw output like `call_exported`. Only valid while the
+// library `plugin_event` came from is loaded.
+fn deliver_event(
+    plugin_event: EventFn,
+    event: &Event,
+    host: *const HostApi,
+    color: extern "C" fn(*const c_char, *const c_char) -> ColorResult,
+) -> *mut c_char {
+    let (kind, source, channel, args, tags) = match (
+        CString::new(event.kind.as_str()),
+        CString::new(event.source.as_str()),
+        CString::new(event.channel.as_str()),
+        CString::new(event.args.join("\n")),
+        CString::new(ircv3::serialize_tags(&event.tags)),
+    ) {
+        (Ok(kind), Ok(source), Ok(channel), Ok(args), Ok(tags)) => {
+            (kind, source, channel, args, tags)
+        }
+        _ => return std::ptr::null_mut(),
+    };
+
+    plugin_event(&EventContext {
+        event: kind.as_ptr(),
+        source: source.as_ptr(),
+        channel: channel.as_ptr(),
+        args: args.as_ptr(),
+        color,
+        host,
+        tags: tags.as_ptr(),
+    })
+}
+
+fn warn_leaking(path: &str) {
+    println!(
+        "Warning: {} has no plugin_free, so its output will be leaked; rebuild it against ABI v{}",
+        path, FREE_ABI_VERSION
+    );
+}
+
+// Copies a string the plugin returned and hands it back to the plugin's
+// `plugin_free`, or leaks it if the plugin has none. `None` if the plugin
+// returned null or invalid UTF-8.
+//
+// `raw` must be null or a NUL-terminated string `free` accepts.
+unsafe fn take_string(raw: *mut c_char, free: Option<FreeFn>) -> Option<String> {
+    if raw.is_null() {
+        return None;
+    }
+
+    let output = unsafe { CStr::from_ptr(raw) }
+        .to_str()
+        .map(|s| s.to_string())
+        .ok();
+    if let Some(free) = free {
+        free(raw);
+    }
+    output
+}
+
+// Like `take_string`, split into lines.
+unsafe fn take_output(raw: *mut c_char, free: Option<FreeFn>) -> Vec<String> {
+    match unsafe { take_string(raw, free) } {
+        Some(output) => output.split("\n").map(|s| s.to_string()).collect(),
+        None => vec![],
+    }
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use super::*;
+    use std::sync::OnceLock;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    static FREED: AtomicUsize = AtomicUsize::new(0);
+
+    // Stands in for a plugin's `plugin_free`
+    extern "C" fn plugin_free(ptr: *mut c_char) {
+        FREED.fetch_add(1, Ordering::SeqCst);
+        _ = unsafe { CString::from_raw(ptr) };
+    }
+
+    // A probe-only plugin with no `plugin_free`, as plugins were built before
+    // it was asked for
+    const LEGACY_PLUGIN: &str = r#"
+        use std::ffi::{CStr, CString};
+        use std::os::raw::c_char;
+
+        #[repr(C)]
+        pub struct Context {
+            cmd: *const c_char,
+            param: *const c_char,
+            author: *const c_char,
+            color: *const (),
+            channel: *const c_char,
+        }
+
+        #[no_mangle]
+        pub extern "C" fn exported(context: &Context) -> *mut c_char {
+            let cmd = unsafe { CStr::from_ptr(context.cmd) }.to_str().unwrap_or("");
+            let output = match cmd {
+                "" => "^ping$",
+                "help" => "+ping",
+                "timers" => "",
+                _ => "pong",
+            };
+            CString::new(output).unwrap().into_raw()
+        }
+    "#;
+
+    // Compiles `source` into a library in a fresh directory and returns its path
+    fn build_library(name: &str, source: &str) -> String {
+        let directory =
+            std::env::temp_dir().join(format!("reinze-test-{}-{}", std::process::id(), name));
+        fs::create_dir_all(&directory).unwrap();
+        let source_path = directory.join(format!("{}.rs", name));
+        fs::write(&source_path, source).unwrap();
+        let library = directory.join(format!(
+            "{}{}{}",
+            std::env::consts::DLL_PREFIX,
+            name,
+            std::env::consts::DLL_SUFFIX
+        ));
+
+        let rustc = std::env::var("RUSTC").unwrap_or_else(|_| "rustc".to_string());
+        let status = std::process::Command::new(rustc)
+            .args(["--crate-type", "cdylib", "--crate-name", name, "-o"])
+            .arg(&library)
+            .arg(&source_path)
+            .status()
+            .unwrap();
+        assert!(status.success());
+
+        library.to_string_lossy().to_string()
+    }
+
+    /// A loaded plugin to build test entries from, answering every command
+    /// with `pong`. Built once per test run.
+    pub(crate) fn legacy_plugin() -> Plugin {
+        static PATH: OnceLock<String> = OnceLock::new();
+        let path = PATH.get_or_init(|| build_library("legacy", LEGACY_PLUGIN));
+        Plugin::load(path).unwrap()
+    }
+
+    #[test]
+    fn test_loads_legacy_plugin_without_free() {
+        let plugin = legacy_plugin();
+        assert!(matches!(
+            plugin.backend,
+            Backend::Resident { free: None, .. }
+        ));
+        assert_eq!(plugin.triggers, vec!["^ping$"]);
+        assert_eq!(plugin.commands, vec!["+ping"]);
+
+        let output = plugin
+            .call("ping", "", &Caller::internal("a!a@a"), "#rshelp", color_ffi)
+            .unwrap();
+        assert_eq!(output, vec!["pong"]);
+    }
+
+    #[test]
+    fn test_diff() {
+        let old = vec!["+ge".to_string(), "+price".to_string(), "".to_string()];
+        let new = vec!["+price".to_string(), "+stats".to_string()];
+        assert_eq!(
+            diff("commands", &old, &new),
+            vec!["+commands +stats", "-commands +ge"]
+        );
+        assert!(diff("commands", &old, &old).is_empty());
+    }
+
+    #[test]
+    fn test_private_copy_removed_on_drop() {
+        let copy = PrivateCopy::create("plugins/libtest.so", b"library").unwrap();
+        let path = copy.path.clone();
+        assert_eq!(fs::read(&path).unwrap(), b"library");
+        assert!(path.to_string_lossy().ends_with("libtest.so"));
+
+        drop(copy);
+        assert!(!path.exists());
+    }
+
+    #[test]
+    fn test_refers_to() {
+        assert!(refers_to(
+            "plugins/librunescape.so",
+            "plugins/librunescape.so"
+        ));
+        assert!(refers_to("plugins/librunescape.so", "librunescape.so"));
+        assert!(refers_to("plugins/librunescape.so", "librunescape"));
+        assert!(!refers_to("plugins/librunescape.so", "runescape"));
+        assert!(!refers_to("plugins/librunescape.so", "plugins"));
+    }
+
+    #[test]
+    fn test_take_output_frees_through_plugin() {
+        let raw = CString::new("Abyssal whip\n1.2m").unwrap().into_raw();
+        let output = unsafe { take_output(raw, Some(plugin_free)) };
+        assert_eq!(output, vec!["Abyssal whip", "1.2m"]);
+        assert_eq!(FREED.load(Ordering::SeqCst), 1);
+
+        // Null output has nothing to free
+        assert!(unsafe { take_output(std::ptr::null_mut(), Some(plugin_free)) }.is_empty());
+        assert_eq!(FREED.load(Ordering::SeqCst), 1);
+    }
+}
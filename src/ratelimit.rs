@@ -0,0 +1,317 @@
+use crate::settings::Settings;
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+/// What a bucket counts commands against.
+#[derive(Clone, Copy, Debug, Eq, Hash, PartialEq)]
+pub enum Scope {
+    /// One `nick!user@host`.
+    User,
+    /// One channel, whoever sends the commands.
+    Channel,
+    /// One plugin's handling of one command.
+    Command,
+}
+
+/// `burst` commands at once, refilled evenly over `per`.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Limit {
+    pub burst: u32,
+    pub per: Duration,
+}
+
+impl Limit {
+    fn rate(&self) -> f64 {
+        self.burst as f64 / self.per.as_secs_f64().max(f64::EPSILON)
+    }
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub enum Verdict {
+    Allowed,
+    /// Over a limit until `wait` has passed. `warn` is set the first time a
+    /// sender hits a limit, and not again until they're allowed or the wait
+    /// they were told has passed, so they're only told once.
+    Limited {
+        wait: Duration,
+        warn: bool,
+    },
+}
+
+struct Bucket {
+    limit: Limit,
+    tokens: f64,
+    updated: Instant,
+}
+
+impl Bucket {
+    fn refill(&mut self, now: Instant) {
+        let elapsed = now.saturating_duration_since(self.updated);
+        self.tokens =
+            (self.tokens + elapsed.as_secs_f64() * self.limit.rate()).min(self.limit.burst as f64);
+        self.updated = now;
+    }
+}
+
+#[derive(Default)]
+struct State {
+    buckets: HashMap<(Scope, String), Bucket>,
+    // Senders who have been told they're limited, and when the wait they
+    // were told ends
+    warned: HashMap<String, Instant>,
+}
+
+/// Token buckets for commands typed by users. Timers and scheduled calls
+/// don't go through here, and admins skip it; the caller checks.
+pub struct RateLimiter {
+    settings: Arc<Settings>,
+    state: Mutex<State>,
+}
+
+impl RateLimiter {
+    pub fn new(settings: Arc<Settings>) -> Self {
+        Self {
+            settings,
+            state: Mutex::new(State::default()),
+        }
+    }
+
+    /// Takes a token from every bucket in `keys` for a command from
+    /// `hostmask`, or from none of them if any is empty. `plugin` picks the
+    /// plugin's own `Scope::Command` limit, if it has one.
+    pub fn check(&self, hostmask: &str, plugin: Option<&str>, keys: &[(Scope, &str)]) -> Verdict {
+        self.check_at(Instant::now(), hostmask, plugin, keys)
+    }
+
+    fn check_at(
+        &self,
+        now: Instant,
+        hostmask: &str,
+        plugin: Option<&str>,
+        keys: &[(Scope, &str)],
+    ) -> Verdict {
+        let limits = keys
+            .iter()
+            .filter_map(|&(scope, key)| {
+                let limit = self.limit(scope, plugin)?;
+                Some(((scope, key.to_lowercase()), limit))
+            })
+            .collect::<Vec<((Scope, String), Limit)>>();
+
+        let mut state = match self.state.lock() {
+            Ok(state) => state,
+            Err(_) => return Verdict::Allowed,
+        };
+
+        // A full bucket is the same as none, so they don't pile up
+        state.buckets.retain(|_, bucket| {
+            bucket.refill(now);
+            bucket.tokens < bucket.limit.burst as f64
+        });
+        // Likewise warnings, once the wait is over
+        state.warned.retain(|_, until| *until > now);
+
+        let mut wait = Duration::ZERO;
+        for (key, limit) in &limits {
+            let bucket = state.buckets.entry(key.clone()).or_insert(Bucket {
+                limit: *limit,
+                tokens: limit.burst as f64,
+                updated: now,
+            });
+            // Settings may have changed since the bucket was made
+            bucket.limit = *limit;
+
+            if bucket.tokens < 1.0 {
+                let missing = Duration::from_secs_f64((1.0 - bucket.tokens) / limit.rate());
+                wait = wait.max(missing);
+            }
+        }
+
+        let hostmask = hostmask.to_lowercase();
+        if !wait.is_zero() {
+            let warn = !state.warned.contains_key(&hostmask);
+            if warn {
+                state.warned.insert(hostmask, now + wait);
+            }
+            return Verdict::Limited { wait, warn };
+        }
+
+        for (key, _) in &limits {
+            if let Some(bucket) = state.buckets.get_mut(key) {
+                bucket.tokens -= 1.0;
+            }
+        }
+        state.warned.remove(&hostmask);
+
+        Verdict::Allowed
+    }
+
+    // The limit for a scope, `None` if it's unlimited
+    fn limit(&self, scope: Scope, plugin: Option<&str>) -> Option<Limit> {
+        let limits = &self.settings.rate_limit;
+        match scope {
+            Scope::User => limits.user(),
+            Scope::Channel => limits.channel(),
+            Scope::Command => match plugin.and_then(|path| self.settings.plugin(path).rate_limit) {
+                Some(limit) => limit.limit(),
+                None => limits.command(),
+            },
+        }
+    }
+}
+
+/// The key of a plugin's `Scope::Command` bucket for `cmd`.
+pub fn command_key(plugin: &str, cmd: &str) -> String {
+    format!("{} {}", plugin, cmd)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn limiter(toml: &str) -> RateLimiter {
+        RateLimiter::new(Arc::new(Settings::parse(toml).unwrap()))
+    }
+
+    // The wait in whole seconds, and whether to warn
+    fn limited(verdict: Verdict) -> Option<(u64, bool)> {
+        match verdict {
+            Verdict::Allowed => None,
+            Verdict::Limited { wait, warn } => Some((wait.as_secs_f64().round() as u64, warn)),
+        }
+    }
+
+    #[test]
+    fn test_burst_then_refill() {
+        let limiter = limiter(
+            r#"
+            [rate_limit.user]
+            burst = 2
+            per = "10s"
+            "#,
+        );
+        let start = Instant::now();
+        let keys = [(Scope::User, "zezima!z@example.com")];
+
+        assert_eq!(
+            limiter.check_at(start, "zezima!z@example.com", None, &keys),
+            Verdict::Allowed
+        );
+        assert_eq!(
+            limiter.check_at(start, "zezima!z@example.com", None, &keys),
+            Verdict::Allowed
+        );
+        assert_eq!(
+            limited(limiter.check_at(start, "zezima!z@example.com", None, &keys)),
+            Some((5, true))
+        );
+        // Only warned once
+        assert_eq!(
+            limited(limiter.check_at(start, "zezima!z@example.com", None, &keys)),
+            Some((5, false))
+        );
+
+        let later = start + Duration::from_secs(6);
+        assert_eq!(
+            limiter.check_at(later, "zezima!z@example.com", None, &keys),
+            Verdict::Allowed
+        );
+    }
+
+    #[test]
+    fn test_warnings_expire() {
+        let limiter = limiter(
+            r#"
+            [rate_limit.user]
+            burst = 1
+            per = "10s"
+            "#,
+        );
+        let start = Instant::now();
+        let keys = [(Scope::User, "zezima!z@e")];
+
+        limiter.check_at(start, "zezima!z@e", None, &keys);
+        assert_eq!(
+            limited(limiter.check_at(start, "zezima!z@e", None, &keys)),
+            Some((10, true))
+        );
+        assert_eq!(limiter.state.lock().unwrap().warned.len(), 1);
+
+        // Someone else's command sweeps the warning once its wait is over,
+        // though the sender never came back
+        let later = start + Duration::from_secs(11);
+        limiter.check_at(later, "b!b@b", None, &[(Scope::User, "b!b@b")]);
+        assert!(limiter.state.lock().unwrap().warned.is_empty());
+    }
+
+    #[test]
+    fn test_limited_takes_nothing() {
+        let limiter = limiter(
+            r#"
+            [rate_limit.user]
+            burst = 2
+            per = "10s"
+
+            [rate_limit.channel]
+            burst = 1
+            per = "10s"
+            "#,
+        );
+        let start = Instant::now();
+        let keys = [(Scope::User, "zezima!z@e"), (Scope::Channel, "#rshelp")];
+
+        assert_eq!(
+            limiter.check_at(start, "zezima!z@e", None, &keys),
+            Verdict::Allowed
+        );
+        // The channel is empty, so the user keeps their second token
+        assert!(matches!(
+            limiter.check_at(start, "zezima!z@e", None, &keys),
+            Verdict::Limited { .. }
+        ));
+        assert_eq!(
+            limiter.check_at(start, "zezima!z@e", None, &keys[..1]),
+            Verdict::Allowed
+        );
+    }
+
+    #[test]
+    fn test_plugin_command_limit() {
+        let limiter = limiter(
+            r#"
+            [rate_limit.user]
+            burst = 0
+
+            [plugins.libprices.rate_limit]
+            burst = 1
+            per = "1m"
+            "#,
+        );
+        let start = Instant::now();
+        let prices = command_key("plugins/libprices.so", "price");
+        let keys = [(Scope::Command, prices.as_str())];
+
+        assert_eq!(
+            limiter.check_at(start, "a!a@a", Some("plugins/libprices.so"), &keys),
+            Verdict::Allowed
+        );
+        assert!(matches!(
+            limiter.check_at(start, "b!b@b", Some("plugins/libprices.so"), &keys),
+            Verdict::Limited { .. }
+        ));
+        // No limit for plugins without one
+        let other = command_key("plugins/libother.so", "price");
+        for _ in 0..10 {
+            assert_eq!(
+                limiter.check_at(
+                    start,
+                    "a!a@a",
+                    Some("plugins/libother.so"),
+                    &[(Scope::Command, other.as_str())]
+                ),
+                Verdict::Allowed
+            );
+        }
+    }
+}
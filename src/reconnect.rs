@@ -0,0 +1,424 @@
+use crate::ignores::describe_duration;
+use crate::settings::{ReconnectSettings, ServerSettings};
+use chrono::{DateTime, Local};
+use irc::client::prelude::Config;
+use std::collections::VecDeque;
+use std::collections::hash_map::RandomState;
+use std::fmt;
+use std::hash::BuildHasher;
+use std::sync::Mutex;
+use std::time::Duration;
+
+// How many finished connections `Connections` remembers
+const MAX_ATTEMPTS: usize = 10;
+
+// Words servers use when closing the link on someone who isn't welcome
+const BANNED: [&str; 4] = ["k-line", "g-line", "z-line", "banned"];
+
+/// How a connection ended.
+#[derive(Clone, Debug, PartialEq)]
+pub enum Outcome {
+    /// Registered, then lost the connection.
+    Disconnected(String),
+    /// The server won't have us: a ban or a bad password. Trying it again
+    /// soon won't help.
+    Refused(String),
+    /// Never got as far as registering, for a reason that may pass.
+    Failed(String),
+}
+
+impl Outcome {
+    /// Sorts the reason in an `ERROR` from the server into a refusal or a
+    /// passing failure, given whether we'd registered.
+    pub fn from_error(reason: &str, registered: bool) -> Self {
+        let lower = reason.to_lowercase();
+        if BANNED.iter().any(|word| lower.contains(word)) {
+            Self::Refused(reason.to_string())
+        } else if registered {
+            Self::Disconnected(reason.to_string())
+        } else {
+            Self::Failed(reason.to_string())
+        }
+    }
+
+    fn name(&self) -> &'static str {
+        match self {
+            Self::Disconnected(_) => "disconnected",
+            Self::Refused(_) => "refused",
+            Self::Failed(_) => "failed",
+        }
+    }
+
+    fn reason(&self) -> &str {
+        match self {
+            Self::Disconnected(reason) | Self::Refused(reason) | Self::Failed(reason) => reason,
+        }
+    }
+}
+
+/// One server to connect to, with whatever it overrides from the network's
+/// own `server`, `port`, `use_tls` and `password`.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Server {
+    pub host: String,
+    pub port: Option<u16>,
+    pub use_tls: Option<bool>,
+    pub password: Option<String>,
+}
+
+impl Server {
+    /// The network's config pointed at this server.
+    pub fn apply(&self, config: &Config) -> Config {
+        let mut config = config.clone();
+        config.server = Some(self.host.clone());
+        config.port = self.port.or(config.port);
+        config.use_tls = self.use_tls.or(config.use_tls);
+        if self.password.is_some() {
+            config.password = self.password.clone();
+        }
+        config
+    }
+}
+
+impl fmt::Display for Server {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.host)?;
+        if let Some(port) = self.port {
+            write!(f, ":{}", port)?;
+        }
+        Ok(())
+    }
+}
+
+/// The servers to try: `[[servers]]` if there are any, else the network's
+/// own `server`.
+pub fn servers(settings: &[ServerSettings], config: &Config) -> Vec<Server> {
+    if settings.is_empty() {
+        return vec![Server {
+            host: config.server.clone().unwrap_or_default(),
+            port: None,
+            use_tls: None,
+            password: None,
+        }];
+    }
+
+    settings
+        .iter()
+        .map(|server| Server {
+            host: server.server.clone(),
+            port: server.port,
+            use_tls: server.use_tls,
+            password: server.password.clone(),
+        })
+        .collect()
+}
+
+/// Which server to connect to next and how long to wait first.
+pub struct Reconnect {
+    servers: Vec<Server>,
+    current: usize,
+    // Failed connections in a row, for the backoff
+    failures: u32,
+    // Servers that refused us in a row
+    refusals: usize,
+    initial: Duration,
+    max: Duration,
+    stable: Duration,
+}
+
+impl Reconnect {
+    pub fn new(settings: &ReconnectSettings, servers: Vec<Server>) -> Self {
+        Self {
+            servers,
+            current: 0,
+            failures: 0,
+            refusals: 0,
+            initial: settings.initial(),
+            max: settings.max(),
+            stable: settings.stable(),
+        }
+    }
+
+    pub fn server(&self) -> &Server {
+        &self.servers[self.current]
+    }
+
+    /// Takes how the last connection ended and how long it lasted, moves on
+    /// to another server if it failed, and returns how long to wait before
+    /// the next one.
+    pub fn next(&mut self, outcome: &Outcome, lasted: Duration) -> Duration {
+        self.next_with(outcome, lasted, jitter())
+    }
+
+    fn next_with(&mut self, outcome: &Outcome, lasted: Duration, jitter: f64) -> Duration {
+        match outcome {
+            // A connection that held up for a while starts the backoff over,
+            // on the same server
+            Outcome::Disconnected(_) if lasted >= self.stable => {
+                self.failures = 0;
+                self.refusals = 0;
+            }
+            Outcome::Disconnected(_) => self.failures += 1,
+            Outcome::Refused(_) => {
+                self.refusals += 1;
+                self.failures += 1;
+                self.rotate();
+            }
+            Outcome::Failed(_) => {
+                self.refusals = 0;
+                self.failures += 1;
+                self.rotate();
+            }
+        }
+
+        // Every server turned us away, so there's no hurry
+        if self.refusals >= self.servers.len() {
+            return self.max;
+        }
+
+        if self.failures == 0 {
+            return self.initial.mul_f64(jitter);
+        }
+
+        let doubled = self
+            .initial
+            .saturating_mul(2u32.saturating_pow(self.failures - 1));
+        doubled.min(self.max).mul_f64(jitter)
+    }
+
+    fn rotate(&mut self) {
+        self.current = (self.current + 1) % self.servers.len();
+    }
+}
+
+// A factor between 0.5 and 1, so networks that dropped together don't all
+// come back at the same moment
+fn jitter() -> f64 {
+    let random = RandomState::new().hash_one(0u8);
+    0.5 + (random % 1000) as f64 / 2000.0
+}
+
+/// A finished connection, for `+servers` and the logs.
+#[derive(Clone, Debug)]
+pub struct Attempt {
+    pub server: String,
+    pub started: DateTime<Local>,
+    pub lasted: Duration,
+    pub outcome: Outcome,
+}
+
+impl fmt::Display for Attempt {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{} at {} after {}: {}",
+            self.outcome.name(),
+            self.started.format("%Y-%m-%d %H:%M:%S"),
+            describe_duration(self.lasted),
+            self.outcome.reason()
+        )
+    }
+}
+
+/// Where the network is connected now, the lag to it, and how the last few
+/// connections ended. Outlives reconnects.
+#[derive(Default)]
+pub struct Connections {
+    current: Mutex<Option<(String, DateTime<Local>)>>,
+    lag: Mutex<Option<Duration>>,
+    attempts: Mutex<VecDeque<Attempt>>,
+}
+
+impl Connections {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Registered with `server`.
+    pub fn connected(&self, server: &str) {
+        if let Ok(mut current) = self.current.lock() {
+            *current = Some((server.to_string(), Local::now()));
+        }
+    }
+
+    pub fn record(&self, attempt: Attempt) {
+        if let Ok(mut current) = self.current.lock() {
+            *current = None;
+        }
+        if let Ok(mut lag) = self.lag.lock() {
+            *lag = None;
+        }
+
+        if let Ok(mut attempts) = self.attempts.lock() {
+            if attempts.len() == MAX_ATTEMPTS {
+                attempts.pop_back();
+            }
+            attempts.push_front(attempt);
+        }
+    }
+
+    /// The server we're registered with and since when, if any.
+    pub fn current(&self) -> Option<(String, DateTime<Local>)> {
+        self.current.lock().ok().and_then(|current| current.clone())
+    }
+
+    /// The round trip of the last PING the watchdog measured.
+    pub fn set_lag(&self, measured: Duration) {
+        if let Ok(mut lag) = self.lag.lock() {
+            *lag = Some(measured);
+        }
+    }
+
+    /// The lag to the server we're connected to, once measured.
+    pub fn lag(&self) -> Option<Duration> {
+        self.lag.lock().ok().and_then(|lag| *lag)
+    }
+
+    /// Finished connections, newest first.
+    pub fn recent(&self) -> Vec<Attempt> {
+        match self.attempts.lock() {
+            Ok(attempts) => attempts.iter().cloned().collect(),
+            Err(_) => vec![],
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::settings::Settings;
+
+    fn reconnect(count: usize) -> Reconnect {
+        let settings = Settings::parse(
+            r#"
+            [reconnect]
+            initial = "2s"
+            max = "1m"
+            stable = "5m"
+            "#,
+        )
+        .unwrap();
+
+        let servers = (0..count)
+            .map(|n| Server {
+                host: format!("irc{}.example", n),
+                port: None,
+                use_tls: None,
+                password: None,
+            })
+            .collect();
+        Reconnect::new(&settings.reconnect, servers)
+    }
+
+    fn failed() -> Outcome {
+        Outcome::Failed("Connection refused".to_string())
+    }
+
+    #[test]
+    fn test_backoff_doubles_to_cap() {
+        let mut reconnect = reconnect(1);
+        let waits = (0..7)
+            .map(|_| {
+                reconnect
+                    .next_with(&failed(), Duration::ZERO, 1.0)
+                    .as_secs()
+            })
+            .collect::<Vec<u64>>();
+        assert_eq!(waits, vec![2, 4, 8, 16, 32, 60, 60]);
+
+        // Half the wait at the least
+        assert_eq!(
+            reconnect.next_with(&failed(), Duration::ZERO, 0.5),
+            Duration::from_secs(30)
+        );
+        assert!((0.5..=1.0).contains(&jitter()));
+    }
+
+    #[test]
+    fn test_stable_session_resets() {
+        let mut reconnect = reconnect(2);
+        reconnect.next_with(&failed(), Duration::ZERO, 1.0);
+        reconnect.next_with(&failed(), Duration::ZERO, 1.0);
+        assert_eq!(reconnect.server().host, "irc0.example");
+
+        let dropped = Outcome::Disconnected("Ping timeout".to_string());
+        let wait = reconnect.next_with(&dropped, Duration::from_secs(600), 1.0);
+        assert_eq!(wait, Duration::from_secs(2));
+        // Back to the server that was working
+        assert_eq!(reconnect.server().host, "irc0.example");
+
+        // A short session doesn't
+        let wait = reconnect.next_with(&dropped, Duration::from_secs(10), 1.0);
+        assert_eq!(wait, Duration::from_secs(2));
+        let wait = reconnect.next_with(&dropped, Duration::from_secs(10), 1.0);
+        assert_eq!(wait, Duration::from_secs(4));
+    }
+
+    #[test]
+    fn test_refusals_rotate_then_wait_longest() {
+        let mut reconnect = reconnect(2);
+        let banned = Outcome::from_error("Closing Link: (K-lined: spam)", false);
+        assert!(matches!(banned, Outcome::Refused(_)));
+
+        assert_eq!(
+            reconnect.next_with(&banned, Duration::ZERO, 1.0),
+            Duration::from_secs(2)
+        );
+        assert_eq!(reconnect.server().host, "irc1.example");
+
+        // Both servers have now refused us
+        assert_eq!(
+            reconnect.next_with(&banned, Duration::ZERO, 1.0),
+            Duration::from_secs(60)
+        );
+        assert_eq!(reconnect.server().host, "irc0.example");
+    }
+
+    #[test]
+    fn test_outcome_from_error() {
+        assert_eq!(
+            Outcome::from_error("Closing Link: (Ping timeout)", true),
+            Outcome::Disconnected("Closing Link: (Ping timeout)".to_string())
+        );
+        assert_eq!(
+            Outcome::from_error("Closing Link: (Registration timed out)", false),
+            Outcome::Failed("Closing Link: (Registration timed out)".to_string())
+        );
+        assert!(matches!(
+            Outcome::from_error("You are banned from this server", true),
+            Outcome::Refused(_)
+        ));
+    }
+
+    #[test]
+    fn test_connections_keep_the_latest() {
+        let connections = Connections::new();
+        connections.connected("irc0.example");
+        assert_eq!(connections.current().unwrap().0, "irc0.example");
+
+        for n in 0..11 {
+            connections.record(Attempt {
+                server: format!("irc{}.example", n),
+                started: Local::now(),
+                lasted: Duration::from_secs(n),
+                outcome: failed(),
+            });
+        }
+
+        connections.set_lag(Duration::from_millis(80));
+        assert_eq!(connections.lag(), Some(Duration::from_millis(80)));
+
+        connections.record(Attempt {
+            server: "irc11.example".to_string(),
+            started: Local::now(),
+            lasted: Duration::from_secs(11),
+            outcome: failed(),
+        });
+
+        let recent = connections.recent();
+        assert!(connections.current().is_none());
+        assert!(connections.lag().is_none());
+        assert_eq!(recent.len(), MAX_ATTEMPTS);
+        assert_eq!(recent[0].server, "irc11.example");
+    }
+}
@@ -0,0 +1,171 @@
+use serde::Deserialize;
+
+/// How an output line is delivered.
+#[derive(Clone, Copy, Debug, Deserialize, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum Kind {
+    Privmsg,
+    Notice,
+    /// A CTCP ACTION, i.e. `/me`.
+    Action,
+    /// A complete IRC line sent as-is, e.g. `TOPIC #channel :...`. Only the
+    /// commands in `RAW_COMMANDS` may be sent this way.
+    Raw,
+}
+
+/// Commands a raw line may use. Anything that could act with the bot's
+/// privileges, like MODE, KICK or QUIT, is left out.
+pub const RAW_COMMANDS: &[&str] = &["TOPIC", "INVITE", "WHO", "WHOIS", "NAMES"];
+
+/// Whether a raw line starts with an allowed command. Lines with a prefix or
+/// tags aren't allowed either.
+pub fn raw_allowed(line: &str) -> bool {
+    line.split_whitespace()
+        .next()
+        .is_some_and(|command| RAW_COMMANDS.contains(&command.to_uppercase().as_str()))
+}
+
+/// One structured output line from a plugin, written as a JSON object such as
+/// `{"target": "nick", "kind": "notice", "text": "..."}`. Only plugins listing
+/// the `structured-output` feature get their lines read this way.
+///
+/// `target` and `kind` default to where and how a plain-text reply would have
+/// gone, so a plugin only has to spell out what it wants to change.
+#[derive(Clone, Debug, Deserialize, PartialEq)]
+#[serde(deny_unknown_fields)]
+pub struct Item {
+    pub target: Option<String>,
+    pub kind: Option<Kind>,
+    /// May be left out on a line that only sets `handled`.
+    #[serde(default)]
+    pub text: String,
+    /// Whether the text may be split across several messages when too long.
+    #[serde(default = "split_default")]
+    pub split: bool,
+    /// Marks the command as handled, so lower-priority plugins whose triggers
+    /// also matched it aren't run.
+    #[serde(default)]
+    pub handled: bool,
+}
+
+fn split_default() -> bool {
+    true
+}
+
+impl Item {
+    /// A plain-text line, delivered exactly like legacy output. Line breaks
+    /// become spaces, so the text can't smuggle a second IRC line out.
+    pub fn plain(text: &str) -> Self {
+        Self {
+            target: None,
+            kind: None,
+            text: flatten(text),
+            split: true,
+            handled: false,
+        }
+    }
+
+    /// Reads one line of plugin output. Unless `structured`, or if the line
+    /// isn't a JSON object with the fields above, it's plain text, so a
+    /// plugin echoing what a user typed can't be made to send anything else.
+    /// Line breaks in a JSON item's target and text become spaces too.
+    pub fn parse(line: &str, structured: bool) -> Self {
+        let trimmed = line.trim();
+        if !structured || !trimmed.starts_with('{') {
+            return Self::plain(line);
+        }
+
+        match serde_json::from_str::<Self>(trimmed) {
+            Ok(item) => Self {
+                target: item.target.as_deref().map(flatten),
+                text: flatten(&item.text),
+                ..item
+            },
+            Err(_) => Self::plain(line),
+        }
+    }
+}
+
+fn flatten(text: &str) -> String {
+    text.replace(['\r', '\n'], " ")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_plain_text() {
+        let item = Item::parse("Abyssal whip: 1.2m", true);
+        assert_eq!(item, Item::plain("Abyssal whip: 1.2m"));
+    }
+
+    #[test]
+    fn test_structured() {
+        let item = Item::parse(
+            r##"{"target": "#rshelp", "kind": "action", "text": "waves"}"##,
+            true,
+        );
+        assert_eq!(item.target.as_deref(), Some("#rshelp"));
+        assert_eq!(item.kind, Some(Kind::Action));
+        assert_eq!(item.text, "waves");
+        assert!(item.split);
+    }
+
+    #[test]
+    fn test_structured_defaults() {
+        let item = Item::parse(r#"{"text": "hi", "split": false}"#, true);
+        assert_eq!(item.target, None);
+        assert_eq!(item.kind, None);
+        assert!(!item.split);
+        assert!(!item.handled);
+    }
+
+    #[test]
+    fn test_handled_only() {
+        let item = Item::parse(r#"{"handled": true}"#, true);
+        assert!(item.handled);
+        assert!(item.text.is_empty());
+    }
+
+    #[test]
+    fn test_line_breaks_flattened() {
+        let item = Item::parse(
+            r##"{"target": "#a\r\nJOIN #b", "text": "hi\r\nQUIT :bye"}"##,
+            true,
+        );
+        assert_eq!(item.target.as_deref(), Some("#a  JOIN #b"));
+        assert_eq!(item.text, "hi  QUIT :bye");
+        assert_eq!(Item::parse("hi\rthere", true).text, "hi there");
+        assert_eq!(Item::plain("hi\nthere").text, "hi there");
+    }
+
+    #[test]
+    fn test_braces_that_arent_items() {
+        assert_eq!(Item::parse("{not json}", true), Item::plain("{not json}"));
+        assert_eq!(
+            Item::parse(r#"{"text": "hi", "colour": "red"}"#, true),
+            Item::plain(r#"{"text": "hi", "colour": "red"}"#)
+        );
+        assert_eq!(
+            Item::parse(r#"{"kind": "shout", "text": "hi"}"#, true),
+            Item::plain(r#"{"kind": "shout", "text": "hi"}"#)
+        );
+    }
+
+    #[test]
+    fn test_unstructured_plugins_send_plain_text() {
+        let line = r#"{"kind": "raw", "text": "QUIT :bye"}"#;
+        assert_eq!(Item::parse(line, false), Item::plain(line));
+    }
+
+    #[test]
+    fn test_raw_allowed() {
+        assert!(raw_allowed("TOPIC #rshelp :Grand Exchange prices"));
+        assert!(raw_allowed("invite Zezima #rshelp"));
+        assert!(!raw_allowed("QUIT :bye"));
+        assert!(!raw_allowed("PRIVMSG NickServ :DROP"));
+        assert!(!raw_allowed(":kick!k@host KICK #rshelp Zezima"));
+        assert!(!raw_allowed(""));
+    }
+}
@@ -0,0 +1,513 @@
+use crate::settings::{Recover, ServicesSettings};
+use irc::client::prelude::{Command, Config, Message, Response};
+use std::time::{Duration, Instant};
+
+// How soon to try for the nick again after asking services to free it
+const RECOVER_DELAY: Duration = Duration::from_secs(5);
+
+// What NickServ says once we're identified, lowercased (Atheme, Anope)
+const IDENTIFIED: [&str; 3] = [
+    "you are now identified",
+    "you are now logged in",
+    "password accepted",
+];
+
+// What it says when the password is wrong
+const REFUSED: [&str; 2] = ["invalid password", "password incorrect"];
+
+/// Nick and services handling for one connection: alternate nicks while
+/// registering, identifying to NickServ, getting the nick back, and joining
+/// channels once identified. Feed it every message from the server and call
+/// `tick` every few seconds, sending whatever either returns.
+///
+/// The client is given no channels, alternate nicks or NickServ password, so
+/// all of this happens here rather than in the `irc` crate.
+pub struct Services {
+    nick: String,
+    alternates: Vec<String>,
+    // How many nicks we've tried while registering, after `nick`
+    attempts: usize,
+    nickserv: String,
+    account: String,
+    password: Option<String>,
+    identify: String,
+    recover: Recover,
+    reclaim_interval: Duration,
+    delay_joins: bool,
+    join_timeout: Duration,
+    // Channels and their keys
+    channels: Vec<(String, Option<String>)>,
+    registered: bool,
+    identified: bool,
+    // When to join anyway if identification hasn't finished, while joins are
+    // held
+    join_deadline: Option<Instant>,
+    joined: bool,
+    next_reclaim: Option<Instant>,
+    last_recover: Option<Instant>,
+}
+
+impl Services {
+    pub fn new(settings: &ServicesSettings, config: &Config) -> Self {
+        let nick = config.nickname().unwrap_or_default().to_string();
+        let alternates = if settings.alt_nicks.is_empty() {
+            config.alternate_nicknames().to_vec()
+        } else {
+            settings.alt_nicks.clone()
+        };
+        let password = settings
+            .password
+            .clone()
+            .or_else(|| Some(config.nick_password().to_string()))
+            .filter(|password| !password.is_empty());
+        let recover = match settings.recover {
+            Recover::None if config.should_ghost() => Recover::Ghost,
+            recover => recover,
+        };
+        let channels = config
+            .channels()
+            .iter()
+            .map(|channel| {
+                let key = config.channel_key(channel).map(|key| key.to_string());
+                (channel.clone(), key)
+            })
+            .collect();
+
+        Self {
+            account: settings.account.clone().unwrap_or_else(|| nick.clone()),
+            nick,
+            alternates,
+            attempts: 0,
+            nickserv: settings.nickserv().to_string(),
+            password,
+            identify: settings.identify().to_string(),
+            recover,
+            reclaim_interval: settings.reclaim_interval(),
+            delay_joins: settings.delay_joins,
+            join_timeout: settings.join_timeout(),
+            channels,
+            registered: false,
+            identified: false,
+            join_deadline: None,
+            joined: false,
+            next_reclaim: None,
+            last_recover: None,
+        }
+    }
+
+    /// The client's config with everything this and the watchdog handle
+    /// taken out.
+    pub fn client_config(config: &Config) -> Config {
+        let mut config = config.clone();
+        config.channels.clear();
+        config.alt_nicks.clear();
+        config.nick_password = None;
+        config.should_ghost = false;
+        // The crate can't be told not to PING, only to wait longer than any
+        // connection lasts
+        config.ping_time = Some(u32::MAX);
+        config.ping_timeout = Some(u32::MAX);
+        config
+    }
+
+    /// Takes the next message from the server, given the nick we're on,
+    /// returning what to send back.
+    pub fn handle(&mut self, message: &Message, current: &str) -> Vec<Command> {
+        self.handle_at(Instant::now(), message, current)
+    }
+
+    fn handle_at(&mut self, now: Instant, message: &Message, current: &str) -> Vec<Command> {
+        let source = message.source_nickname().unwrap_or_default();
+
+        match &message.command {
+            Command::Response(Response::RPL_WELCOME, _) => {
+                self.registered = true;
+                // Go for the nick on the next tick if we didn't get it
+                self.next_reclaim = Some(now);
+                vec![]
+            }
+            Command::Response(
+                Response::ERR_NICKNAMEINUSE
+                | Response::ERR_ERRONEOUSNICKNAME
+                | Response::ERR_UNAVAILRESOURCE,
+                _,
+            ) => self.rejected(),
+            Command::Response(Response::RPL_ENDOFMOTD | Response::ERR_NOMOTD, _) => {
+                self.after_motd(now)
+            }
+            Command::Response(Response::RPL_LOGGEDIN, _) => self.identified(),
+            Command::Response(Response::RPL_ISON, args) => {
+                let online = args.last().is_some_and(|nicks| {
+                    nicks
+                        .split_whitespace()
+                        .any(|nick| nick.eq_ignore_ascii_case(&self.nick))
+                });
+
+                self.reclaim(now, current, online)
+            }
+            Command::NOTICE(_, text) if source.eq_ignore_ascii_case(&self.nickserv) => {
+                let text = text.to_lowercase();
+                if IDENTIFIED.iter().any(|phrase| text.contains(phrase)) {
+                    self.identified()
+                } else if REFUSED.iter().any(|phrase| text.contains(phrase)) {
+                    println!(
+                        "{} refused the password for {}",
+                        self.nickserv, self.account
+                    );
+                    // Nothing to wait for
+                    self.join()
+                } else {
+                    vec![]
+                }
+            }
+            // Whoever had the nick let go of it
+            Command::QUIT(_) | Command::NICK(_)
+                if self.registered
+                    && source.eq_ignore_ascii_case(&self.nick)
+                    && !current.eq_ignore_ascii_case(&self.nick) =>
+            {
+                vec![Command::NICK(self.nick.clone())]
+            }
+            _ => vec![],
+        }
+    }
+
+    /// The server refused the nick we asked for. While registering, moves on
+    /// to the next alternate; after that the next reclaim tries again.
+    pub fn rejected(&mut self) -> Vec<Command> {
+        if self.registered {
+            return vec![];
+        }
+
+        let next = match self.alternates.get(self.attempts) {
+            Some(alternate) => alternate.clone(),
+            None => format!("{}{}", self.nick, self.attempts + 1 - self.alternates.len()),
+        };
+        self.attempts += 1;
+
+        println!("Nick taken, trying {}", next);
+        vec![Command::NICK(next)]
+    }
+
+    /// What to send as time passes: held joins that waited too long, and
+    /// attempts to get the nick back.
+    pub fn tick(&mut self, current: &str) -> Vec<Command> {
+        self.tick_at(Instant::now(), current)
+    }
+
+    fn tick_at(&mut self, now: Instant, current: &str) -> Vec<Command> {
+        let mut commands = vec![];
+
+        if self.join_deadline.is_some_and(|deadline| now >= deadline) {
+            println!(
+                "Not identified after {:?}, joining anyway",
+                self.join_timeout
+            );
+            commands.extend(self.join());
+        }
+
+        if self.registered
+            && !current.eq_ignore_ascii_case(&self.nick)
+            && self.next_reclaim.is_some_and(|next| now >= next)
+        {
+            // Ask first, since a refused NICK would count against the client
+            self.next_reclaim = Some(now + self.reclaim_interval);
+            commands.push(Command::ISON(vec![self.nick.clone()]));
+        }
+
+        commands
+    }
+
+    // Identifies and joins (or holds the joins) once registration is over
+    fn after_motd(&mut self, now: Instant) -> Vec<Command> {
+        if self.joined || self.join_deadline.is_some() {
+            return vec![];
+        }
+
+        let mut commands = vec![];
+        if let Some(password) = &self.password
+            && !self.identified
+        {
+            let text = self
+                .identify
+                .replace("{account}", &self.account)
+                .replace("{password}", password);
+            commands.push(Command::PRIVMSG(self.nickserv.clone(), text));
+
+            if self.delay_joins {
+                self.join_deadline = Some(now + self.join_timeout);
+                return commands;
+            }
+        }
+
+        commands.extend(self.join());
+        commands
+    }
+
+    fn identified(&mut self) -> Vec<Command> {
+        if !self.identified {
+            println!("Identified to services as {}", self.account);
+        }
+        self.identified = true;
+
+        if self.join_deadline.is_some() {
+            self.join()
+        } else {
+            vec![]
+        }
+    }
+
+    fn join(&mut self) -> Vec<Command> {
+        self.join_deadline = None;
+        if self.joined {
+            return vec![];
+        }
+        self.joined = true;
+
+        self.channels
+            .iter()
+            .map(|(channel, key)| Command::JOIN(channel.clone(), key.clone(), None))
+            .collect()
+    }
+
+    // Takes the nick if it's free, or asks services to free it
+    fn reclaim(&mut self, now: Instant, current: &str, online: bool) -> Vec<Command> {
+        if !self.registered || current.eq_ignore_ascii_case(&self.nick) {
+            return vec![];
+        }
+
+        if !online {
+            return vec![Command::NICK(self.nick.clone())];
+        }
+
+        let password = match &self.password {
+            Some(password) => password,
+            None => return vec![],
+        };
+
+        // Once per interval, so we don't keep killing someone's connection
+        // if services won't hand the nick over
+        if self
+            .last_recover
+            .is_some_and(|last| now < last + self.reclaim_interval)
+        {
+            return vec![];
+        }
+
+        let verb = match self.recover {
+            Recover::None => return vec![],
+            Recover::Ghost => "GHOST",
+            Recover::Regain => "REGAIN",
+            Recover::Release => "RELEASE",
+        };
+        self.last_recover = Some(now);
+        if self.recover != Recover::Regain {
+            self.next_reclaim = Some(now + RECOVER_DELAY);
+        }
+
+        println!("{} has our nick, sending {}", self.nick, verb);
+        vec![Command::PRIVMSG(
+            self.nickserv.clone(),
+            format!("{} {} {}", verb, self.nick, password),
+        )]
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::settings::Settings;
+
+    fn services(toml: &str) -> Services {
+        let settings = Settings::parse(toml).unwrap();
+        let config = Config {
+            nickname: Some("RustKick".to_string()),
+            alt_nicks: vec!["RustKick_".to_string()],
+            channels: vec!["#rshelp".to_string(), "#drops".to_string()],
+            channel_keys: [("#drops".to_string(), "loot".to_string())]
+                .into_iter()
+                .collect(),
+            ..Config::default()
+        };
+        Services::new(&settings.services, &config)
+    }
+
+    // Feeds the server's lines in order, checking the raw lines sent back
+    fn converse(services: &mut Services, now: Instant, current: &str, script: &[(&str, &[&str])]) {
+        for (line, expected) in script {
+            let message = line.parse::<Message>().unwrap();
+            assert_eq!(
+                &raw(services.handle_at(now, &message, current)),
+                expected,
+                "after '{}'",
+                line
+            );
+        }
+    }
+
+    fn raw(commands: Vec<Command>) -> Vec<String> {
+        commands
+            .into_iter()
+            .map(|command| Message::from(command).to_string().trim_end().to_string())
+            .collect()
+    }
+
+    #[test]
+    fn test_alternates_then_numbers() {
+        let mut services = services("");
+        assert_eq!(raw(services.rejected()), vec!["NICK RustKick_"]);
+        assert_eq!(raw(services.rejected()), vec!["NICK RustKick1"]);
+        assert_eq!(raw(services.rejected()), vec!["NICK RustKick2"]);
+    }
+
+    #[test]
+    fn test_identify_before_joining() {
+        let mut services = services(
+            r#"
+            [services]
+            password = "hunter2"
+            delay_joins = true
+            "#,
+        );
+        let now = Instant::now();
+
+        converse(
+            &mut services,
+            now,
+            "RustKick",
+            &[
+                (":irc.example 001 RustKick :Welcome", &[]),
+                (
+                    ":irc.example 376 RustKick :End of /MOTD command.",
+                    &["PRIVMSG NickServ :IDENTIFY RustKick hunter2"],
+                ),
+                (
+                    ":NickServ!services@services.example NOTICE RustKick :You are now identified for RustKick.",
+                    &["JOIN #rshelp", "JOIN #drops loot"],
+                ),
+            ],
+        );
+        assert!(raw(services.tick_at(now + Duration::from_secs(60), "RustKick")).is_empty());
+    }
+
+    #[test]
+    fn test_held_joins_time_out() {
+        let mut services = services(
+            r#"
+            [services]
+            password = "hunter2"
+            delay_joins = true
+            join_timeout = "10s"
+            "#,
+        );
+        let now = Instant::now();
+
+        converse(
+            &mut services,
+            now,
+            "RustKick",
+            &[
+                (":irc.example 001 RustKick :Welcome", &[]),
+                (
+                    ":irc.example 422 RustKick :MOTD File is missing",
+                    &["PRIVMSG NickServ :IDENTIFY RustKick hunter2"],
+                ),
+            ],
+        );
+        assert!(raw(services.tick_at(now + Duration::from_secs(5), "RustKick")).is_empty());
+        assert_eq!(
+            raw(services.tick_at(now + Duration::from_secs(10), "RustKick")),
+            vec!["JOIN #rshelp", "JOIN #drops loot"]
+        );
+    }
+
+    #[test]
+    fn test_joins_straight_away_without_password() {
+        let mut services = services("[services]\ndelay_joins = true");
+        converse(
+            &mut services,
+            Instant::now(),
+            "RustKick",
+            &[(
+                ":irc.example 376 RustKick :End of /MOTD command.",
+                &["JOIN #rshelp", "JOIN #drops loot"],
+            )],
+        );
+    }
+
+    #[test]
+    fn test_reclaims_with_ghost() {
+        let mut services = services(
+            r#"
+            [services]
+            password = "hunter2"
+            recover = "ghost"
+            reclaim_interval = "5m"
+            "#,
+        );
+        let now = Instant::now();
+
+        converse(
+            &mut services,
+            now,
+            "RustKick_",
+            &[(":irc.example 001 RustKick_ :Welcome", &[])],
+        );
+        assert_eq!(
+            raw(services.tick_at(now, "RustKick_")),
+            vec!["ISON RustKick"]
+        );
+        // Not again until the interval is up
+        assert!(raw(services.tick_at(now + Duration::from_secs(60), "RustKick_")).is_empty());
+
+        converse(
+            &mut services,
+            now,
+            "RustKick_",
+            &[
+                (
+                    ":irc.example 303 RustKick_ :RustKick",
+                    &["PRIVMSG NickServ :GHOST RustKick hunter2"],
+                ),
+                // Only once per interval
+                (":irc.example 303 RustKick_ :RustKick", &[]),
+            ],
+        );
+
+        // Checked again shortly after the ghost
+        let later = now + RECOVER_DELAY;
+        assert_eq!(
+            raw(services.tick_at(later, "RustKick_")),
+            vec!["ISON RustKick"]
+        );
+        converse(
+            &mut services,
+            later,
+            "RustKick_",
+            &[(":irc.example 303 RustKick_ :", &["NICK RustKick"])],
+        );
+    }
+
+    #[test]
+    fn test_takes_nick_when_freed() {
+        let mut services = services("");
+        let now = Instant::now();
+
+        converse(
+            &mut services,
+            now,
+            "RustKick_",
+            &[
+                (":irc.example 001 RustKick_ :Welcome", &[]),
+                (
+                    ":RustKick!kick@elsewhere.example QUIT :Ping timeout",
+                    &["NICK RustKick"],
+                ),
+                // Without a password there's no asking services
+                (":irc.example 303 RustKick_ :RustKick", &[]),
+            ],
+        );
+        // Registered, so a refused NICK is left to the next reclaim
+        assert!(services.rejected().is_empty());
+    }
+}
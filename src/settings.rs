@@ -0,0 +1,948 @@
+use crate::ratelimit::Limit;
+use crate::timers::{parse_duration, parse_interval};
+use serde::Deserialize;
+use std::collections::HashMap;
+use std::fs;
+use std::path::Path;
+use std::time::Duration;
+
+/// Host settings read from the same network TOML as the `irc` crate's
+/// `Config`. The `irc` crate ignores these tables, and everything here is
+/// optional so existing network files keep working unchanged.
+#[derive(Clone, Debug, Default, Deserialize)]
+#[serde(default)]
+pub struct Settings {
+    /// Per-plugin settings keyed by the library's file stem, e.g.
+    /// `[plugins.librunescape]` for `plugins/librunescape.so`.
+    pub plugins: HashMap<String, PluginSettings>,
+    /// How many plugin calls may run at once across all plugins.
+    pub plugin_threads: Option<usize>,
+    /// Deprecated: read as more `[permissions] admin` masks, which is where
+    /// admins belong.
+    pub admins: Vec<String>,
+    /// Who gets which permission level.
+    pub permissions: PermissionSettings,
+    /// Who the bot never answers, and how it keeps out of loops with other
+    /// bots.
+    pub ignore: IgnoreSettings,
+    /// How many commands users may send.
+    pub rate_limit: RateLimitSettings,
+    /// How fast the bot may send.
+    pub flood: FloodSettings,
+    /// How much of a command's output is sent before the rest waits for
+    /// `+more`.
+    pub paging: PagingSettings,
+    /// Which IRCv3 capabilities to negotiate, and how to log in with SASL.
+    pub capabilities: CapabilitySettings,
+    /// Identifying to NickServ, and keeping hold of the bot's nick.
+    pub services: ServicesSettings,
+    /// Servers to rotate through when one fails. Without any, the network's
+    /// own `server` is used.
+    pub servers: Vec<ServerSettings>,
+    /// How long to wait between connection attempts.
+    pub reconnect: ReconnectSettings,
+    /// How the bot checks the connection is still alive.
+    pub watchdog: WatchdogSettings,
+}
+
+/// The bot's own PINGs, as intervals like `"1m"`.
+#[derive(Clone, Debug, Default, Deserialize)]
+#[serde(default)]
+pub struct WatchdogSettings {
+    /// How often to PING the server and measure the lag.
+    pub interval: Option<String>,
+    /// How long to wait for the PONG before reconnecting.
+    pub timeout: Option<String>,
+}
+
+/// One `[[servers]]` entry. Anything left out comes from the network's own
+/// settings.
+#[derive(Clone, Debug, Default, Deserialize)]
+#[serde(default)]
+pub struct ServerSettings {
+    pub server: String,
+    pub port: Option<u16>,
+    pub use_tls: Option<bool>,
+    pub password: Option<String>,
+}
+
+/// Exponential backoff between connection attempts, as intervals like `"5m"`.
+#[derive(Clone, Debug, Default, Deserialize)]
+#[serde(default)]
+pub struct ReconnectSettings {
+    /// The first wait, doubled after every failure.
+    pub initial: Option<String>,
+    /// The longest wait, and the wait once every server has refused us.
+    pub max: Option<String>,
+    /// How long a connection has to last for the backoff to start over.
+    pub stable: Option<String>,
+}
+
+#[derive(Clone, Debug, Default, Deserialize)]
+#[serde(default)]
+pub struct ServicesSettings {
+    /// Who to identify to and recover nicks through.
+    pub nickserv: Option<String>,
+    /// The services account. Defaults to the network's `nickname`.
+    pub account: Option<String>,
+    /// Defaults to the network's `nick_password`. Without one the bot doesn't
+    /// identify or recover its nick, only waits for it to come free.
+    pub password: Option<String>,
+    /// What to send NickServ to identify, with `{account}` and `{password}`
+    /// filled in.
+    pub identify: Option<String>,
+    /// How to get the nick back from whoever has it. Defaults to `ghost` if
+    /// the network sets `should_ghost`.
+    pub recover: Recover,
+    /// Nicks to try in order while the nick is taken. Defaults to the
+    /// network's `alt_nicks`; the nick with a number after it comes next.
+    pub alt_nicks: Vec<String>,
+    /// How often to try for the nick while on another one, as an interval
+    /// like `"5m"`.
+    pub reclaim_interval: Option<String>,
+    /// Hold channel joins until identified, so channels that need an account
+    /// let the bot in.
+    pub delay_joins: bool,
+    /// How long to hold them before joining anyway, as an interval like
+    /// `"30s"`.
+    pub join_timeout: Option<String>,
+}
+
+#[derive(Clone, Copy, Debug, Default, Deserialize, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum Recover {
+    #[default]
+    None,
+    /// `GHOST` disconnects whoever has the nick, then the bot takes it.
+    Ghost,
+    /// `REGAIN` has services move the bot onto the nick.
+    Regain,
+    /// `RELEASE` frees a nick services are holding, then the bot takes it.
+    Release,
+}
+
+#[derive(Clone, Debug, Default, Deserialize)]
+#[serde(default)]
+pub struct CapabilitySettings {
+    /// Capabilities to request when the server offers them. Defaults to
+    /// every one the bot uses; `[]` skips negotiation unless SASL is set.
+    pub request: Option<Vec<String>>,
+    pub sasl: Option<SaslSettings>,
+}
+
+#[derive(Clone, Debug, Default, Deserialize)]
+#[serde(default)]
+pub struct SaslSettings {
+    pub mechanism: SaslMechanism,
+    /// Account and password for `plain`. `external` logs in with the client
+    /// certificate instead.
+    pub account: String,
+    pub password: String,
+    /// Disconnect rather than carry on unidentified if SASL fails.
+    pub required: bool,
+}
+
+#[derive(Clone, Copy, Debug, Default, Deserialize, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum SaslMechanism {
+    #[default]
+    Plain,
+    External,
+}
+
+#[derive(Clone, Debug, Default, Deserialize)]
+#[serde(default)]
+pub struct PagingSettings {
+    /// Lines one command may send to a channel at once.
+    pub channel_lines: Option<usize>,
+    /// Lines one command may send to a nick at once.
+    pub query_lines: Option<usize>,
+    /// How long held lines wait for `+more`, as an interval like `"5m"`.
+    pub expiry: Option<String>,
+    /// Overrides `channel_lines` for particular channels.
+    pub channels: HashMap<String, usize>,
+}
+
+/// Outbound flood control: `burst` lines at once, then one per `interval`.
+#[derive(Clone, Debug, Default, Deserialize)]
+#[serde(default)]
+pub struct FloodSettings {
+    pub burst: Option<u32>,
+    /// As an interval like `"2s"`.
+    pub interval: Option<String>,
+    /// How many lines may wait for one target before more are dropped.
+    pub max_queued: Option<usize>,
+}
+
+/// Token-bucket limits on commands typed by users. Missing sections fall back
+/// to the defaults, and `burst = 0` turns a limit off.
+#[derive(Clone, Debug, Default, Deserialize)]
+#[serde(default)]
+pub struct RateLimitSettings {
+    /// Per `nick!user@host`.
+    pub user: Option<LimitSettings>,
+    /// Per channel, whoever sends the commands.
+    pub channel: Option<LimitSettings>,
+    /// Per plugin and command, unless the plugin sets its own.
+    pub command: Option<LimitSettings>,
+}
+
+#[derive(Clone, Debug, Default, Deserialize)]
+#[serde(default)]
+pub struct LimitSettings {
+    /// How many commands may be sent at once.
+    pub burst: u32,
+    /// How long a full burst takes to refill, as an interval like `"30s"`.
+    pub per: Option<String>,
+}
+
+/// Masks granting each level, as `nick!user@host` with `*` and `?` wildcards
+/// or `$a:` and a services account. Anyone unmatched is a plain user.
+#[derive(Clone, Debug, Default, Deserialize)]
+#[serde(default)]
+pub struct PermissionSettings {
+    pub owner: Vec<String>,
+    pub admin: Vec<String>,
+    pub trusted: Vec<String>,
+    /// Deprecated: read as more `[ignore] masks`, which is where ignores
+    /// belong.
+    pub ignored: Vec<String>,
+}
+
+#[derive(Clone, Debug, Default, Deserialize)]
+#[serde(default)]
+pub struct IgnoreSettings {
+    /// Nicks, `nick!user@host` masks or `$a:account` masks that are never
+    /// answered. The one list of ignores; `+ignore` edits it at runtime.
+    pub masks: Vec<String>,
+    /// Whether NOTICEs may trigger commands. Off by default, since answering
+    /// notices is how two bots end up replying to each other forever.
+    pub notice_commands: bool,
+    /// How many identical commands in a row from one sender count as a loop.
+    pub loop_threshold: Option<usize>,
+    /// How close together those commands have to be, as an interval like
+    /// `"30s"`.
+    pub loop_window: Option<String>,
+    /// How long a sender caught looping is ignored for.
+    pub loop_ignore: Option<String>,
+}
+
+#[derive(Clone, Debug, Default, Deserialize)]
+#[serde(default)]
+pub struct PluginSettings {
+    /// Run the plugin inside a `reinze-plugin-host` child process instead of
+    /// loading it into the bot.
+    pub isolated: bool,
+    /// How long a single call may run before the caller is told it timed out,
+    /// as an interval like `"10s"`. An isolated plugin's child is restarted
+    /// once it goes this long without answering.
+    pub timeout: Option<String>,
+    /// How many calls into this plugin may run at once. An isolated plugin
+    /// gets a child process for each.
+    pub concurrency: Option<usize>,
+    /// Overrides the priority the plugin declared. When triggers from several
+    /// plugins match a command, higher priorities run first.
+    pub priority: Option<i32>,
+    /// Overrides `[rate_limit.command]` for this plugin's commands.
+    pub rate_limit: Option<LimitSettings>,
+    /// Passive listeners keyed by the name the plugin declared them under.
+    /// A listener only runs in the channels enabled here.
+    pub listeners: HashMap<String, ListenerSettings>,
+}
+
+#[derive(Clone, Debug, Default, Deserialize)]
+#[serde(default)]
+pub struct ListenerSettings {
+    /// Channels the listener runs in, or `"*"` for every channel.
+    pub channels: Vec<String>,
+    /// Overrides the cooldown the plugin declared, as an interval like `"1m"`,
+    /// or `"0s"` for none.
+    pub cooldown: Option<String>,
+}
+
+impl ListenerSettings {
+    pub fn enabled_in(&self, channel: &str) -> bool {
+        self.channels
+            .iter()
+            .any(|enabled| enabled == "*" || enabled.eq_ignore_ascii_case(channel))
+    }
+
+    pub fn cooldown(&self) -> Option<Duration> {
+        self.cooldown.as_deref().and_then(parse_duration)
+    }
+}
+
+const DEFAULT_PLUGIN_THREADS: usize = 16;
+const DEFAULT_TIMEOUT: Duration = Duration::from_secs(15);
+const DEFAULT_CONCURRENCY: usize = 4;
+const DEFAULT_LOOP_THRESHOLD: usize = 5;
+const DEFAULT_LOOP_WINDOW: Duration = Duration::from_secs(30);
+const DEFAULT_LOOP_IGNORE: Duration = Duration::from_secs(15 * 60);
+
+const DEFAULT_LIMIT_PER: Duration = Duration::from_secs(60);
+const DEFAULT_USER_LIMIT: Limit = Limit {
+    burst: 5,
+    per: Duration::from_secs(20),
+};
+const DEFAULT_CHANNEL_LIMIT: Limit = Limit {
+    burst: 15,
+    per: Duration::from_secs(60),
+};
+
+impl LimitSettings {
+    pub fn limit(&self) -> Option<Limit> {
+        if self.burst == 0 {
+            return None;
+        }
+
+        let per = self
+            .per
+            .as_deref()
+            .and_then(parse_interval)
+            .unwrap_or(DEFAULT_LIMIT_PER);
+
+        Some(Limit {
+            burst: self.burst,
+            per,
+        })
+    }
+}
+
+impl RateLimitSettings {
+    pub fn user(&self) -> Option<Limit> {
+        match &self.user {
+            Some(user) => user.limit(),
+            None => Some(DEFAULT_USER_LIMIT),
+        }
+    }
+
+    pub fn channel(&self) -> Option<Limit> {
+        match &self.channel {
+            Some(channel) => channel.limit(),
+            None => Some(DEFAULT_CHANNEL_LIMIT),
+        }
+    }
+
+    pub fn command(&self) -> Option<Limit> {
+        self.command.as_ref().and_then(LimitSettings::limit)
+    }
+}
+
+// RFC 1459's client flood control: a line every two seconds, with ten
+// seconds' worth of slack
+const DEFAULT_BURST: u32 = 5;
+const DEFAULT_INTERVAL: Duration = Duration::from_secs(2);
+const DEFAULT_MAX_QUEUED: usize = 30;
+
+impl FloodSettings {
+    pub fn burst(&self) -> u32 {
+        self.burst.filter(|&n| n > 0).unwrap_or(DEFAULT_BURST)
+    }
+
+    pub fn interval(&self) -> Duration {
+        self.interval
+            .as_deref()
+            .and_then(parse_interval)
+            .unwrap_or(DEFAULT_INTERVAL)
+    }
+
+    pub fn max_queued(&self) -> usize {
+        self.max_queued
+            .filter(|&n| n > 0)
+            .unwrap_or(DEFAULT_MAX_QUEUED)
+    }
+}
+
+const DEFAULT_CHANNEL_LINES: usize = 4;
+const DEFAULT_QUERY_LINES: usize = 15;
+const DEFAULT_PAGE_EXPIRY: Duration = Duration::from_secs(5 * 60);
+
+impl PagingSettings {
+    pub fn channel_lines(&self, channel: &str) -> usize {
+        self.channels
+            .iter()
+            .find(|(name, _)| name.eq_ignore_ascii_case(channel))
+            .map(|(_, &lines)| lines)
+            .or(self.channel_lines)
+            .filter(|&n| n > 0)
+            .unwrap_or(DEFAULT_CHANNEL_LINES)
+    }
+
+    pub fn query_lines(&self) -> usize {
+        self.query_lines
+            .filter(|&n| n > 0)
+            .unwrap_or(DEFAULT_QUERY_LINES)
+    }
+
+    pub fn expiry(&self) -> Duration {
+        self.expiry
+            .as_deref()
+            .and_then(parse_interval)
+            .unwrap_or(DEFAULT_PAGE_EXPIRY)
+    }
+}
+
+const DEFAULT_CAPABILITIES: [&str; 7] = [
+    "message-tags",
+    "account-tag",
+    "server-time",
+    "away-notify",
+    "extended-join",
+    "echo-message",
+    "multi-prefix",
+];
+
+impl CapabilitySettings {
+    pub fn request(&self) -> Vec<String> {
+        match &self.request {
+            Some(request) => request.clone(),
+            None => DEFAULT_CAPABILITIES.map(String::from).to_vec(),
+        }
+    }
+}
+
+impl SaslMechanism {
+    pub fn name(&self) -> &'static str {
+        match self {
+            Self::Plain => "PLAIN",
+            Self::External => "EXTERNAL",
+        }
+    }
+}
+
+const DEFAULT_INITIAL_BACKOFF: Duration = Duration::from_secs(1);
+const DEFAULT_MAX_BACKOFF: Duration = Duration::from_secs(5 * 60);
+const DEFAULT_STABLE: Duration = Duration::from_secs(5 * 60);
+
+impl ReconnectSettings {
+    pub fn initial(&self) -> Duration {
+        self.initial
+            .as_deref()
+            .and_then(parse_interval)
+            .unwrap_or(DEFAULT_INITIAL_BACKOFF)
+    }
+
+    pub fn max(&self) -> Duration {
+        self.max
+            .as_deref()
+            .and_then(parse_interval)
+            .unwrap_or(DEFAULT_MAX_BACKOFF)
+            .max(self.initial())
+    }
+
+    pub fn stable(&self) -> Duration {
+        self.stable
+            .as_deref()
+            .and_then(parse_interval)
+            .unwrap_or(DEFAULT_STABLE)
+    }
+}
+
+const DEFAULT_PING_INTERVAL: Duration = Duration::from_secs(60);
+const DEFAULT_PING_TIMEOUT: Duration = Duration::from_secs(30);
+
+impl WatchdogSettings {
+    pub fn interval(&self) -> Duration {
+        self.interval
+            .as_deref()
+            .and_then(parse_interval)
+            .unwrap_or(DEFAULT_PING_INTERVAL)
+    }
+
+    pub fn timeout(&self) -> Duration {
+        self.timeout
+            .as_deref()
+            .and_then(parse_interval)
+            .unwrap_or(DEFAULT_PING_TIMEOUT)
+    }
+}
+
+const DEFAULT_NICKSERV: &str = "NickServ";
+const DEFAULT_IDENTIFY: &str = "IDENTIFY {account} {password}";
+const DEFAULT_RECLAIM_INTERVAL: Duration = Duration::from_secs(5 * 60);
+const DEFAULT_JOIN_TIMEOUT: Duration = Duration::from_secs(30);
+
+impl ServicesSettings {
+    pub fn nickserv(&self) -> &str {
+        self.nickserv.as_deref().unwrap_or(DEFAULT_NICKSERV)
+    }
+
+    pub fn identify(&self) -> &str {
+        self.identify.as_deref().unwrap_or(DEFAULT_IDENTIFY)
+    }
+
+    pub fn reclaim_interval(&self) -> Duration {
+        self.reclaim_interval
+            .as_deref()
+            .and_then(parse_interval)
+            .unwrap_or(DEFAULT_RECLAIM_INTERVAL)
+    }
+
+    pub fn join_timeout(&self) -> Duration {
+        self.join_timeout
+            .as_deref()
+            .and_then(parse_interval)
+            .unwrap_or(DEFAULT_JOIN_TIMEOUT)
+    }
+}
+
+impl IgnoreSettings {
+    pub fn loop_threshold(&self) -> usize {
+        self.loop_threshold
+            .filter(|&n| n > 1)
+            .unwrap_or(DEFAULT_LOOP_THRESHOLD)
+    }
+
+    pub fn loop_window(&self) -> Duration {
+        self.loop_window
+            .as_deref()
+            .and_then(parse_interval)
+            .unwrap_or(DEFAULT_LOOP_WINDOW)
+    }
+
+    pub fn loop_ignore(&self) -> Duration {
+        self.loop_ignore
+            .as_deref()
+            .and_then(parse_interval)
+            .unwrap_or(DEFAULT_LOOP_IGNORE)
+    }
+}
+
+impl PluginSettings {
+    pub fn timeout(&self) -> Duration {
+        self.timeout
+            .as_deref()
+            .and_then(parse_interval)
+            .unwrap_or(DEFAULT_TIMEOUT)
+    }
+
+    pub fn concurrency(&self) -> usize {
+        self.concurrency
+            .filter(|&n| n > 0)
+            .unwrap_or(DEFAULT_CONCURRENCY)
+    }
+
+    /// Settings for one of the plugin's listeners, disabled everywhere if it
+    /// has none.
+    pub fn listener(&self, name: &str) -> ListenerSettings {
+        self.listeners.get(name).cloned().unwrap_or_default()
+    }
+}
+
+impl Settings {
+    /// Reads the host settings from a network TOML. Falls back to defaults
+    /// (after logging why) so a typo can't keep the network offline.
+    pub fn load(path: &str) -> Self {
+        let contents = match fs::read_to_string(path) {
+            Ok(contents) => contents,
+            Err(e) => {
+                println!("Error reading settings from {}: {}", path, e);
+                return Self::default();
+            }
+        };
+
+        match Self::parse(&contents) {
+            Ok(settings) => {
+                for warning in settings.deprecations() {
+                    println!("Warning: {}", warning);
+                }
+                settings
+            }
+            Err(e) => {
+                println!("Error parsing settings from {}: {}", path, e);
+                Self::default()
+            }
+        }
+    }
+
+    pub fn parse(contents: &str) -> Result<Self, toml::de::Error> {
+        let settings: Self = toml::from_str(contents)?;
+
+        // Rotation would only ever fail on these
+        if let Some(server) = settings
+            .servers
+            .iter()
+            .find(|server| server.server.trim().is_empty() || server.port == Some(0))
+        {
+            return Err(serde::de::Error::custom(format!(
+                "[[servers]] entry '{}' needs a server and a port above 0",
+                server.server
+            )));
+        }
+
+        Ok(settings)
+    }
+
+    /// Masks that are never answered: `[ignore] masks`, plus any still under
+    /// the old `[permissions] ignored`.
+    pub fn ignore_masks(&self) -> Vec<String> {
+        self.ignore
+            .masks
+            .iter()
+            .chain(&self.permissions.ignored)
+            .cloned()
+            .collect()
+    }
+
+    /// Masks granted admin: `[permissions] admin`, plus any still under the
+    /// old top-level `admins`.
+    pub fn admin_masks(&self) -> Vec<String> {
+        self.permissions
+            .admin
+            .iter()
+            .chain(&self.admins)
+            .cloned()
+            .collect()
+    }
+
+    /// Settings that still work but have moved, as warnings to log.
+    pub fn deprecations(&self) -> Vec<String> {
+        let mut warnings = vec![];
+        if !self.admins.is_empty() {
+            warnings
+                .push("admins is deprecated; move its masks to [permissions] admin".to_string());
+        }
+        if !self.permissions.ignored.is_empty() {
+            warnings.push(
+                "[permissions] ignored is deprecated; move its masks to [ignore] masks".to_string(),
+            );
+        }
+        warnings
+    }
+
+    pub fn plugin_threads(&self) -> usize {
+        self.plugin_threads
+            .filter(|&n| n > 0)
+            .unwrap_or(DEFAULT_PLUGIN_THREADS)
+    }
+
+    /// Settings for the plugin at `path`, or the defaults if it has none.
+    pub fn plugin(&self, path: &str) -> PluginSettings {
+        Path::new(path)
+            .file_stem()
+            .and_then(|stem| stem.to_str())
+            .and_then(|stem| self.plugins.get(stem))
+            .cloned()
+            .unwrap_or_default()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_ignores_irc_fields() {
+        let settings = Settings::parse(
+            r##"
+            nickname = "RustKick"
+            channels = ["#rshelp"]
+
+            [plugins.librunescape]
+            isolated = true
+            timeout = "1m"
+            priority = 5
+            "##,
+        )
+        .unwrap();
+
+        let runescape = settings.plugin("plugins/librunescape.so");
+        assert!(runescape.isolated);
+        assert_eq!(runescape.timeout(), Duration::from_secs(60));
+        assert_eq!(runescape.priority, Some(5));
+        assert!(!settings.plugin("plugins/libother.so").isolated);
+    }
+
+    #[test]
+    fn test_plugin_defaults() {
+        let settings = Settings::parse(
+            r#"
+            [plugins.libslow]
+            timeout = "soon"
+            concurrency = 0
+            "#,
+        )
+        .unwrap();
+
+        let slow = settings.plugin("plugins/libslow.so");
+        assert_eq!(slow.timeout(), DEFAULT_TIMEOUT);
+        assert_eq!(slow.concurrency(), DEFAULT_CONCURRENCY);
+        assert_eq!(settings.plugin_threads(), DEFAULT_PLUGIN_THREADS);
+    }
+
+    #[test]
+    fn test_listener_settings() {
+        let settings = Settings::parse(
+            r##"
+            [plugins.libprices.listeners.price]
+            channels = ["#rshelp"]
+            cooldown = "2m"
+
+            [plugins.libprices.listeners.url]
+            channels = ["*"]
+            cooldown = "0s"
+            "##,
+        )
+        .unwrap();
+
+        let prices = settings.plugin("plugins/libprices.so");
+        let price = prices.listener("price");
+        assert!(price.enabled_in("#RSHelp"));
+        assert!(!price.enabled_in("#other"));
+        assert_eq!(price.cooldown(), Some(Duration::from_secs(120)));
+        assert!(prices.listener("url").enabled_in("#other"));
+        assert_eq!(prices.listener("url").cooldown(), Some(Duration::ZERO));
+        assert!(!prices.listener("missing").enabled_in("#rshelp"));
+    }
+
+    #[test]
+    fn test_permission_settings() {
+        let settings = Settings::parse(
+            r#"
+            [permissions]
+            admin = ["*!*@staff.example.net", "$a:kick"]
+            "#,
+        )
+        .unwrap();
+
+        assert_eq!(settings.permissions.admin.len(), 2);
+        assert!(settings.permissions.owner.is_empty());
+    }
+
+    #[test]
+    fn test_ignore_settings() {
+        let settings = Settings::parse(
+            r#"
+            [ignore]
+            masks = ["OtherBot"]
+            notice_commands = true
+            loop_window = "1m"
+            loop_threshold = 1
+            "#,
+        )
+        .unwrap();
+
+        assert!(settings.ignore.notice_commands);
+        assert_eq!(settings.ignore.loop_window(), Duration::from_secs(60));
+        assert_eq!(settings.ignore.loop_threshold(), DEFAULT_LOOP_THRESHOLD);
+        assert_eq!(settings.ignore.loop_ignore(), DEFAULT_LOOP_IGNORE);
+        assert!(!Settings::default().ignore.notice_commands);
+        assert!(settings.deprecations().is_empty());
+
+        // The old list still counts, with a warning
+        let settings = Settings::parse(
+            r#"
+            [permissions]
+            ignored = ["*!*@spam.example"]
+
+            [ignore]
+            masks = ["OtherBot"]
+            "#,
+        )
+        .unwrap();
+        assert_eq!(
+            settings.ignore_masks(),
+            vec!["OtherBot", "*!*@spam.example"]
+        );
+        assert_eq!(settings.deprecations().len(), 1);
+    }
+
+    #[test]
+    fn test_deprecated_admins() {
+        let settings = Settings::parse(
+            r#"
+            admins = ["*!*@old.example.net"]
+
+            [permissions]
+            admin = ["*!*@staff.example.net"]
+            "#,
+        )
+        .unwrap();
+
+        assert_eq!(
+            settings.admin_masks(),
+            vec!["*!*@staff.example.net", "*!*@old.example.net"]
+        );
+        assert_eq!(settings.deprecations().len(), 1);
+        assert!(Settings::default().admin_masks().is_empty());
+    }
+
+    #[test]
+    fn test_rate_limit_settings() {
+        let settings = Settings::parse(
+            r#"
+            [rate_limit.user]
+            burst = 3
+            per = "1m"
+
+            [rate_limit.channel]
+            burst = 0
+
+            [rate_limit.command]
+            burst = 2
+            "#,
+        )
+        .unwrap();
+
+        let limits = settings.rate_limit;
+        assert_eq!(
+            limits.user(),
+            Some(Limit {
+                burst: 3,
+                per: Duration::from_secs(60)
+            })
+        );
+        assert_eq!(limits.channel(), None);
+        assert_eq!(
+            limits.command().map(|limit| limit.per),
+            Some(DEFAULT_LIMIT_PER)
+        );
+
+        let defaults = RateLimitSettings::default();
+        assert_eq!(defaults.user(), Some(DEFAULT_USER_LIMIT));
+        assert_eq!(defaults.channel(), Some(DEFAULT_CHANNEL_LIMIT));
+        assert_eq!(defaults.command(), None);
+    }
+
+    #[test]
+    fn test_flood_settings() {
+        let settings = Settings::parse(
+            r#"
+            [flood]
+            burst = 10
+            interval = "1s"
+            max_queued = 0
+            "#,
+        )
+        .unwrap();
+
+        assert_eq!(settings.flood.burst(), 10);
+        assert_eq!(settings.flood.interval(), Duration::from_secs(1));
+        assert_eq!(settings.flood.max_queued(), DEFAULT_MAX_QUEUED);
+        assert_eq!(Settings::default().flood.interval(), DEFAULT_INTERVAL);
+    }
+
+    #[test]
+    fn test_paging_settings() {
+        let settings = Settings::parse(
+            r##"
+            [paging]
+            channel_lines = 6
+
+            [paging.channels]
+            "#Drops" = 10
+            "#quiet" = 0
+            "##,
+        )
+        .unwrap();
+
+        let paging = settings.paging;
+        assert_eq!(paging.channel_lines("#rshelp"), 6);
+        assert_eq!(paging.channel_lines("#drops"), 10);
+        assert_eq!(paging.channel_lines("#quiet"), DEFAULT_CHANNEL_LINES);
+        assert_eq!(paging.query_lines(), DEFAULT_QUERY_LINES);
+        assert_eq!(paging.expiry(), DEFAULT_PAGE_EXPIRY);
+    }
+
+    #[test]
+    fn test_capability_settings() {
+        let settings = Settings::parse("").unwrap();
+        assert_eq!(settings.capabilities.request().len(), 7);
+        assert!(settings.capabilities.sasl.is_none());
+
+        let settings = Settings::parse(
+            r#"
+            [capabilities]
+            request = ["server-time"]
+
+            [capabilities.sasl]
+            mechanism = "external"
+            required = true
+            "#,
+        )
+        .unwrap();
+
+        let capabilities = settings.capabilities;
+        assert_eq!(capabilities.request(), vec!["server-time"]);
+        let sasl = capabilities.sasl.unwrap();
+        assert_eq!(sasl.mechanism, SaslMechanism::External);
+        assert_eq!(sasl.mechanism.name(), "EXTERNAL");
+        assert!(sasl.required);
+    }
+
+    #[test]
+    fn test_services_settings() {
+        let services = Settings::parse("").unwrap().services;
+        assert_eq!(services.nickserv(), "NickServ");
+        assert_eq!(services.recover, Recover::None);
+        assert_eq!(services.reclaim_interval(), DEFAULT_RECLAIM_INTERVAL);
+
+        let services = Settings::parse(
+            r#"
+            [services]
+            nickserv = "AuthServ"
+            identify = "AUTH {account} {password}"
+            recover = "regain"
+            alt_nicks = ["RustKick_", "RustKick2"]
+            reclaim_interval = "1m"
+            delay_joins = true
+            "#,
+        )
+        .unwrap()
+        .services;
+        assert_eq!(services.nickserv(), "AuthServ");
+        assert_eq!(services.identify(), "AUTH {account} {password}");
+        assert_eq!(services.recover, Recover::Regain);
+        assert_eq!(services.reclaim_interval(), Duration::from_secs(60));
+        assert_eq!(services.join_timeout(), DEFAULT_JOIN_TIMEOUT);
+    }
+
+    #[test]
+    fn test_server_settings() {
+        let settings = Settings::parse(
+            r#"
+            [[servers]]
+            server = "irc1.example"
+            port = 6697
+            use_tls = true
+
+            [[servers]]
+            server = "irc2.example"
+
+            [reconnect]
+            max = "10s"
+            initial = "30s"
+            "#,
+        )
+        .unwrap();
+
+        assert_eq!(settings.servers.len(), 2);
+        assert_eq!(settings.servers[0].port, Some(6697));
+        assert_eq!(settings.servers[1].use_tls, None);
+        // The cap is never below the first wait
+        assert_eq!(settings.reconnect.max(), Duration::from_secs(30));
+        assert_eq!(settings.reconnect.stable(), DEFAULT_STABLE);
+
+        assert!(Settings::parse("[[servers]]\nport = 6697").is_err());
+        assert!(Settings::parse("[[servers]]\nserver = \" \"").is_err());
+        assert!(Settings::parse("[[servers]]\nserver = \"irc.example\"\nport = 0").is_err());
+    }
+
+    #[test]
+    fn test_watchdog_settings() {
+        let watchdog = Settings::parse("").unwrap().watchdog;
+        assert_eq!(watchdog.interval(), DEFAULT_PING_INTERVAL);
+        assert_eq!(watchdog.timeout(), DEFAULT_PING_TIMEOUT);
+
+        let watchdog = Settings::parse("[watchdog]\ninterval = \"2m\"")
+            .unwrap()
+            .watchdog;
+        assert_eq!(watchdog.interval(), Duration::from_secs(120));
+    }
+
+    #[test]
+    fn test_parse_empty() {
+        let settings = Settings::parse("").unwrap();
+        assert!(settings.plugins.is_empty());
+    }
+}
@@ -0,0 +1,251 @@
+use irc::client::prelude::Command;
+
+/// The longest line a server accepts, including the trailing CRLF.
+pub const MAX_LINE: usize = 512;
+
+// Longest user and host a server may show for us before we've seen our own
+// prefix (ident plus `~`, and RFC 2812's 63-byte host names)
+pub const USERLEN: usize = 10;
+pub const HOSTLEN: usize = 63;
+
+const BOLD: char = '\x02';
+const COLOR: char = '\x03';
+const MONOSPACE: char = '\x11';
+const REVERSE: char = '\x16';
+const ITALIC: char = '\x1D';
+const STRIKETHROUGH: char = '\x1E';
+const UNDERLINE: char = '\x1F';
+const RESET: char = '\x0F';
+
+/// How many bytes of text fit in `command` (built with empty text) once the
+/// server has put `:<prefix> ` in front of it, for a prefix `prefix_len`
+/// bytes long.
+pub fn budget(prefix_len: usize, command: &Command) -> usize {
+    let overhead = match command {
+        Command::PRIVMSG(target, text) => "PRIVMSG".len() + target.len() + text.len(),
+        Command::NOTICE(target, text) => "NOTICE".len() + target.len() + text.len(),
+        _ => 0,
+    };
+
+    // ":" prefix " " command " " target " :" text "\r\n"
+    MAX_LINE.saturating_sub(1 + prefix_len + 1 + overhead + 1 + 2 + 2)
+}
+
+/// mIRC formatting in effect at some point in a line.
+#[derive(Clone, Debug, Default, PartialEq)]
+struct Format {
+    bold: bool,
+    italic: bool,
+    underline: bool,
+    strikethrough: bool,
+    monospace: bool,
+    reverse: bool,
+    foreground: Option<String>,
+    background: Option<String>,
+}
+
+impl Format {
+    fn apply(&mut self, unit: &str) {
+        let mut chars = unit.chars();
+        match chars.next() {
+            Some(BOLD) => self.bold = !self.bold,
+            Some(ITALIC) => self.italic = !self.italic,
+            Some(UNDERLINE) => self.underline = !self.underline,
+            Some(STRIKETHROUGH) => self.strikethrough = !self.strikethrough,
+            Some(MONOSPACE) => self.monospace = !self.monospace,
+            Some(REVERSE) => self.reverse = !self.reverse,
+            Some(RESET) => *self = Self::default(),
+            Some(COLOR) => {
+                let code = chars.as_str();
+                if code.is_empty() {
+                    self.foreground = None;
+                    self.background = None;
+                    return;
+                }
+
+                let (foreground, background) = match code.split_once(',') {
+                    Some((foreground, background)) => (foreground, Some(background)),
+                    None => (code, None),
+                };
+                self.foreground = Some(foreground.to_string());
+                if let Some(background) = background {
+                    self.background = Some(background.to_string());
+                }
+            }
+            _ => (),
+        }
+    }
+
+    // The codes that bring a fresh line to this state. Colors are written
+    // with two digits so text starting with a digit can't extend them.
+    fn restore(&self) -> String {
+        let mut codes = String::new();
+
+        if let Some(foreground) = &self.foreground {
+            codes.push(COLOR);
+            codes.push_str(&format!("{:0>2}", foreground));
+            if let Some(background) = &self.background {
+                codes.push_str(&format!(",{:0>2}", background));
+            }
+        }
+
+        let toggles = [
+            (self.bold, BOLD),
+            (self.italic, ITALIC),
+            (self.underline, UNDERLINE),
+            (self.strikethrough, STRIKETHROUGH),
+            (self.monospace, MONOSPACE),
+            (self.reverse, REVERSE),
+        ];
+        codes.extend(toggles.iter().filter(|(on, _)| *on).map(|(_, code)| code));
+
+        codes
+    }
+}
+
+// Splits a word into pieces that must stay together: single characters, and
+// whole color codes (`\x03` with up to two digits, then optionally a comma
+// and up to two more).
+fn units(word: &str) -> Vec<&str> {
+    let mut units = vec![];
+    let mut rest = word;
+
+    while let Some(c) = rest.chars().next() {
+        let mut end = c.len_utf8();
+
+        if c == COLOR {
+            let digits = |from: usize| {
+                rest[from..]
+                    .bytes()
+                    .take(2)
+                    .take_while(u8::is_ascii_digit)
+                    .count()
+            };
+
+            let foreground = digits(end);
+            end += foreground;
+            if foreground > 0 && rest[end..].starts_with(',') {
+                let background = digits(end + 1);
+                if background > 0 {
+                    end += 1 + background;
+                }
+            }
+        }
+
+        units.push(&rest[..end]);
+        rest = &rest[end..];
+    }
+
+    units
+}
+
+/// Splits `text` into lines of at most `budget` bytes, between words where
+/// possible. Words too long for a line of their own are broken, but never
+/// inside a character or a color code, and each continuation line starts
+/// with whatever formatting was in effect where the last one stopped.
+pub fn split(text: &str, budget: usize) -> Vec<String> {
+    let mut lines = vec![];
+    let mut format = Format::default();
+    let mut line = String::new();
+    // Where the line's own text starts, after restored formatting
+    let mut start = 0;
+
+    let mut push = |line: &mut String, start: &mut usize, format: &Format| {
+        lines.push(std::mem::replace(line, format.restore()));
+        *start = line.len();
+    };
+
+    for word in text.split_whitespace() {
+        let separator = usize::from(line.len() > start);
+
+        if line.len() + separator + word.len() > budget && line.len() > start {
+            push(&mut line, &mut start, &format);
+        }
+
+        if line.len() > start {
+            line.push(' ');
+        }
+
+        for unit in units(word) {
+            if line.len() + unit.len() > budget && line.len() > start {
+                push(&mut line, &mut start, &format);
+            }
+
+            line.push_str(unit);
+            format.apply(unit);
+        }
+    }
+
+    if line.len() > start {
+        lines.push(line);
+    }
+
+    lines
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_budget() {
+        let command = Command::PRIVMSG("#rshelp".to_string(), String::new());
+        // ":RustKick!~kick@host PRIVMSG #rshelp :" and CRLF
+        let prefix = "RustKick!~kick@host";
+        assert_eq!(
+            budget(prefix.len(), &command),
+            512 - format!(":{} PRIVMSG #rshelp :\r\n", prefix).len()
+        );
+
+        let action = Command::PRIVMSG("#rshelp".to_string(), "\x01ACTION \x01".to_string());
+        assert_eq!(
+            budget(prefix.len(), &action),
+            budget(prefix.len(), &command) - 9
+        );
+    }
+
+    #[test]
+    fn test_split_between_words() {
+        assert_eq!(
+            split("the quick brown fox", 10),
+            vec!["the quick", "brown fox"]
+        );
+        assert_eq!(split("  spaced   out ", 100), vec!["spaced out"]);
+        assert!(split("", 10).is_empty());
+    }
+
+    #[test]
+    fn test_split_is_byte_accurate() {
+        let text = "ab ".repeat(300);
+        for line in split(&text, 100) {
+            assert!(line.len() <= 100);
+        }
+    }
+
+    #[test]
+    fn test_hard_wraps_on_char_boundaries() {
+        // Each 'é' is two bytes, so five fit in eleven bytes but not six
+        let lines = split(&"é".repeat(12), 11);
+        assert_eq!(lines, vec!["ééééé", "ééééé", "éé"]);
+    }
+
+    #[test]
+    fn test_color_codes_stay_whole() {
+        assert_eq!(units("\x0304,12red"), vec!["\x0304,12", "r", "e", "d"]);
+        assert_eq!(units("\x034,"), vec!["\x034", ","]);
+        assert_eq!(units("\x03"), vec!["\x03"]);
+
+        // The code can't fit with "ab", so it moves to the next line whole
+        let lines = split("ab\x0304,12cd", 7);
+        assert_eq!(lines, vec!["ab", "\x0304,12c", "\x0304,12d"]);
+    }
+
+    #[test]
+    fn test_formatting_carries_over() {
+        let lines = split("\x02\x034bold red\x0F plain", 10);
+        assert_eq!(lines, vec!["\x02\x034bold", "\x0304\x02red\x0F", "plain"]);
+
+        let lines = split("\x1Fa\x1F b", 3);
+        assert_eq!(lines, vec!["\x1Fa\x1F", "b"]);
+    }
+}
@@ -1,10 +1,10 @@
+use crate::executor::Executor;
+use crate::permissions::Caller;
 use crate::plugins::Plugin;
-use common::{ColorResult, PluginContext};
-use libloading::{Library, Symbol};
+use common::ColorResult;
 use log::{error, info};
-use std::ffi::{CStr, CString};
 use std::os::raw::c_char;
-use std::sync::{Arc, Mutex, RwLock};
+use std::sync::{Arc, Mutex};
 use std::time::Duration;
 use tokio::task::JoinHandle;
 
@@ -18,6 +18,12 @@ pub struct TimerDef {
 /// Supported units: s (seconds), m (minutes), h (hours), d (days), w (weeks).
 /// Returns None for empty, invalid, or zero-duration input.
 pub fn parse_interval(input: &str) -> Option<Duration> {
+    parse_duration(input).filter(|duration| !duration.is_zero())
+}
+
+/// Like `parse_interval`, but zero is allowed, e.g. `"0s"` for a cooldown
+/// that's turned off.
+pub fn parse_duration(input: &str) -> Option<Duration> {
     let input = input.trim();
     if input.is_empty() {
         return None;
@@ -51,10 +57,6 @@ pub fn parse_interval(input: &str) -> Option<Duration> {
         return None;
     }
 
-    if total_secs == 0 {
-        return None;
-    }
-
     Some(Duration::from_secs(total_secs))
 }
 
@@ -83,138 +85,94 @@ pub fn parse_timer_declarations(output: &str) -> Vec<TimerDef> {
         .collect()
 }
 
-/// Spawn a tokio task for each timer declared by currently loaded plugins.
+/// Spawn a tokio task for each timer declared by `plugins`, tagged with the
+/// plugin's name so they can be cancelled per plugin.
 /// Each task loops: sleep(interval) then call run_timer_tick().
 pub fn spawn_timers(
-    active: Arc<RwLock<Vec<Plugin>>>,
+    plugins: &[Plugin],
+    executor: Arc<Executor>,
     color_ffi: extern "C" fn(*const c_char, *const c_char) -> ColorResult,
     runtime: &tokio::runtime::Handle,
-) -> Vec<JoinHandle<()>> {
-    let plugins = match active.read() {
-        Ok(guard) => guard.clone(),
-        Err(_) => {
-            error!("Failed to read plugin list for timer spawning");
-            return vec![];
-        }
-    };
-
+) -> Vec<(String, JoinHandle<()>)> {
     let mut handles = Vec::new();
 
-    for plugin in &plugins {
+    for plugin in plugins {
         for timer in &plugin.timers {
-            let plugin_path = plugin.name.clone();
+            let plugin = plugin.clone();
+            let executor = executor.clone();
             let command = timer.command.clone();
             let interval = timer.interval;
 
             info!(
                 "Spawning timer '{}' for plugin '{}' every {:?}",
-                command, plugin_path, interval
+                command, plugin.name, interval
             );
 
+            let name = plugin.name.clone();
             let handle = runtime.spawn(async move {
                 loop {
                     tokio::time::sleep(interval).await;
-                    run_timer_tick(&plugin_path, &command, color_ffi);
+                    run_timer_tick(&executor, &plugin, &command, color_ffi).await;
                 }
             });
 
-            handles.push(handle);
+            handles.push((name, handle));
         }
     }
 
     handles
 }
 
-/// Execute a single timer tick: load the plugin .so, call `exported` with a
-/// synthetic PluginContext, and log the result.
-fn run_timer_tick(
-    plugin_path: &str,
+/// Execute a single timer tick: run the plugin's `exported` entry point on the
+/// executor with a synthetic PluginContext, and log the result.
+async fn run_timer_tick(
+    executor: &Executor,
+    plugin: &Plugin,
     command: &str,
     color_ffi: extern "C" fn(*const c_char, *const c_char) -> ColorResult,
 ) {
-    let lib = match unsafe { Library::new(plugin_path) } {
-        Ok(lib) => lib,
+    let results = match executor
+        .call(
+            plugin,
+            command,
+            "",
+            &Caller::internal("timer!timer@reinze.internal"),
+            "",
+            color_ffi,
+        )
+        .await
+    {
+        Ok(results) => results,
         Err(e) => {
-            error!("Timer: error loading plugin '{}': {}", plugin_path, e);
+            error!("Timer [{}] {}: {}", plugin.name, command, e);
             return;
         }
     };
 
-    let results = unsafe {
-        let exported: Symbol<extern "C" fn(context: &PluginContext) -> *mut c_char> =
-            match lib.get(b"exported\0") {
-                Ok(exported) => exported,
-                Err(e) => {
-                    error!(
-                        "Timer: error loading 'exported' from '{}': {}",
-                        plugin_path, e
-                    );
-                    return;
-                }
-            };
-
-        let cstr_cmd = match CString::new(command) {
-            Ok(cmd) => cmd.into_raw(),
-            Err(_) => return,
-        };
-        let cstr_param = match CString::new("") {
-            Ok(param) => param.into_raw(),
-            Err(_) => return,
-        };
-        let cstr_author = match CString::new("timer!timer@reinze.internal") {
-            Ok(author) => author.into_raw(),
-            Err(_) => return,
-        };
-        let cstr_channel = match CString::new("") {
-            Ok(channel) => channel.into_raw(),
-            Err(_) => return,
-        };
-
-        let context = PluginContext {
-            cmd: cstr_cmd,
-            param: cstr_param,
-            author: cstr_author,
-            color: color_ffi,
-            channel: cstr_channel,
-        };
-
-        let raw_results = exported(&context);
-
-        let output = match CStr::from_ptr(raw_results).to_str() {
-            Ok(results) => results
-                .split("\n")
-                .map(|s| s.to_string())
-                .collect::<Vec<String>>(),
-            _ => vec![],
-        };
-
-        _ = CString::from_raw(raw_results);
-        _ = CString::from_raw(cstr_channel);
-        _ = CString::from_raw(cstr_author);
-        _ = CString::from_raw(cstr_param);
-        _ = CString::from_raw(cstr_cmd);
-
-        output
-    };
-
     for line in &results {
         if !line.is_empty() {
-            info!("Timer [{}] {}: {}", plugin_path, command, line);
+            info!("Timer [{}] {}: {}", plugin.name, command, line);
         }
     }
 }
 
 /// Manages timer lifecycle, supporting hot-reload and clean shutdown.
 pub struct TimerManager {
-    handles: Mutex<Vec<JoinHandle<()>>>,
+    // Each timer task with the name of the plugin it belongs to
+    handles: Mutex<Vec<(String, JoinHandle<()>)>>,
+    executor: Arc<Executor>,
     color_ffi: extern "C" fn(*const c_char, *const c_char) -> ColorResult,
     runtime_handle: Mutex<Option<tokio::runtime::Handle>>,
 }
 
 impl TimerManager {
-    pub fn new(color_ffi: extern "C" fn(*const c_char, *const c_char) -> ColorResult) -> Self {
+    pub fn new(
+        executor: Arc<Executor>,
+        color_ffi: extern "C" fn(*const c_char, *const c_char) -> ColorResult,
+    ) -> Self {
         Self {
             handles: Mutex::new(vec![]),
+            executor,
             color_ffi,
             runtime_handle: Mutex::new(None),
         }
@@ -225,23 +183,48 @@ impl TimerManager {
         *self.runtime_handle.lock().unwrap() = Some(handle);
     }
 
-    /// Cancel all running timers and spawn new ones from the current plugin set.
-    pub fn restart(&self, active: &Arc<RwLock<Vec<Plugin>>>) {
+    /// Cancel all running timers and spawn new ones for `plugins`.
+    pub fn restart(&self, plugins: &[Plugin]) {
         let mut handles = self.handles.lock().unwrap();
-        for handle in handles.drain(..) {
+        for (_, handle) in handles.drain(..) {
             handle.abort();
         }
         let runtime = self.runtime_handle.lock().unwrap();
         if let Some(rt) = runtime.as_ref() {
-            let new_handles = spawn_timers(active.clone(), self.color_ffi, rt);
+            let new_handles = spawn_timers(plugins, self.executor.clone(), self.color_ffi, rt);
             *handles = new_handles;
         }
     }
 
+    /// Spawn one plugin's timers alongside the ones already running.
+    pub fn start(&self, plugin: &Plugin) {
+        let mut handles = self.handles.lock().unwrap();
+        let runtime = self.runtime_handle.lock().unwrap();
+        if let Some(rt) = runtime.as_ref() {
+            handles.extend(spawn_timers(
+                std::slice::from_ref(plugin),
+                self.executor.clone(),
+                self.color_ffi,
+                rt,
+            ));
+        }
+    }
+
+    /// Cancel the timers of the plugin named `name`, leaving the rest running.
+    pub fn cancel(&self, name: &str) {
+        let mut handles = self.handles.lock().unwrap();
+        handles.retain(|(plugin, handle)| {
+            if plugin == name {
+                handle.abort();
+            }
+            plugin != name
+        });
+    }
+
     /// Cancel all running timers (used on disconnect).
     pub fn cancel_all(&self) {
         let mut handles = self.handles.lock().unwrap();
-        for handle in handles.drain(..) {
+        for (_, handle) in handles.drain(..) {
             handle.abort();
         }
     }
@@ -313,6 +296,14 @@ mod tests {
         assert_eq!(parse_interval("0h0m0s"), None);
     }
 
+    #[test]
+    fn test_parse_duration_allows_zero() {
+        assert_eq!(parse_duration("0s"), Some(Duration::ZERO));
+        assert_eq!(parse_duration("5m"), Some(Duration::from_secs(300)));
+        assert_eq!(parse_duration(""), None);
+        assert_eq!(parse_duration("soon"), None);
+    }
+
     #[test]
     fn test_parse_timer_line() {
         let def = parse_timer_line("tracksnapshot:6h");
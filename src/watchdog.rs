@@ -0,0 +1,163 @@
+use crate::settings::WatchdogSettings;
+use irc::client::prelude::{Command, Message};
+use std::time::{Duration, Instant};
+
+// Marks our own PINGs, so replies to the client's aren't mistaken for them
+const TOKEN_PREFIX: &str = "reinze-lag-";
+
+/// What the watchdog wants done.
+#[derive(Debug, PartialEq)]
+pub enum Tick {
+    Idle,
+    Ping(Command),
+    /// No reply to the last PING in time; the connection is presumed dead.
+    Stale(Duration),
+}
+
+/// Sends our own PINGs once registered, measures how long the PONGs take,
+/// and gives up on the connection when one doesn't come back.
+pub struct Watchdog {
+    interval: Duration,
+    timeout: Duration,
+    next_ping: Option<Instant>,
+    // The token of the PING we're waiting on and when it went out
+    pending: Option<(String, Instant)>,
+    sent: u64,
+}
+
+impl Watchdog {
+    pub fn new(settings: &WatchdogSettings) -> Self {
+        Self {
+            interval: settings.interval(),
+            timeout: settings.timeout(),
+            next_ping: None,
+            pending: None,
+            sent: 0,
+        }
+    }
+
+    /// Starts pinging, from registration on.
+    pub fn start(&mut self) {
+        self.start_at(Instant::now());
+    }
+
+    fn start_at(&mut self, now: Instant) {
+        self.next_ping = Some(now);
+    }
+
+    pub fn tick(&mut self) -> Tick {
+        self.tick_at(Instant::now())
+    }
+
+    fn tick_at(&mut self, now: Instant) -> Tick {
+        if let Some((_, sent)) = &self.pending {
+            let waited = now.saturating_duration_since(*sent);
+            return if waited >= self.timeout {
+                Tick::Stale(waited)
+            } else {
+                Tick::Idle
+            };
+        }
+
+        match self.next_ping {
+            Some(next) if now >= next => {
+                self.sent += 1;
+                let token = format!("{}{}", TOKEN_PREFIX, self.sent);
+                self.pending = Some((token.clone(), now));
+                self.next_ping = Some(now + self.interval);
+                Tick::Ping(Command::PING(token, None))
+            }
+            _ => Tick::Idle,
+        }
+    }
+
+    /// The round trip, if `message` answers our outstanding PING.
+    pub fn pong(&mut self, message: &Message) -> Option<Duration> {
+        self.pong_at(Instant::now(), message)
+    }
+
+    fn pong_at(&mut self, now: Instant, message: &Message) -> Option<Duration> {
+        // Servers put the token last: `PONG <server> :<token>`
+        let token = match &message.command {
+            Command::PONG(server, token) => token.as_ref().unwrap_or(server),
+            _ => return None,
+        };
+
+        match &self.pending {
+            Some((pending, sent)) if pending == token => {
+                let lag = now.saturating_duration_since(*sent);
+                self.pending = None;
+                Some(lag)
+            }
+            _ => None,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::settings::Settings;
+
+    fn watchdog() -> Watchdog {
+        let settings = Settings::parse(
+            r#"
+            [watchdog]
+            interval = "1m"
+            timeout = "20s"
+            "#,
+        )
+        .unwrap();
+        Watchdog::new(&settings.watchdog)
+    }
+
+    fn pong(token: &str) -> Message {
+        format!(":irc.example PONG irc.example :{}", token)
+            .parse()
+            .unwrap()
+    }
+
+    #[test]
+    fn test_measures_lag() {
+        let mut watchdog = watchdog();
+        let now = Instant::now();
+        // Nothing before registration
+        assert_eq!(watchdog.tick_at(now), Tick::Idle);
+
+        watchdog.start_at(now);
+        let token = match watchdog.tick_at(now) {
+            Tick::Ping(Command::PING(token, None)) => token,
+            tick => panic!("expected a ping, got {:?}", tick),
+        };
+        assert_eq!(watchdog.tick_at(now + Duration::from_secs(5)), Tick::Idle);
+
+        // Replies to someone else's PING don't count
+        assert_eq!(watchdog.pong_at(now, &pong("1700000000")), None);
+        let later = now + Duration::from_millis(250);
+        assert_eq!(
+            watchdog.pong_at(later, &pong(&token)),
+            Some(Duration::from_millis(250))
+        );
+
+        // The next one waits for the interval
+        assert_eq!(watchdog.tick_at(now + Duration::from_secs(30)), Tick::Idle);
+        assert!(matches!(
+            watchdog.tick_at(now + Duration::from_secs(60)),
+            Tick::Ping(_)
+        ));
+    }
+
+    #[test]
+    fn test_stale_without_pong() {
+        let mut watchdog = watchdog();
+        let now = Instant::now();
+        watchdog.start_at(now);
+        watchdog.tick_at(now);
+
+        assert_eq!(watchdog.tick_at(now + Duration::from_secs(19)), Tick::Idle);
+        assert_eq!(
+            watchdog.tick_at(now + Duration::from_secs(20)),
+            Tick::Stale(Duration::from_secs(20))
+        );
+    }
+}